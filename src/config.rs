@@ -1,15 +1,314 @@
-use clap::Parser;
+use clap::{AppSettings, Parser, Subcommand};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+use crate::entity::{self, mount};
+
+// エントリと属性をカーネルにキャッシュさせる時間の既定値
+const TTL_DEFAULT: Duration = Duration::from_secs(1);
 
 #[derive(Parser, Debug)]
 #[clap(
     name = "hfs",
     version = "0.0.1",
     author = "higuruchi",
+    setting = AppSettings::SubcommandsNegateReqs,
 )]
 pub struct Config {
-    #[clap(short, long)]
-    pub config_path: String,
+    #[clap(short, long, required = true)]
+    pub config_path: Option<String>,
+
+    #[clap(short, long, required = true)]
+    pub mountpoint: Option<String>,
+
+    /// イメージを変更せずに読み取り専用でマウントする
+    #[clap(long)]
+    pub read_only: bool,
+
+    /// readとreaddirでatimeを更新する条件(noatime, relatime, strictatime)
+    #[clap(long, default_value = "relatime")]
+    pub atime: mount::Atime,
+
+    /// マウントオプションをカンマ区切りで指定する
+    /// (allow_other, allow_root, default_permissions, auto_unmount, fsname=名前, subtype=名前,
+    /// ro, noatime, relatime, strictatime, entry_timeout=秒, attr_timeout=秒, negative_timeout=秒, max_threads=数,
+    /// flush_interval=秒, flush_batch=数)
+    #[clap(short = 'o', multiple_occurrences = true, use_delimiter = true)]
+    pub options: Vec<MountOption>,
+
+    /// マウントした後にバックグラウンドで動作する
+    #[clap(long)]
+    pub daemon: bool,
+
+    /// マウント中のプロセスIDを書き込むファイル
+    #[clap(long)]
+    pub pidfile: Option<String>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>
+}
+
+// -oで指定できるマウントオプション
+#[derive(Debug, Clone, PartialEq)]
+pub enum MountOption {
+    // カーネルにそのまま渡すもの
+    AllowOther,
+    AllowRoot,
+    DefaultPermissions,
+    AutoUnmount,
+    FsName(String),
+    Subtype(String),
+    // hfsが解釈するもの
+    ReadOnly,
+    Atime(mount::Atime),
+    Timeout(Ttl, Duration),
+    MaxThreads(usize),
+    FlushInterval(Duration),
+    FlushBatch(usize)
+}
+
+// カーネルにキャッシュさせるものの種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ttl {
+    // 名前からinodeへの対応
+    Entry,
+    // 属性
+    Attr,
+    // 名前が存在しないこと
+    Negative
+}
+
+impl MountOption {
+    // fuse::mountに渡す形式を返す
+    // hfsが解釈するものはNoneを返す
+    fn kernel(&self) -> Option<String> {
+        match self {
+            MountOption::AllowOther => Some("allow_other".to_string()),
+            MountOption::AllowRoot => Some("allow_root".to_string()),
+            MountOption::DefaultPermissions => Some("default_permissions".to_string()),
+            MountOption::AutoUnmount => Some("auto_unmount".to_string()),
+            MountOption::FsName(name) => Some(format!("fsname={}", name)),
+            MountOption::Subtype(name) => Some(format!("subtype={}", name)),
+            _ => None
+        }
+    }
+}
+
+impl FromStr for MountOption {
+    type Err = entity::Error;
+
+    fn from_str(s: &str) -> Result<MountOption, entity::Error> {
+        let (name, value) = match s.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (s, None)
+        };
+
+        match (name, value) {
+            ("allow_other", None) => Ok(MountOption::AllowOther),
+            ("allow_root", None) => Ok(MountOption::AllowRoot),
+            ("default_permissions", None) => Ok(MountOption::DefaultPermissions),
+            ("auto_unmount", None) => Ok(MountOption::AutoUnmount),
+            ("fsname", Some(value)) if !value.is_empty() => Ok(MountOption::FsName(value.to_string())),
+            ("subtype", Some(value)) if !value.is_empty() => Ok(MountOption::Subtype(value.to_string())),
+            ("ro", None) => Ok(MountOption::ReadOnly),
+            ("noatime", None) | ("relatime", None) | ("strictatime", None) => Ok(MountOption::Atime(name.parse()?)),
+            ("entry_timeout", Some(value)) => Ok(MountOption::Timeout(Ttl::Entry, seconds(value)?)),
+            ("attr_timeout", Some(value)) => Ok(MountOption::Timeout(Ttl::Attr, seconds(value)?)),
+            ("negative_timeout", Some(value)) => Ok(MountOption::Timeout(Ttl::Negative, seconds(value)?)),
+            ("max_threads", Some(value)) => match value.parse::<usize>() {
+                Ok(threads) if threads > 0 => Ok(MountOption::MaxThreads(threads)),
+                _ => Err(entity::Error::InvalidArgument)
+            },
+            ("flush_interval", Some(value)) => Ok(MountOption::FlushInterval(seconds(value)?)),
+            ("flush_batch", Some(value)) => match value.parse::<usize>() {
+                Ok(batch) if batch > 0 => Ok(MountOption::FlushBatch(batch)),
+                _ => Err(entity::Error::InvalidArgument)
+            },
+            _ => Err(entity::Error::InvalidArgument)
+        }
+    }
+}
+
+// 0以上の秒数を読み込む
+// カーネルに渡すときにi64の秒数になるため、それを超える値も受け付けない
+fn seconds(value: &str) -> Result<Duration, entity::Error> {
+    let secs = match value.parse::<f64>() {
+        Ok(secs) => secs,
+        Err(_) => return Err(entity::Error::InvalidArgument)
+    };
+    match Duration::try_from_secs_f64(secs) {
+        Ok(duration) if duration.as_secs() <= i64::MAX as u64 => Ok(duration),
+        _ => Err(entity::Error::InvalidArgument)
+    }
+}
+
+impl Config {
+    // usecaseに渡すオプション
+    // -oの指定は--read-onlyや--atimeより優先する
+    pub fn mount_options(&self) -> mount::Options {
+        let mut options = mount::Options {
+            read_only: self.read_only,
+            atime: self.atime,
+            write_behind: mount::WriteBehind::default()
+        };
+
+        for option in self.options.iter() {
+            match option {
+                MountOption::ReadOnly => options.read_only = true,
+                MountOption::Atime(atime) => options.atime = *atime,
+                MountOption::FlushInterval(interval) => options.write_behind.interval = *interval,
+                MountOption::FlushBatch(batch) => options.write_behind.batch = *batch,
+                _ => {}
+            }
+        }
+        options
+    }
+
+    // fuse::mountに渡すオプション
+    // 読み取り専用の場合はカーネルにも伝える
+    pub fn kernel_options(&self) -> Vec<String> {
+        let mut options: Vec<String> = self.options.iter().filter_map(|option| option.kernel()).collect();
+
+        if self.mount_options().read_only {
+            options.push("ro".to_string());
+        }
+        options
+    }
+
+    // 同じ種類を複数回指定した場合は最後の指定を使う
+    // 存在しないことは、指定しなければキャッシュさせない
+    pub fn ttl(&self, kind: Ttl) -> Duration {
+        let default = match kind {
+            Ttl::Negative => Duration::ZERO,
+            _ => TTL_DEFAULT
+        };
+
+        self.options.iter().fold(default, |ttl, option| match option {
+            MountOption::Timeout(k, secs) if *k == kind => *secs,
+            _ => ttl
+        })
+    }
+
+    // リクエストを並行して処理するスレッドの数
+    // 指定しなければCPUの数だけ動かす
+    pub fn threads(&self) -> usize {
+        let default = match thread::available_parallelism() {
+            Ok(threads) => threads.get(),
+            Err(_) => 1
+        };
+
+        self.options.iter().fold(default, |threads, option| match option {
+            MountOption::MaxThreads(n) => *n,
+            _ => threads
+        })
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// イメージのログを現在の状態だけに書き直す
+    Compact {
+        /// image.yamlへのパス
+        image: String
+    },
+    /// イメージを別の形式に変換する
+    Convert {
+        /// 変換元のimage.yamlへのパス
+        from: String,
+        /// 変換先のimage.yamlへのパス
+        to: String
+    },
+    /// ホストのディレクトリツリーからイメージを作る
+    Import {
+        /// 取り込むディレクトリ
+        dir: String,
+        /// 作成するイメージのimage.yamlへのパス(なければ作成する)
+        image: String,
+        /// image.yamlが既にあっても、その内容を取り込んだツリーで書き直す
+        #[clap(long)]
+        force: bool
+    },
+    /// マウントせずにイメージの内容を書き出す
+    Export {
+        /// image.yamlへのパス
+        image: String,
+        /// 書き出し先のディレクトリ(.tarで終わる場合はtarファイル)
+        target: String
+    },
+    /// マウントせずにイメージの不整合を調べる
+    Fsck {
+        /// image.yamlへのパス
+        image: String,
+        /// 見つかった不整合を修復する
+        #[clap(long)]
+        repair: bool
+    },
+    /// マウントせずにイメージの使用量と上限を表示する
+    Usage {
+        /// image.yamlへのパス
+        image: String
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Config {
+        Config::try_parse_from([&["hfs", "-c", "image.yaml", "-m", "mnt"], args].concat()).unwrap()
+    }
+
+    #[test]
+    fn mount_options_split_kernel_and_hfs_options() {
+        let config = parse(&["-o", "allow_other,fsname=golden,ro", "-o", "noatime,entry_timeout=30", "-o", "attr_timeout=0.5"]);
+
+        assert_eq!(config.mount_options(), mount::Options { read_only: true, atime: mount::Atime::NoAtime, ..Default::default() });
+        assert_eq!(config.kernel_options(), vec!["allow_other", "fsname=golden", "ro"]);
+        assert_eq!(config.ttl(Ttl::Entry), Duration::from_secs(30));
+        assert_eq!(config.ttl(Ttl::Attr), Duration::from_millis(500));
+        assert_eq!(config.ttl(Ttl::Negative), Duration::ZERO);
+
+        let config = parse(&[]);
+        assert_eq!(config.mount_options(), mount::Options::default());
+        assert!(config.kernel_options().is_empty());
+        assert_eq!(config.ttl(Ttl::Entry), TTL_DEFAULT);
+
+        let config = parse(&["-o", "entry_timeout=0,attr_timeout=0", "-o", "negative_timeout=60,entry_timeout=5"]);
+        assert_eq!(config.ttl(Ttl::Entry), Duration::from_secs(5));
+        assert_eq!(config.ttl(Ttl::Attr), Duration::ZERO);
+        assert_eq!(config.ttl(Ttl::Negative), Duration::from_secs(60));
+
+        let config = parse(&["-o", "max_threads=2", "-o", "max_threads=8"]);
+        assert_eq!(config.threads(), 8);
+        assert!(config.kernel_options().is_empty());
+
+        let config = parse(&["-o", "flush_interval=0.1,flush_batch=16"]);
+        assert_eq!(config.mount_options().write_behind, mount::WriteBehind { interval: Duration::from_millis(100), batch: 16 });
+        assert!(config.kernel_options().is_empty());
+    }
+
+    #[test]
+    fn mount_options_reject_unknown_and_malformed() {
+        let args = |option: &str| ["hfs", "-c", "image.yaml", "-m", "mnt", "-o", option].map(|arg| arg.to_string());
+
+        assert!(Config::try_parse_from(args("nosuchoption")).is_err());
+        assert!(Config::try_parse_from(args("fsname=")).is_err());
+        assert!(Config::try_parse_from(args("entry_timeout=-1")).is_err());
+        assert!(Config::try_parse_from(args("allow_other=1")).is_err());
+        assert!(Config::try_parse_from(args("max_threads=0")).is_err());
+        assert!(Config::try_parse_from(args("flush_batch=0")).is_err());
+        assert!(Config::try_parse_from(args("flush_interval=x")).is_err());
+    }
 
-    #[clap(short, long)]
-    pub mountpoint: String
-}
\ No newline at end of file
+    #[test]
+    fn seconds_reject_negative_and_out_of_range() {
+        assert_eq!(seconds("1.5").ok(), Some(Duration::from_millis(1500)));
+        assert_eq!(seconds("0").ok(), Some(Duration::ZERO));
+        assert!(seconds("-1").is_err());
+        assert!(seconds("-0.5").is_err());
+        assert!(seconds("1e300").is_err());
+        assert!(seconds("inf").is_err());
+        assert!(seconds("NaN").is_err());
+        assert!(seconds("1e19").is_err());
+    }
+}
@@ -1,18 +1,149 @@
 use crate::{
     // externalinterface::{fuse, yaml_image},
     externalinterface,
-    interfaceadapter::{controller, file_repository},
+    interfaceadapter::{controller, file_repository, worker},
     usecase,
     config
 };
+use crate::entity::{self, limit, xattr};
+#[cfg(any(test, feature = "test-support"))]
+use crate::entity::mount;
 use fuse;
+use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
-pub fn initialize(config: config::Config) -> Result<impl fuse::Filesystem , ()> {
-    let yaml_image = externalinterface::yaml_image::YAMLImageStruct::new();
-    let file_repository = file_repository::new(yaml_image);
-    let usecase = usecase::new(file_repository);
-    let controller = controller::new(usecase);
+// イメージを読み込んでからfuseに渡す
+// マウントする前に読み込むことで、失敗した場合に終了コードで伝えられるようにする
+// シグナルを受け取ったときに使うよう、まとめている変更をディスクに同期する関数も返す
+pub fn initialize(config: config::Config) -> anyhow::Result<(impl fuse::Filesystem, impl Fn() -> anyhow::Result<()> + Send + 'static)> {
+    let options = config.mount_options();
+    let image = if options.read_only {
+        externalinterface::image::ImageStruct::read_only()
+    } else {
+        externalinterface::image::ImageStruct::new()
+    };
+    let file_repository = file_repository::new(image);
+    let usecase = usecase::with_options(file_repository, options);
+    let mut controller = controller::new(usecase);
+    controller::Controller::init(&mut controller, &config.config_path.clone().unwrap_or_default())?;
+    let controller = Arc::new(controller);
+    let sync = {
+        let controller = Arc::clone(&controller);
+        move || controller::Controller::fsync(&*controller)
+    };
     let fuse = externalinterface::fuse::new(config, controller);
 
-    return Ok(fuse);
-}
\ No newline at end of file
+    return Ok((fuse, sync));
+}
+
+// マウントせずにイメージを読み込み、ファイルシステムの操作を直接呼び出せるようにする
+// カーネルを介さずに読み書きの振る舞いを確かめる結合テストで使うため、test-supportの場合だけ公開する
+#[cfg(any(test, feature = "test-support"))]
+pub fn open(image: &str, options: mount::Options) -> anyhow::Result<impl usecase::Usecase> {
+    let image_struct = if options.read_only {
+        externalinterface::image::ImageStruct::read_only()
+    } else {
+        externalinterface::image::ImageStruct::new()
+    };
+    let file_repository = file_repository::new(image_struct);
+    let mut usecase = usecase::with_options(file_repository, options);
+
+    usecase::Usecase::init(&mut usecase, Path::new(image))?;
+    Ok(usecase)
+}
+
+// マウントせずにイメージをコンパクションする
+pub fn compact(path: &str) -> anyhow::Result<()> {
+    let image = externalinterface::image::ImageStruct::new();
+    let file_repository = file_repository::new(image);
+    let mut usecase = usecase::new(file_repository);
+
+    usecase::Usecase::init(&mut usecase, Path::new(path))?;
+    usecase::Usecase::compact(&usecase)
+}
+
+// マウントせずにイメージの不整合を調べ、repairがtrueなら修復する
+pub fn fsck(image: &str, repair: bool) -> anyhow::Result<Vec<usecase::fsck::Problem>> {
+    let image_struct = externalinterface::image::ImageStruct::new();
+    let file_repository = file_repository::new(image_struct);
+    let mut usecase = usecase::new(file_repository);
+
+    usecase::Usecase::init(&mut usecase, Path::new(image))?;
+    usecase::Usecase::fsck(&usecase, repair)
+}
+
+// マウントせずにイメージ全体と所有者ごとの使用量を、上限とともに返す
+pub fn usage(image: &str) -> anyhow::Result<(limit::Limits, limit::Usage, BTreeMap<u32, limit::Usage>)> {
+    let image_struct = externalinterface::image::ImageStruct::read_only();
+    let file_repository = file_repository::new(image_struct);
+    let mut usecase = usecase::new(file_repository);
+
+    usecase::Usecase::init(&mut usecase, Path::new(image))?;
+    let (total, per_uid) = usecase::Usecase::usage(&usecase)?;
+
+    Ok((usecase::Usecase::limits(&usecase).clone(), total, per_uid))
+}
+
+// ホストのディレクトリツリーから新しいイメージを作る
+// 既存のイメージを書き潰さないよう、image.yamlがあればforceの場合だけ書き直す
+pub fn import(dir: &str, image: &str, force: bool) -> anyhow::Result<()> {
+    if Path::new(image).exists() && !force {
+        return Err(entity::Error::Exists.into());
+    }
+    let (attrs, entries, data) = externalinterface::host_dir::import(Path::new(dir))?;
+    externalinterface::image::ImageStruct::create(Path::new(image))?;
+    let destination = externalinterface::image::ImageStruct::open(Path::new(image))?;
+    // ホストの拡張属性は取り込まない
+    let xattrs = xattr::XattrsStruct::new(HashMap::new());
+
+    worker::File::compact(&destination, &attrs, &entries, &data, &xattrs)
+}
+
+// マウントせずにイメージの内容をディレクトリかtarファイルに書き出す
+pub fn export(image: &str, target: &str) -> anyhow::Result<()> {
+    let mut source = externalinterface::image::ImageStruct::read_only();
+    let (_, attrs, entries, data, _) = worker::File::init(&mut source, Path::new(image))?;
+
+    if target.ends_with(".tar") {
+        externalinterface::tarball::export(&attrs, &entries, &data, Path::new(target))
+    } else {
+        externalinterface::host_dir::export(&attrs, &entries, &data, Path::new(target))
+    }
+}
+
+// fromのイメージを読み込み、toのimage.yamlが指す形式で書き出す
+pub fn convert(from: &str, to: &str) -> anyhow::Result<()> {
+    let mut source = externalinterface::image::ImageStruct::new();
+    let (_, attrs, entries, data, xattrs) = worker::File::init(&mut source, Path::new(from))?;
+    let destination = externalinterface::image::ImageStruct::open(Path::new(to))?;
+
+    worker::File::compact(&destination, &attrs, &entries, &data, &xattrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn import_refuses_to_overwrite_existing_image_without_force() {
+        let dir = TempDir::new("di-import");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/first"), b"first").unwrap();
+        let src = dir.join("src").display().to_string();
+        let image = dir.join("image.yaml").display().to_string();
+
+        import(&src, &image, false).unwrap();
+        let imported = fs::read(dir.join("data.yaml")).unwrap();
+
+        fs::write(dir.join("src/second"), b"second").unwrap();
+        let result = import(&src, &image, false);
+        assert!(matches!(result.unwrap_err().downcast_ref::<entity::Error>(), Some(entity::Error::Exists)));
+        assert_eq!(fs::read(dir.join("data.yaml")).unwrap(), imported);
+
+        import(&src, &image, true).unwrap();
+        assert_ne!(fs::read(dir.join("data.yaml")).unwrap(), imported);
+    }
+}
@@ -1,7 +1,12 @@
 pub mod attr;
+pub mod credentials;
 pub mod data;
 pub mod entry;
+pub mod handle;
+pub mod limit;
 pub mod lookup_count;
+pub mod mount;
+pub mod xattr;
 
 use std::collections::HashMap;
 use std::{error, fmt};
@@ -20,9 +25,32 @@ pub enum Error {
     InvalidEntry,
     InternalError,
     InvalidAtime,
-    InvalidNlink
+    InvalidNlink,
+    NotFound,
+    NotEmpty,
+    Exists,
+    NotDir,
+    IsDir,
+    PermissionDenied,
+    NotPermitted,
+    NameTooLong,
+    NotSymlink,
+    NoXattr,
+    XattrRange,
+    XattrTooBig,
+    NotSupported,
+    InvalidArgument,
+    NoSpace,
+    QuotaExceeded,
+    FileTooBig,
+    ReadOnly,
+    BadHandle,
+    Io
 }
 
+// ファイル名の最大長(バイト)
+pub const NAME_MAX: usize = 255;
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt ::Formatter<'_>) -> fmt::Result {
         match self {
@@ -37,7 +65,27 @@ impl fmt::Display for Error {
             Self::InvalidEntry => write!(f, "There is no entry or entry is invalid"),
             Self::InternalError => write!(f, "Internal Error"),
             Self::InvalidAtime => write!(f, "There is no atime or atime is invalid"),
-            Self::InvalidNlink => write!(f, "There is no nlink or nlink is invalid")
+            Self::InvalidNlink => write!(f, "There is no nlink or nlink is invalid"),
+            Self::NotFound => write!(f, "No such file or directory"),
+            Self::NotEmpty => write!(f, "Directory not empty"),
+            Self::Exists => write!(f, "File exists"),
+            Self::NotDir => write!(f, "Not a directory"),
+            Self::IsDir => write!(f, "Is a directory"),
+            Self::PermissionDenied => write!(f, "Permission denied"),
+            Self::NotPermitted => write!(f, "Operation not permitted"),
+            Self::NameTooLong => write!(f, "File name too long"),
+            Self::NotSymlink => write!(f, "Not a symbolic link"),
+            Self::NoXattr => write!(f, "No such attribute"),
+            Self::XattrRange => write!(f, "Attribute name or buffer is out of range"),
+            Self::XattrTooBig => write!(f, "Attribute value too large"),
+            Self::NotSupported => write!(f, "Operation not supported"),
+            Self::InvalidArgument => write!(f, "Invalid argument"),
+            Self::NoSpace => write!(f, "No space left on device"),
+            Self::QuotaExceeded => write!(f, "Disk quota exceeded"),
+            Self::FileTooBig => write!(f, "File too large"),
+            Self::ReadOnly => write!(f, "Read-only file system"),
+            Self::BadHandle => write!(f, "Bad file descriptor"),
+            Self::Io => write!(f, "Input/output error")
         } 
     }
 }
@@ -6,7 +6,6 @@ use anyhow::Result;
 pub struct Attr {
     pub ino: u64,
     pub size: u64,
-    pub name: String,
     // pub blocks: u32,
     pub atime: SystemTime,
     pub mtime: SystemTime,
@@ -23,13 +22,14 @@ pub struct Attr {
 #[derive (Clone, Copy, Debug)]
 pub enum FileType {
     Directory,
-    TextFile
+    TextFile,
+    Symlink
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct SystemTime(pub u64, pub u32);
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AttrsStruct {
     attrs: HashMap<u64, Attr>,
 }
@@ -57,7 +57,6 @@ impl Attr {
     pub fn new(
         ino: u64,
         size: u64,
-        name: String,
         kind: FileType,
         perm: u16,
         uid: u32,
@@ -70,7 +69,6 @@ impl Attr {
         Attr {
             ino: ino,
             size: size,
-            name: name,
             kind: kind,
             perm: perm,
             uid: uid,
@@ -90,10 +88,6 @@ impl Attr {
         self.size
     }
 
-    pub fn name(&self) -> &str {
-        &self.name
-    }
-
     pub fn file_type(&self) -> FileType {
         self.kind
     }
@@ -161,10 +155,6 @@ impl Attr {
     pub fn nlink_mut(&mut self) -> &mut u32 {
         &mut self.nlink
     }
-
-    pub fn set_name(&mut self, name: &str) {
-        self.name = name.to_string()
-    }
 }
 
 impl SystemTime {
@@ -197,6 +187,10 @@ impl AttrsStruct {
         }
     }
 
+    pub fn attrs(&self) -> &HashMap<u64, Attr> {
+        &self.attrs
+    }
+
     pub fn attr(&self, ino: u64) -> Option<&Attr> {
         match self.attrs.get(&ino) {
             Some(attr) => Some(attr),
@@ -312,15 +306,41 @@ impl AttrsStruct {
         return Ok(());
     }
 
-    pub fn update_name(&mut self, ino: u64, name: &str) -> Result<(), Error> {
+    pub fn inc_nlink(&mut self, ino: u64) -> Result<u32, Error> {
+        let attr = match self.attrs.get_mut(&ino) {
+            Some(attr) => attr,
+            None => return Err(Error::InternalError.into())
+        };
+
+        let nlink_p = attr.nlink_mut();
+        let nlink = *nlink_p + 1;
+        *nlink_p = nlink;
+
+        Ok(nlink)
+    }
+
+    pub fn update_nlink(&mut self, ino: u64, nlink: u32) -> Result<(), Error> {
+        let attr = match self.attrs.get_mut(&ino) {
+            Some(attr) => attr,
+            None => return Err(Error::InternalError.into())
+        };
+
+        let nlink_p = attr.nlink_mut();
+        *nlink_p = nlink;
+        return Ok(());
+    }
+
+    pub fn dec_nlink(&mut self, ino: u64) -> Result<u32, Error> {
         let attr = match self.attrs.get_mut(&ino) {
             Some(attr) => attr,
             None => return Err(Error::InternalError.into())
         };
 
-        attr.set_name(name);
+        let nlink_p = attr.nlink_mut();
+        let nlink = nlink_p.saturating_sub(1);
+        *nlink_p = nlink;
 
-        Ok(())     
+        Ok(nlink)
     }
 
     pub fn del(&mut self, ino: u64) -> Result<Attr, Error> {
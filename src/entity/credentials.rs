@@ -0,0 +1,81 @@
+use crate::entity::attr;
+
+// 読み込み・書き込み・実行(検索)の許可を表すビット
+pub const R_OK: u32 = 4;
+pub const W_OK: u32 = 2;
+pub const X_OK: u32 = 1;
+
+// スティッキービットとSGIDビット
+pub const S_ISVTX: u16 = 0o1000;
+pub const S_ISGID: u16 = 0o2000;
+
+// 操作を要求したプロセスのユーザとグループ
+#[derive(Debug, Clone, Copy)]
+pub struct Credentials {
+    uid: u32,
+    gid: u32
+}
+
+impl Credentials {
+    pub fn new(uid: u32, gid: u32) -> Credentials {
+        Credentials {
+            uid: uid,
+            gid: gid
+        }
+    }
+
+    pub fn root() -> Credentials {
+        Credentials::new(0, 0)
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    pub fn is_owner(&self, attr: &attr::Attr) -> bool {
+        self.is_root() || self.uid == attr.uid()
+    }
+
+    // maskで指定した操作がattrのパーミッションで許可されているか
+    pub fn permits(&self, attr: &attr::Attr, mask: u32) -> bool {
+        let perm = attr.perm() as u32;
+
+        // rootは読み書きを常に許可され、実行はいずれかの実行ビットがあるかディレクトリの場合のみ許可される
+        if self.is_root() {
+            if mask & X_OK == 0 {
+                return true;
+            }
+            return match attr.kind() {
+                attr::FileType::Directory => true,
+                _ => perm & 0o111 != 0
+            };
+        }
+
+        let granted = if self.uid == attr.uid() {
+            (perm >> 6) & 0o7
+        } else if self.gid == attr.gid() {
+            (perm >> 3) & 0o7
+        } else {
+            perm & 0o7
+        };
+
+        granted & mask == mask
+    }
+
+    // スティッキービットを持つディレクトリdirから、childを削除・移動できるか
+    pub fn may_delete(&self, dir: &attr::Attr, child: &attr::Attr) -> bool {
+        if dir.perm() & S_ISVTX == 0 {
+            return true;
+        }
+
+        self.is_root() || self.uid == dir.uid() || self.uid == child.uid()
+    }
+}
@@ -1,26 +1,26 @@
 use std::collections::HashMap;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Data {
     pub ino: u64,
-    data: String
+    data: Vec<u8>
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AllDataStruct {
     all_data: HashMap<u64, Data>
 }
 pub trait AllData {}
 
 impl Data {
-    pub fn new(ino: u64, data: String) -> Data {
+    pub fn new(ino: u64, data: Vec<u8>) -> Data {
         Data{
             ino: ino,
             data: data
         }
     }
 
-    pub fn data(&self) -> &str {
+    pub fn data(&self) -> &[u8] {
         return &self.data;
     }
 }
@@ -36,6 +36,10 @@ impl AllDataStruct {
         }
     }
 
+    pub fn data(&self) -> &HashMap<u64, Data> {
+        &self.all_data
+    }
+
     pub fn all_data(&self, ino: u64) -> Option<&Data> {
         match self.all_data.get(&ino) {
             Some(data) => return Some(data),
@@ -56,4 +60,4 @@ impl AllDataStruct {
     }
 }
 
-impl AllData for AllDataStruct {}
\ No newline at end of file
+impl AllData for AllDataStruct {}
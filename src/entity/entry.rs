@@ -3,10 +3,11 @@ use std::collections::HashMap;
 #[derive(Debug, Clone)]
 pub struct Entry {
     // pub ino: u64,
-    pub child_ino: u64
+    pub child_ino: u64,
+    pub name: String
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct EntriesStruct {
     entries: HashMap<u64, Vec<Entry>>,
 }
@@ -15,17 +16,29 @@ pub trait Entries {}
 impl Entry {
     pub fn new(
         // ino: u64,
-        child_ino: u64
+        child_ino: u64,
+        name: String
     ) -> Entry {
         Entry {
             // ino: ino,
-            child_ino: child_ino
+            child_ino: child_ino,
+            name: name
         }
     }
 
     pub fn child_ino(&self) -> u64 {
         return self.child_ino;
     }
+
+    pub fn name(&self) -> &str {
+        return &self.name;
+    }
+}
+
+// ディレクトリのエントリとして使える名前か確かめる
+// 空の名前、"."と".."、"/"やNULを含む名前はパスとして別の場所を指すため使えない
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/') && !name.contains('\0')
 }
 
 impl EntriesStruct {
@@ -46,58 +59,54 @@ impl EntriesStruct {
         }
     }
 
-    pub fn insert_child_ino(&mut self, parent_ino: u64, child_ino: u64) -> Option<&Vec<Entry>> {
+    pub fn child(&self, parent_ino: u64, name: &str) -> Option<&Entry> {
+        match self.entries.get(&parent_ino) {
+            Some(entry) => entry.iter().find(|e| e.name() == name),
+            None => None
+        }
+    }
+
+    pub fn insert_child_ino(&mut self, parent_ino: u64, child_ino: u64, name: &str) -> Option<&Vec<Entry>> {
         let entry = match self.entries.get_mut(&parent_ino) {
             Some(entry) => entry,
             None => return None
         };
 
-        entry.push(Entry::new(child_ino));
+        entry.push(Entry::new(child_ino, name.to_string()));
 
         Some(entry)
     }
 
+    pub fn update_entry(&mut self, ino: u64, entry: Vec<Entry>) -> Option<Vec<Entry>> {
+        self.entries.insert(ino, entry)
+    }
+
     pub fn insert_entry(&mut self, ino: u64) {
         self.entries.insert(ino, Vec::new());
     }
 
-    // TODO: 返却値一時的になし
-    pub fn remove_child_ino(&mut self, parent_ino: u64, child_ino: u64) {
-        let mut new_entry: Vec<Entry> = Vec::new();
-
-        let entry = match self.entries.get(&parent_ino) {
+    // 親ディレクトリからnameの名前を持つエントリを削除し、そのエントリを返す
+    pub fn remove_child(&mut self, parent_ino: u64, name: &str) -> Option<Entry> {
+        let entry = match self.entries.get_mut(&parent_ino) {
             Some(entry) => entry,
-            None => return
+            None => return None
         };
 
-        for e in entry {
-            if e.child_ino() != child_ino {
-                new_entry.push(Entry::new(e.child_ino()));
-            }
+        match entry.iter().position(|e| e.name() == name) {
+            Some(i) => Some(entry.remove(i)),
+            None => None
         }
-
-        self.entries.insert(parent_ino, new_entry);
     }
 
     pub fn del(&mut self, ino: u64) {
         self.entries.remove(&ino);
     }
-    // fn insert_entry(&mut self, ino: u64) -> Option<&mut Vec<Entry>> {
-    //     self.entries().insert(ino, Vec::new());
-    //     self.entries().get_mut(&ino)
-    // }
-
-    pub fn mov(&mut self, ino: u64, parent_ino: u64, new_parent_ino: u64) {
-        // parent_inoのエントリからinoをフィルタリングしたものを挿入
-        let mut parent_entry_vec = Vec::new();
-        for e in self.entries.get(&parent_ino).unwrap().iter() {
-            if e.child_ino() != ino {
-                parent_entry_vec.push(e.clone());
-            }
-        }
-        self.entries.insert(parent_ino, parent_entry_vec);
 
-        self.insert_child_ino(new_parent_ino, ino);
+    pub fn mov(&mut self, parent_ino: u64, name: &str, new_parent_ino: u64, new_name: &str) {
+        // parent_inoのエントリからnameを取り除き、new_parent_inoにnew_nameとして挿入
+        if let Some(e) = self.remove_child(parent_ino, name) {
+            self.insert_child_ino(new_parent_ino, e.child_ino(), new_name);
+        }
     }
 }
-impl Entries for EntriesStruct {}
\ No newline at end of file
+impl Entries for EntriesStruct {}
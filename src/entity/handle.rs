@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+// open(2)のフラグ(Linuxの値)
+pub const O_ACCMODE: u32 = 0o3;
+pub const O_RDONLY: u32 = 0o0;
+pub const O_WRONLY: u32 = 0o1;
+pub const O_RDWR: u32 = 0o2;
+pub const O_EXCL: u32 = 0o200;
+pub const O_APPEND: u32 = 0o2000;
+
+// 開いているファイルかディレクトリ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Handle {
+    ino: u64,
+    flags: u32
+}
+
+// fhから開いているinodeを引く表
+// 開いている間はnlinkが0になってもinodeを削除しない
+#[derive(Debug)]
+pub struct Handles {
+    next_fh: u64,
+    handles: HashMap<u64, Handle>,
+    // inoごとに開いているハンドルの数
+    open_count: HashMap<u64, u64>
+}
+
+impl Handle {
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    pub fn writable(&self) -> bool {
+        self.flags & O_ACCMODE != O_RDONLY
+    }
+
+    pub fn append(&self) -> bool {
+        self.flags & O_APPEND != 0
+    }
+}
+
+impl Handles {
+    // fhの0はハンドルなしを表すため1から振る
+    pub fn new() -> Handles {
+        Handles {
+            next_fh: 1,
+            handles: HashMap::new(),
+            open_count: HashMap::new()
+        }
+    }
+
+    pub fn open(&mut self, ino: u64, flags: u32) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+
+        self.handles.insert(fh, Handle { ino: ino, flags: flags });
+        *self.open_count.entry(ino).or_insert(0) += 1;
+        fh
+    }
+
+    pub fn handle(&self, fh: u64) -> Option<&Handle> {
+        self.handles.get(&fh)
+    }
+
+    // 閉じたハンドルを返す
+    pub fn release(&mut self, fh: u64) -> Option<Handle> {
+        let handle = self.handles.remove(&fh)?;
+
+        if let Some(count) = self.open_count.get_mut(&handle.ino) {
+            *count -= 1;
+            if *count == 0 {
+                self.open_count.remove(&handle.ino);
+            }
+        }
+        Some(handle)
+    }
+
+    pub fn is_open(&self, ino: u64) -> bool {
+        self.open_count.contains_key(&ino)
+    }
+}
+
@@ -0,0 +1,125 @@
+use std::collections::{BTreeMap, HashMap};
+
+// statfsで報告するブロックの大きさ(バイト)
+pub const BLOCK_SIZE: u64 = 4096;
+
+// max-file-sizeを設定しない場合の1つのファイルの大きさの上限
+// ファイルの内容は全てメモリ上に持つため、大きなoffsetへの書き込みで際限なく確保しないようにする
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 1 << 30;
+
+// image.yamlで設定するイメージの上限
+// Noneは上限なしを表す
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    // データの総バイト数
+    pub capacity: Option<u64>,
+    // inodeの数
+    pub max_inodes: Option<u64>,
+    // 1つのファイルの大きさ(Noneの場合はDEFAULT_MAX_FILE_SIZE)
+    pub max_file_size: Option<u64>,
+    // 所有者ごとの上限
+    pub quotas: HashMap<u32, Quota>
+}
+
+// 1人のユーザが所有できるデータとinodeの上限
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Quota {
+    pub bytes: Option<u64>,
+    pub inodes: Option<u64>
+}
+
+// データのバイト数とinodeの数
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    pub bytes: u64,
+    pub inodes: u64
+}
+
+// イメージ全体と所有者ごとの使用量
+// 上限を確かめるたびに全てのinodeを数え直さないよう、変更に合わせて増減させる
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageTable {
+    total: Usage,
+    per_uid: BTreeMap<u32, Usage>
+}
+
+impl UsageTable {
+    pub fn new() -> UsageTable {
+        UsageTable::default()
+    }
+
+    pub fn total(&self) -> Usage {
+        self.total
+    }
+
+    pub fn of(&self, uid: u32) -> Usage {
+        self.per_uid.get(&uid).copied().unwrap_or_default()
+    }
+
+    pub fn per_uid(&self) -> &BTreeMap<u32, Usage> {
+        &self.per_uid
+    }
+
+    // uidが所有するbytesバイトのinodeを加える
+    pub fn add(&mut self, uid: u32, bytes: u64) {
+        let usage = self.per_uid.entry(uid).or_default();
+        usage.bytes += bytes;
+        usage.inodes += 1;
+        self.total.bytes += bytes;
+        self.total.inodes += 1;
+    }
+
+    // uidが所有するbytesバイトのinodeを取り除く
+    // inodeが無くなった所有者は一覧からも取り除く
+    pub fn remove(&mut self, uid: u32, bytes: u64) {
+        if let Some(usage) = self.per_uid.get_mut(&uid) {
+            usage.bytes = usage.bytes.saturating_sub(bytes);
+            usage.inodes = usage.inodes.saturating_sub(1);
+            if usage.inodes == 0 {
+                self.per_uid.remove(&uid);
+            }
+        }
+        self.total.bytes = self.total.bytes.saturating_sub(bytes);
+        self.total.inodes = self.total.inodes.saturating_sub(1);
+    }
+
+    // uidが所有するinodeの大きさをoldからnewに変える
+    pub fn resize(&mut self, uid: u32, old: u64, new: u64) {
+        let usage = self.per_uid.entry(uid).or_default();
+        usage.bytes = (usage.bytes + new).saturating_sub(old);
+        self.total.bytes = (self.total.bytes + new).saturating_sub(old);
+    }
+
+    // bytesバイトのinodeの所有者をfromからtoに変える
+    pub fn chown(&mut self, from: u32, to: u32, bytes: u64) {
+        if from == to {
+            return;
+        }
+        self.remove(from, bytes);
+        self.add(to, bytes);
+    }
+}
+
+// statfsで返すファイルシステムの使用状況
+#[derive(Debug, PartialEq)]
+pub struct StatFs {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32
+}
+
+impl Limits {
+    // 設定がなくても既定の上限を返す
+    pub fn file_size(&self) -> u64 {
+        self.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE)
+    }
+}
+
+// バイト数を占有するブロック数に切り上げる
+pub fn blocks(bytes: u64) -> u64 {
+    (bytes + BLOCK_SIZE - 1) / BLOCK_SIZE
+}
@@ -49,9 +49,10 @@ impl LookupCount {
 
     pub fn forget(&mut self, ino: u64, nlookup: u64) -> Option<u64> {
         if let Some(lookup_count) = self.count.get(&ino) {
-            let new_lookup_count = lookup_count - nlookup;
+            let new_lookup_count = lookup_count.saturating_sub(nlookup);
 
-            self.count.insert(ino, new_lookup_count)
+            self.count.insert(ino, new_lookup_count);
+            Some(new_lookup_count)
         } else {
             None
         }
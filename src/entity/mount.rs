@@ -0,0 +1,84 @@
+use std::str::FromStr;
+use std::time::Duration;
+use super::{attr, Error};
+
+// relatimeでも、atimeがこの秒数より古ければ更新する
+const RELATIME_INTERVAL: u64 = 24 * 60 * 60;
+
+// 変更をまとめて書き込むまでの時間と件数の既定値
+const FLUSH_INTERVAL_DEFAULT: Duration = Duration::from_secs(1);
+const FLUSH_BATCH_DEFAULT: usize = 256;
+
+// マウント時に指定するオプション
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Options {
+    // イメージを一切変更せず、変更する操作はEROFSで拒否する
+    pub read_only: bool,
+    pub atime: Atime,
+    pub write_behind: WriteBehind
+}
+
+// イメージへの変更をinodeごとにまとめて書き込む条件
+// どちらかを満たしたら、まとめた変更を書き込む
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WriteBehind {
+    // 最初の変更を受け取ってから書き込むまでの時間(0なら変更が途切れたらすぐに書き込む)
+    pub interval: Duration,
+    // まとめたレコードの数
+    pub batch: usize
+}
+
+impl Default for WriteBehind {
+    fn default() -> WriteBehind {
+        WriteBehind {
+            interval: FLUSH_INTERVAL_DEFAULT,
+            batch: FLUSH_BATCH_DEFAULT
+        }
+    }
+}
+
+// readとreaddirでatimeを更新する条件
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Atime {
+    // 更新しない
+    NoAtime,
+    // atimeがmtimeかctime以前の場合か、1日以上前の場合だけ更新する
+    RelAtime,
+    // アクセスのたびに更新する
+    StrictAtime
+}
+
+impl Default for Atime {
+    fn default() -> Atime {
+        Atime::RelAtime
+    }
+}
+
+impl Atime {
+    // nowにアクセスしたとき、attrのatimeを更新するべきかを返す
+    pub fn needs_update(&self, attr: &attr::Attr, now: attr::SystemTime) -> bool {
+        match self {
+            Atime::NoAtime => false,
+            Atime::StrictAtime => true,
+            Atime::RelAtime => {
+                let atime = attr.atime();
+                atime <= attr.mtime()
+                    || atime <= attr.ctime()
+                    || now.as_secs().saturating_sub(atime.as_secs()) >= RELATIME_INTERVAL
+            }
+        }
+    }
+}
+
+impl FromStr for Atime {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Atime, Error> {
+        match s {
+            "noatime" => Ok(Atime::NoAtime),
+            "relatime" => Ok(Atime::RelAtime),
+            "strictatime" => Ok(Atime::StrictAtime),
+            _ => Err(Error::InvalidArgument)
+        }
+    }
+}
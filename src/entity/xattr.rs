@@ -0,0 +1,61 @@
+use std::collections::{BTreeMap, HashMap};
+
+// 1つのinodeが持つ拡張属性
+// 名前の順に並べておき、listxattrやイメージへの書き出しで順序が変わらないようにする
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+// 拡張属性の名前と値の最大長(バイト)
+pub const XATTR_NAME_MAX: usize = 255;
+pub const XATTR_SIZE_MAX: usize = 65536;
+
+// setxattrのflags
+pub const XATTR_CREATE: u32 = 1;
+pub const XATTR_REPLACE: u32 = 2;
+
+#[derive(Debug, Clone)]
+pub struct XattrsStruct {
+    xattrs: HashMap<u64, Xattrs>
+}
+
+impl XattrsStruct {
+    pub fn new(xattrs: HashMap<u64, Xattrs>) -> XattrsStruct {
+        XattrsStruct {
+            xattrs: xattrs
+        }
+    }
+
+    pub fn xattrs(&self) -> &HashMap<u64, Xattrs> {
+        &self.xattrs
+    }
+
+    // 拡張属性を1つも持たないinodeはNoneを返す
+    pub fn xattr(&self, ino: u64) -> Option<&Xattrs> {
+        self.xattrs.get(&ino)
+    }
+
+    pub fn value(&self, ino: u64, name: &str) -> Option<&[u8]> {
+        match self.xattrs.get(&ino) {
+            Some(xattrs) => xattrs.get(name).map(|value| value.as_slice()),
+            None => None
+        }
+    }
+
+    pub fn set(&mut self, ino: u64, name: &str, value: &[u8]) {
+        self.xattrs.entry(ino).or_insert_with(BTreeMap::new).insert(name.to_string(), value.to_vec());
+    }
+
+    pub fn remove(&mut self, ino: u64, name: &str) -> Option<Vec<u8>> {
+        let xattrs = self.xattrs.get_mut(&ino)?;
+        let value = xattrs.remove(name);
+
+        if xattrs.is_empty() {
+            self.xattrs.remove(&ino);
+        }
+        value
+    }
+
+    // inodeの削除に合わせて全ての拡張属性を削除する
+    pub fn del(&mut self, ino: u64) -> Option<Xattrs> {
+        self.xattrs.remove(&ino)
+    }
+}
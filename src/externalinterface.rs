@@ -1,2 +1,7 @@
 pub mod fuse;
-pub mod yaml_image;
\ No newline at end of file
+pub mod host_dir;
+pub mod image;
+pub mod tarball;
+pub mod thread_pool;
+pub mod yaml_image;
+pub mod yaml_single_image;
\ No newline at end of file
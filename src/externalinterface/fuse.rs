@@ -1,5 +1,6 @@
-use std::fs::File;
-use crate::{interfaceadapter::controller, config};
+use crate::{interfaceadapter::controller, config, entity};
+use crate::externalinterface::thread_pool;
+use crate::entity::credentials;
 use fuse::{
     Filesystem,
     ReplyEntry,
@@ -9,111 +10,202 @@ use fuse::{
     Request,
     ReplyWrite,
     ReplyCreate,
-    ReplyEmpty
+    ReplyEmpty,
+    ReplyOpen,
+    ReplyXattr,
+    ReplyStatfs
 };
 use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use time;
 use libc;
 
 struct FuseStruct <C: controller::Controller>{
-    config: String,
     mountpoint: String,
-    controller: C
+    // エントリと属性、存在しないことをカーネルにキャッシュさせる時間
+    entry_ttl: time::Timespec,
+    attr_ttl: time::Timespec,
+    negative_ttl: time::Timespec,
+    controller: Arc<C>,
+    // リクエストを処理するスレッド
+    // 返信はスレッドから送るため、遅い操作が他のリクエストを待たせない
+    // --daemonでforkした後に作るため、initで起動する
+    threads: usize,
+    pool: Option<thread_pool::ThreadPool>
 }
 
-pub fn new<C>(config: config::Config, controller: C) -> impl Filesystem
-    where C: controller::Controller,
+// controllerはイメージを読み込んだ状態で渡す
+// シグナルを受け取ったスレッドからも使えるよう、共有した状態で受け取る
+pub fn new<C>(config: config::Config, controller: Arc<C>) -> impl Filesystem
+    where C: controller::Controller + 'static,
 {
     FuseStruct{
-        config: config.config_path,
-        mountpoint: config.mountpoint,
+        entry_ttl: timespec(config.ttl(config::Ttl::Entry)),
+        attr_ttl: timespec(config.ttl(config::Ttl::Attr)),
+        negative_ttl: timespec(config.ttl(config::Ttl::Negative)),
+        threads: config.threads(),
+        pool: None,
+        mountpoint: config.mountpoint.unwrap_or_default(),
         controller: controller
     }
 }
 
-impl<C: controller::Controller> Filesystem for FuseStruct<C> {
-    fn init(&mut self, _req: &Request<'_>) -> Result<(), libc::c_int> {
+impl<C: controller::Controller + 'static> FuseStruct<C> {
+    // リクエストをスレッドに渡して処理する
+    // 引数は所有する形にしてから渡す
+    fn spawn<F>(&self, job: F)
+        where F: FnOnce(&C) + Send + 'static
+    {
+        let controller = Arc::clone(&self.controller);
 
-        match self.controller.init(&self.config) {
-            Ok(_) => {
-				println!("Initialized!");
-				return Ok(());
-			},
-            Err(e) => {
-				println!("Failed Initialized!: {}", e);
-				return Err(libc::ENOENT)
-			}
+        match &self.pool {
+            Some(pool) => pool.execute(move || job(&controller)),
+            None => job(&controller)
         }
     }
+}
+
+impl<C: controller::Controller + 'static> Filesystem for FuseStruct<C> {
+    fn init(&mut self, _req: &Request<'_>) -> Result<(), libc::c_int> {
+        self.pool = Some(thread_pool::ThreadPool::new(self.threads));
+        println!("Initialized!");
+        Ok(())
+    }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry){
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry){
+        let cred = credentials(req);
+        let name = name.to_os_string();
+        let (entry_ttl, negative_ttl) = (self.entry_ttl, self.negative_ttl);
 
-        match self.controller.lookup(parent, name) {
-            Some(attr) => reply.entry(&time::Timespec{sec: 1, nsec: 0}, &attr , 0),
-            None => reply.error(libc::ENOENT)
-        };
+        self.spawn(move |controller| {
+            match controller.lookup(&cred, parent, &name) {
+                Ok(attr) => reply.entry(&entry_ttl, &attr , 0),
+                // 存在しないことをキャッシュさせる場合は、ENOENTの代わりにino 0のエントリを返す
+                Err(e) if is_negative(&e) && negative_ttl != time::Timespec::new(0, 0) => {
+                    reply.entry(&negative_ttl, &negative_entry(), 0)
+                },
+                Err(e) => reply.error(errno(&e))
+            };
+        });
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-		
-        match self.controller.getattr(ino) {
-            Some(attr) => reply.attr(&time::Timespec{sec: 1, nsec: 0}, &attr),
-            None => reply.error(libc::ENOENT)
-        }
-		return;
-    }
-
-    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
-		if offset == 0 {
-
-			let files_data = match self.controller.readdir(ino) {
-				Some(files_data) => files_data,
-				None => return reply.error(libc::ENOENT)
-			};
-			
-			for (i, file_data) in files_data.iter().enumerate() {
-				let offset: i64 = (i + 1).try_into().unwrap();
-				let ino = file_data.0;
-				let kind = file_data.2;
-				let name = file_data.1;
-
-				let full = reply.add(ino, offset, kind, name);
-				if full {
-					break;
-				}
-			}
-		}
-		reply.ok();
-	}
-
-    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData){
-        let data = match self.controller.read(ino, offset, size as u64) {
-            Some(data) => data,
-            None => return  reply.error(libc::ENOENT)
-        };
+        let attr_ttl = self.attr_ttl;
+
+        self.spawn(move |controller| {
+            match controller.getattr(ino) {
+                Ok(attr) => reply.attr(&attr_ttl, &attr),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn readdir(&mut self, req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let cred = credentials(req);
+
+        self.spawn(move |controller| {
+            if offset == 0 {
+
+                let files_data = match controller.readdir(&cred, ino) {
+                    Ok(files_data) => files_data,
+                    Err(e) => return reply.error(errno(&e))
+                };
+
+                for (i, file_data) in files_data.iter().enumerate() {
+                    let offset: i64 = (i + 1).try_into().unwrap();
+                    let ino = file_data.0;
+                    let kind = file_data.2;
+                    let name = &file_data.1;
+
+                    let full = reply.add(ino, offset, kind, name);
+                    if full {
+                        break;
+                    }
+                }
+            }
+            reply.ok();
+        });
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
+        let cred = credentials(req);
+
+        self.spawn(move |controller| {
+            match controller.open(&cred, ino, flags) {
+                Ok(fh) => reply.opened(fh, 0),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn release(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+        self.spawn(move |controller| {
+            match controller.release(fh) {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
+        let cred = credentials(req);
 
-        reply.data(data);
-    } 
+        self.spawn(move |controller| {
+            match controller.opendir(&cred, ino, flags) {
+                Ok(fh) => reply.opened(fh, 0),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn releasedir(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
+        self.spawn(move |controller| {
+            match controller.releasedir(fh) {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn read(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData){
+        let cred = credentials(req);
+
+        self.spawn(move |controller| {
+            let data = match controller.read(&cred, ino, Some(fh), offset, size as u64) {
+                Ok(data) => data,
+                Err(e) => return reply.error(errno(&e))
+            };
+
+            reply.data(&data);
+        });
+    }
 
     fn write(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         flags: u32,
         reply: ReplyWrite
     ) {
-        match self.controller.write(ino, offset as u64, data) {
-            Ok(size) => reply.written(size),
-            Err(_) => reply.error(libc::ENOENT)
-        }
+        let cred = credentials(req);
+        let data = data.to_vec();
+
+        self.spawn(move |controller| {
+            match controller.write(&cred, ino, Some(fh), offset, &data) {
+                Ok(size) => reply.written(size),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
     }
 
     fn setattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64, // 更新対象のinode番号
         mode: Option<u32>, // アクセス権
         uid: Option<u32>, // ファイル所有者のUID
@@ -121,61 +213,77 @@ impl<C: controller::Controller> Filesystem for FuseStruct<C> {
         size: Option<u64>, // ファイルサイズ
         atime: Option<time::Timespec>, // 最終アクセス時刻
         mtime: Option<time::Timespec>, // 最終更新時刻
-        _fh: Option<u64>,
+        fh: Option<u64>,
         crtime: Option<time::Timespec>, // mac用
         chgtime: Option<time::Timespec>, // mac用
         bkuptime: Option<time::Timespec>, // mac用
         flags: Option<u32>, // mac用
         reply: ReplyAttr
     ) {
-        match self.controller.setattr(ino, mode, uid, gid, size, atime, mtime) {
-            Ok(attr) => reply.attr(&time::Timespec{sec: 1, nsec: 0}, &attr),
-            Err(_) => reply.error(libc::ENOENT)
-        }
+        let cred = credentials(req);
+        let attr_ttl = self.attr_ttl;
+
+        self.spawn(move |controller| {
+            match controller.setattr(&cred, ino, fh, mode, uid, gid, size, atime, mtime) {
+                Ok(attr) => reply.attr(&attr_ttl, &attr),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
     }
 
     fn create(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         mode: u32,
         flags: u32,
         reply: ReplyCreate
     ) {
-        // すでにあるかチェック
-        // なかった場合modeを指定して作成
-        match self.controller.lookup(parent, name) {
-            Some(attr) => reply.created(&time::Timespec{sec: 1, nsec: 0}, &attr , 0, 0, 0),
-            None => {
-                match self.controller.create(parent, name, mode, flags) {
-                    Ok(attr) => reply.created(&time::Timespec{sec: 1, nsec: 0}, &attr , 0, 0, 0),
-                    Err(_) => reply.error(libc::ENOENT)
-                }
+        let cred = credentials(req);
+        let name = name.to_os_string();
+        let entry_ttl = self.entry_ttl;
+
+        self.spawn(move |controller| {
+            // すでにある場合はそのファイルを開き、なかった場合modeを指定して作成する
+            // 確かめるのと作成するのはusecaseが名前空間をロックしたまま行う
+            match controller.create(&cred, parent, &name, mode, flags) {
+                Ok((attr, fh)) => reply.created(&entry_ttl, &attr , 0, fh, 0),
+                Err(e) => reply.error(errno(&e))
             }
-        }
+        });
     }
 
     fn unlink(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         reply: ReplyEmpty
     ) {
-        match self.controller.unlink(parent, name) {
-            Ok(_) => reply.ok(),
-            Err(_) => reply.error(libc::ENOENT)
-        }
+        let cred = credentials(req);
+        let name = name.to_os_string();
+
+        self.spawn(move |controller| {
+            match controller.unlink(&cred, parent, &name) {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
     }
 
     fn forget(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
-        _nlookup: u64
+        ino: u64,
+        nlookup: u64
     ) {
-        self.controller.forget(_ino, _nlookup);
+        // forgetには返信しないため、失敗はログに残すだけにする
+        self.spawn(move |controller| {
+            if let Err(e) = controller.forget(ino, nlookup) {
+                eprintln!("Failed to forget {}: {}", ino, e);
+            }
+        });
     }
 
     fn mkdir(
@@ -186,43 +294,362 @@ impl<C: controller::Controller> Filesystem for FuseStruct<C> {
         mode: u32,
         reply: ReplyEntry
     ) {
-        // 親ディレクトリのSGIDがONの場合、子にSGIDを追加
-        // 親のスティッキービットがONの場合、子にスティッキービットを追加
+        let cred = credentials(req);
+        let name = name.to_os_string();
+        let entry_ttl = self.entry_ttl;
 
-        match self.controller.mkdir(parent, name, mode) {
-            Ok(attr) => reply.entry(&time::Timespec{sec: 1, nsec: 0}, &attr, 0),
-            Err(_) => reply.error(libc::ENOENT)
-        };
+        self.spawn(move |controller| {
+            match controller.mkdir(&cred, parent, &name, mode) {
+                Ok(attr) => reply.entry(&entry_ttl, &attr, 0),
+                Err(e) => reply.error(errno(&e))
+            };
+        });
     }
 
     fn rmdir(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         reply: ReplyEmpty
     ) {
-        match self.controller.rmdir(parent, name) {
-            Ok(_) => reply.ok(),
-            Err(_) => reply.error(libc::ENOENT)
-        }
+        let cred = credentials(req);
+        let name = name.to_os_string();
+
+        self.spawn(move |controller| {
+            match controller.rmdir(&cred, parent, &name) {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
     }
 
     fn rename(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
         reply: ReplyEmpty
     ) {
-        match self.controller.rename(parent, name, newparent, newname) {
-            Ok(_) => reply.ok(),
-            Err(_) => reply.error(libc::ENOENT)
+        let cred = credentials(req);
+        let name = name.to_os_string();
+        let newname = newname.to_os_string();
+
+        self.spawn(move |controller| {
+            match controller.rename(&cred, parent, &name, newparent, &newname) {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry
+    ) {
+        let cred = credentials(req);
+        let name = name.to_os_string();
+        let link = link.to_path_buf();
+        let entry_ttl = self.entry_ttl;
+
+        self.spawn(move |controller| {
+            match controller.symlink(&cred, parent, &name, &link) {
+                Ok(attr) => reply.entry(&entry_ttl, &attr, 0),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        self.spawn(move |controller| {
+            match controller.readlink(ino) {
+                Ok(link) => reply.data(&link),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn link(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry
+    ) {
+        let cred = credentials(req);
+        let newname = newname.to_os_string();
+        let entry_ttl = self.entry_ttl;
+
+        self.spawn(move |controller| {
+            match controller.link(&cred, ino, newparent, &newname) {
+                Ok(attr) => reply.entry(&entry_ttl, &attr, 0),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: u32, reply: ReplyEmpty) {
+        let cred = credentials(req);
+
+        self.spawn(move |controller| {
+            match controller.access(&cred, ino, mask) {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        _position: u32, // mac用
+        reply: ReplyEmpty
+    ) {
+        let cred = credentials(req);
+        let name = name.to_os_string();
+        let value = value.to_vec();
+
+        self.spawn(move |controller| {
+            match controller.setxattr(&cred, ino, &name, &value, flags) {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn getxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let cred = credentials(req);
+        let name = name.to_os_string();
+
+        self.spawn(move |controller| {
+            match controller.getxattr(&cred, ino, &name) {
+                Ok(value) => reply_xattr(&value, size, reply),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let cred = credentials(req);
+
+        self.spawn(move |controller| {
+            match controller.listxattr(&cred, ino) {
+                Ok(names) => reply_xattr(&names, size, reply),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let cred = credentials(req);
+        let name = name.to_os_string();
+
+        self.spawn(move |controller| {
+            match controller.removexattr(&cred, ino, &name) {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        self.spawn(move |controller| {
+            match controller.statfs() {
+                Ok(st) => reply.statfs(st.blocks, st.bfree, st.bavail, st.files, st.ffree, st.bsize, st.namelen, st.bsize),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    // close(2)のたびに呼ばれる
+    // まとめている変更を書き込んでから返す
+    fn flush(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        self.spawn(move |controller| {
+            match controller.flush() {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    // イメージのファイルをディスクに同期してから返す
+    // 属性とデータを同じファイルに書いているため、datasyncでも全て同期する
+    fn fsync(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        self.spawn(move |controller| {
+            match controller.fsync() {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    fn fsyncdir(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        self.spawn(move |controller| {
+            match controller.fsync() {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(errno(&e))
+            }
+        });
+    }
+
+    // fuseblk以外ではカーネルから呼ばれないため、アンマウント後の破棄でも同じことを行う
+    fn destroy(&mut self, _req: &Request<'_>) {
+        self.shutdown();
+    }
+}
+
+impl<C: controller::Controller> FuseStruct<C> {
+    // 処理中のリクエストを終えてから、イメージをコンパクションしてディスクに同期する
+    // initの前(マウントに失敗した場合)や、既に終えている場合は何もしない
+    fn shutdown(&mut self) {
+        let pool = match self.pool.take() {
+            Some(pool) => pool,
+            None => return
+        };
+        drop(pool);
+
+        if let Err(e) = self.controller.destroy() {
+            eprintln!("Failed compaction: {}", e);
         }
     }
+}
+
+impl<C: controller::Controller> Drop for FuseStruct<C> {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
 
+// 設定の読み込みで範囲外の値は弾いているが、負の秒数にならないよう上限で止める
+fn timespec(duration: Duration) -> time::Timespec {
+    let secs = i64::try_from(duration.as_secs()).unwrap_or(i64::MAX);
+    time::Timespec::new(secs, duration.subsec_nanos() as i32)
+}
+
+// 親ディレクトリに名前が存在しなかった場合
+fn is_negative(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<entity::Error>(), Some(entity::Error::NotFound))
+}
 
+// カーネルはino 0のエントリを、名前が存在しないこととしてキャッシュする
+fn negative_entry() -> fuse::FileAttr {
+    let epoch = time::Timespec::new(0, 0);
+
+    fuse::FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: epoch,
+        mtime: epoch,
+        ctime: epoch,
+        crtime: epoch,
+        kind: fuse::FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    }
 }
 
+// sizeが0なら必要なバッファの大きさを返す
+// バッファに収まらなければERANGEを返す
+fn reply_xattr(value: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+// リクエストを発行したプロセスのユーザとグループ
+// fuse 0.3.1はFUSE_DONT_MASKを求めないため、作成するファイルのmodeはカーネルがumaskを落としてから渡す
+fn credentials(req: &Request<'_>) -> credentials::Credentials {
+    credentials::Credentials::new(req.uid(), req.gid())
+}
+
+// usecaseのエラーをerrnoに変換する
+fn errno(e: &anyhow::Error) -> libc::c_int {
+    if let Some(e) = e.downcast_ref::<entity::Error>() {
+        return match e {
+            entity::Error::NotFound | entity::Error::InvalidINO => libc::ENOENT,
+            entity::Error::NotEmpty => libc::ENOTEMPTY,
+            entity::Error::Exists => libc::EEXIST,
+            entity::Error::NotDir => libc::ENOTDIR,
+            entity::Error::IsDir => libc::EISDIR,
+            entity::Error::PermissionDenied => libc::EACCES,
+            entity::Error::NotPermitted => libc::EPERM,
+            entity::Error::NameTooLong => libc::ENAMETOOLONG,
+            entity::Error::InvalidName | entity::Error::NotSymlink | entity::Error::InvalidArgument => libc::EINVAL,
+            entity::Error::NoXattr => libc::ENODATA,
+            entity::Error::XattrRange => libc::ERANGE,
+            entity::Error::XattrTooBig => libc::E2BIG,
+            entity::Error::NotSupported => libc::EOPNOTSUPP,
+            entity::Error::NoSpace => libc::ENOSPC,
+            entity::Error::QuotaExceeded => libc::EDQUOT,
+            entity::Error::FileTooBig => libc::EFBIG,
+            entity::Error::ReadOnly => libc::EROFS,
+            entity::Error::BadHandle => libc::EBADF,
+            _ => libc::EIO
+        };
+    }
+
+    if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        return match e.raw_os_error() {
+            Some(code) => code,
+            None => libc::EIO
+        };
+    }
+
+    libc::EIO
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errno_maps_usecase_errors() {
+        assert_eq!(errno(&entity::Error::NotFound.into()), libc::ENOENT);
+        assert_eq!(errno(&entity::Error::NotEmpty.into()), libc::ENOTEMPTY);
+        assert_eq!(errno(&entity::Error::Exists.into()), libc::EEXIST);
+        assert_eq!(errno(&entity::Error::NameTooLong.into()), libc::ENAMETOOLONG);
+        assert_eq!(errno(&entity::Error::PermissionDenied.into()), libc::EACCES);
+        assert_eq!(errno(&entity::Error::NotPermitted.into()), libc::EPERM);
+        assert_eq!(errno(&entity::Error::NoXattr.into()), libc::ENODATA);
+        assert_eq!(errno(&entity::Error::NotSupported.into()), libc::EOPNOTSUPP);
+        assert_eq!(errno(&entity::Error::QuotaExceeded.into()), libc::EDQUOT);
+        assert_eq!(errno(&entity::Error::ReadOnly.into()), libc::EROFS);
+        assert_eq!(errno(&entity::Error::BadHandle.into()), libc::EBADF);
+        assert_eq!(errno(&std::io::Error::from_raw_os_error(libc::ENOSPC).into()), libc::ENOSPC);
+        assert_eq!(errno(&entity::Error::InternalError.into()), libc::EIO);
+    }
+
+    #[test]
+    fn only_missing_names_are_cached_as_negative() {
+        assert!(is_negative(&entity::Error::NotFound.into()));
+        assert!(!is_negative(&entity::Error::PermissionDenied.into()));
+        assert!(!is_negative(&entity::Error::NotDir.into()));
+        assert_eq!(negative_entry().ino, 0);
+    }
+
+    #[test]
+    fn timespec_never_wraps_negative() {
+        assert_eq!(timespec(Duration::from_millis(1500)), time::Timespec::new(1, 500_000_000));
+        assert_eq!(timespec(Duration::MAX).sec, i64::MAX);
+    }
+}
@@ -0,0 +1,357 @@
+use std::path;
+use std::fs;
+use std::ffi::OsStr;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use crate::entity::{
+    self,
+    attr,
+    data,
+    entry
+};
+use anyhow::Result;
+
+// ホストのディレクトリツリーを読み込み、イメージに書き出せる状態を作る
+struct ImportStruct {
+    attrs: HashMap<u64, attr::Attr>,
+    entries: HashMap<u64, Vec<entry::Entry>>,
+    all_data: HashMap<u64, data::Data>,
+    next_ino: u64,
+    // ハードリンクを同じinodeにまとめるため、ホストの(dev, ino)と割り当てたinoを記録する
+    host_inos: HashMap<(u64, u64), u64>
+}
+
+pub const ROOT_INO: u64 = 1;
+
+pub fn import(dir: &path::Path) -> Result<(attr::AttrsStruct, entry::EntriesStruct, data::AllDataStruct)> {
+    let metadata = fs::metadata(dir)?;
+    if !metadata.is_dir() {
+        return Err(entity::Error::NotDir.into());
+    }
+
+    let mut import = ImportStruct{
+        attrs: HashMap::new(),
+        entries: HashMap::new(),
+        all_data: HashMap::new(),
+        next_ino: ROOT_INO + 1,
+        host_inos: HashMap::new()
+    };
+    import.import_dir(dir, ROOT_INO, &metadata)?;
+
+    Ok((
+        attr::AttrsStruct::new(import.attrs),
+        entry::EntriesStruct::new(import.entries),
+        data::AllDataStruct::new(import.all_data)
+    ))
+}
+
+// イメージのツリーをホストのディレクトリに書き出す
+// 書き出し先は存在しないか空のディレクトリでなければならない
+pub fn export(
+    attrs: &attr::AttrsStruct,
+    entries: &entry::EntriesStruct,
+    all_data: &data::AllDataStruct,
+    dir: &path::Path
+) -> Result<()> {
+    if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+        return Err(entity::Error::NotEmpty.into());
+    }
+    fs::create_dir_all(dir)?;
+
+    // 2つ目以降のハードリンクは最初に書き出したパスへのリンクにする
+    let mut exported: HashMap<u64, path::PathBuf> = HashMap::new();
+    // 子を書き出してからパーミッションと時刻を設定するため、ディレクトリを記録しておく
+    let mut dirs = vec![(dir.to_path_buf(), ROOT_INO)];
+
+    walk(attrs, entries, |child_path, attr| {
+        let host_path = dir.join(child_path);
+        // walk_dirで名前を確かめているが、書き出し先の外には決して書き込まない
+        if !host_path.starts_with(dir) || child_path.components().any(|c| !matches!(c, path::Component::Normal(_))) {
+            return Err(entity::Error::InvalidName.into());
+        }
+
+        if let Some(first_path) = exported.get(&attr.ino()) {
+            fs::hard_link(first_path, &host_path)?;
+            return Ok(());
+        }
+
+        match attr.kind() {
+            attr::FileType::Directory => {
+                fs::create_dir(&host_path)?;
+                dirs.push((host_path, attr.ino()));
+                return Ok(());
+            },
+            attr::FileType::TextFile => {
+                let data = match all_data.all_data(attr.ino()) {
+                    Some(data) => data.data(),
+                    None => &[]
+                };
+                fs::write(&host_path, data)?;
+                fs::set_permissions(&host_path, fs::Permissions::from_mode(attr.perm() as u32))?;
+                set_mtime(&host_path, attr, false)?;
+            },
+            attr::FileType::Symlink => {
+                let link = match all_data.all_data(attr.ino()) {
+                    Some(data) => data.data(),
+                    None => return Err(entity::Error::InvalidData.into())
+                };
+                symlink(OsStr::from_bytes(link), &host_path)?;
+                set_mtime(&host_path, attr, true)?;
+            }
+        }
+
+        exported.insert(attr.ino(), host_path);
+        Ok(())
+    })?;
+
+    // 深いディレクトリから設定し、親の書き込み権限や時刻を子の書き出しで崩さないようにする
+    for (host_path, ino) in dirs.iter().rev() {
+        if let Some(attr) = attrs.attr(*ino) {
+            fs::set_permissions(host_path, fs::Permissions::from_mode(attr.perm() as u32))?;
+            set_mtime(host_path, attr, false)?;
+        }
+    }
+
+    Ok(())
+}
+
+// ino 1から子をたどり、ルートからの相対パスとattrを親から順に渡す
+pub fn walk<F>(attrs: &attr::AttrsStruct, entries: &entry::EntriesStruct, mut visit: F) -> Result<()>
+    where F: FnMut(&path::Path, &attr::Attr) -> Result<()>
+{
+    let mut visited = HashSet::new();
+    walk_dir(attrs, entries, ROOT_INO, path::Path::new(""), &mut visited, &mut visit)
+}
+
+fn walk_dir<F>(
+    attrs: &attr::AttrsStruct,
+    entries: &entry::EntriesStruct,
+    ino: u64,
+    dir: &path::Path,
+    visited: &mut HashSet<u64>,
+    visit: &mut F
+) -> Result<()>
+    where F: FnMut(&path::Path, &attr::Attr) -> Result<()>
+{
+    // 壊れたイメージでディレクトリが循環していても止まるようにする
+    if !visited.insert(ino) {
+        return Err(entity::Error::InvalidEntry.into());
+    }
+
+    let child_inos = match entries.entry(ino) {
+        Some(child_inos) => child_inos,
+        None => return Err(entity::Error::InvalidEntry.into())
+    };
+
+    for child in child_inos.iter() {
+        let attr = match attrs.attr(child.child_ino()) {
+            Some(attr) => attr,
+            None => return Err(entity::Error::InvalidINO.into())
+        };
+        if !entry::valid_name(child.name()) {
+            return Err(entity::Error::InvalidName.into());
+        }
+        let child_path = dir.join(child.name());
+
+        visit(&child_path, attr)?;
+        if let attr::FileType::Directory = attr.kind() {
+            walk_dir(attrs, entries, child.child_ino(), &child_path, visited, visit)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn set_mtime(path: &path::Path, attr: &attr::Attr, symlink: bool) -> Result<()> {
+    let atime = filetime::FileTime::from_unix_time(attr.atime().as_secs() as i64, attr.atime().subsec_nanos());
+    let mtime = filetime::FileTime::from_unix_time(attr.mtime().as_secs() as i64, attr.mtime().subsec_nanos());
+
+    if symlink {
+        filetime::set_symlink_file_times(path, atime, mtime)?;
+    } else {
+        filetime::set_file_times(path, atime, mtime)?;
+    }
+
+    Ok(())
+}
+
+impl ImportStruct {
+    fn import_dir(&mut self, dir: &path::Path, ino: u64, metadata: &fs::Metadata) -> Result<()> {
+        let mut children: Vec<fs::DirEntry> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+        children.sort_by_key(|child| child.file_name());
+
+        let mut entries = Vec::new();
+        let mut nlink = 2;
+
+        for child in children {
+            let child_path = child.path();
+            let name = match child.file_name().to_str() {
+                Some(name) => name.to_string(),
+                None => {
+                    eprintln!("Skipped {:?}: name is not UTF-8", child_path);
+                    continue;
+                }
+            };
+            if name.len() > entity::NAME_MAX {
+                eprintln!("Skipped {:?}: name is too long", child_path);
+                continue;
+            }
+
+            let child_metadata = fs::symlink_metadata(&child_path)?;
+            let file_type = child_metadata.file_type();
+
+            // 既に読み込んだファイルへのハードリンクはnlinkを増やすだけにする
+            let host_ino = (child_metadata.dev(), child_metadata.ino());
+            if !file_type.is_dir() && child_metadata.nlink() > 1 {
+                if let Some(child_ino) = self.host_inos.get(&host_ino) {
+                    let child_ino = *child_ino;
+                    if let Some(attr) = self.attrs.get_mut(&child_ino) {
+                        attr.nlink += 1;
+                    }
+                    entries.push(entry::Entry::new(child_ino, name));
+                    continue;
+                }
+            }
+
+            let child_ino = self.next_ino;
+            if file_type.is_dir() {
+                self.next_ino += 1;
+                self.import_dir(&child_path, child_ino, &child_metadata)?;
+                nlink += 1;
+            } else if file_type.is_file() {
+                self.next_ino += 1;
+                let data = fs::read(&child_path)?;
+                self.attrs.insert(child_ino, new_attr(child_ino, data.len() as u64, attr::FileType::TextFile, &child_metadata));
+                self.all_data.insert(child_ino, data::Data::new(child_ino, data));
+            } else if file_type.is_symlink() {
+                self.next_ino += 1;
+                let link = fs::read_link(&child_path)?.as_os_str().as_bytes().to_vec();
+                self.attrs.insert(child_ino, new_attr(child_ino, link.len() as u64, attr::FileType::Symlink, &child_metadata));
+                self.all_data.insert(child_ino, data::Data::new(child_ino, link));
+            } else {
+                // デバイスファイルやソケットなどはhfsで表現できない
+                eprintln!("Skipped {:?}: unsupported file type", child_path);
+                continue;
+            }
+
+            self.host_inos.insert(host_ino, child_ino);
+            entries.push(entry::Entry::new(child_ino, name));
+        }
+
+        let mut attr = new_attr(ino, entries.len() as u64, attr::FileType::Directory, metadata);
+        attr.nlink = nlink;
+        self.attrs.insert(ino, attr);
+        self.entries.insert(ino, entries);
+
+        Ok(())
+    }
+}
+
+fn new_attr(ino: u64, size: u64, kind: attr::FileType, metadata: &fs::Metadata) -> attr::Attr {
+    attr::Attr::new(
+        ino,
+        size,
+        kind,
+        (metadata.mode() & 0o7777) as u16,
+        metadata.uid(),
+        metadata.gid(),
+        system_time(metadata.atime(), metadata.atime_nsec()),
+        system_time(metadata.mtime(), metadata.mtime_nsec()),
+        system_time(metadata.ctime(), metadata.ctime_nsec()),
+        1
+    )
+}
+
+// エポックより前の時刻は0として扱う
+fn system_time(sec: i64, nsec: i64) -> attr::SystemTime {
+    if sec < 0 {
+        return attr::SystemTime::new(0, 0);
+    }
+
+    attr::SystemTime::new(sec as u64, nsec as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn import_records_tree_links_and_contents() {
+        let dir = TempDir::new("import");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("file1"), b"hello").unwrap();
+        fs::write(dir.join("sub/bin"), [0xffu8, 0x00]).unwrap();
+        fs::hard_link(dir.join("file1"), dir.join("sub/link")).unwrap();
+        symlink("../file1", dir.join("sub/sym")).unwrap();
+
+        let (attrs, entries, data) = import(&dir).unwrap();
+
+        let root: Vec<&str> = entries.entry(ROOT_INO).unwrap().iter().map(|entry| entry.name()).collect();
+        assert_eq!(root, vec!["file1", "sub"]);
+        assert_eq!(attrs.attr(ROOT_INO).unwrap().nlink(), 3);
+        assert_eq!(attrs.attr(ROOT_INO).unwrap().size(), 2);
+
+        let file1 = entries.entry(ROOT_INO).unwrap()[0].child_ino();
+        let sub = entries.entry(ROOT_INO).unwrap()[1].child_ino();
+        let sub_entries = entries.entry(sub).unwrap();
+        assert_eq!(sub_entries[1].name(), "link");
+        assert_eq!(sub_entries[1].child_ino(), file1);
+        assert_eq!(attrs.attr(file1).unwrap().nlink(), 2);
+        assert_eq!(data.all_data(file1).unwrap().data(), b"hello");
+        assert_eq!(data.all_data(sub_entries[0].child_ino()).unwrap().data(), &[0xff, 0x00]);
+
+        let sym = sub_entries[2].child_ino();
+        assert!(matches!(attrs.attr(sym).unwrap().kind(), attr::FileType::Symlink));
+        assert_eq!(data.all_data(sym).unwrap().data(), b"../file1");
+    }
+
+    #[test]
+    fn export_restores_tree_perms_and_mtimes() {
+        let dir = TempDir::new("export");
+        fs::create_dir_all(dir.join("tree/sub")).unwrap();
+        fs::write(dir.join("tree/sub/file1"), b"hello").unwrap();
+        fs::set_permissions(dir.join("tree/sub/file1"), fs::Permissions::from_mode(0o640)).unwrap();
+        fs::hard_link(dir.join("tree/sub/file1"), dir.join("tree/link")).unwrap();
+        symlink("sub/file1", dir.join("tree/sym")).unwrap();
+        filetime::set_file_times(dir.join("tree/sub/file1"), filetime::FileTime::from_unix_time(1000, 0), filetime::FileTime::from_unix_time(2000, 5)).unwrap();
+        fs::set_permissions(dir.join("tree/sub"), fs::Permissions::from_mode(0o555)).unwrap();
+
+        let (attrs, entries, data) = import(&dir.join("tree")).unwrap();
+        export(&attrs, &entries, &data, &dir.join("out")).unwrap();
+
+        let file1 = fs::metadata(dir.join("out/sub/file1")).unwrap();
+        assert_eq!(fs::read(dir.join("out/sub/file1")).unwrap(), b"hello");
+        assert_eq!(file1.mode() & 0o7777, 0o640);
+        assert_eq!((file1.mtime(), file1.mtime_nsec()), (2000, 5));
+        assert_eq!(file1.nlink(), 2);
+        assert_eq!(fs::metadata(dir.join("out/link")).unwrap().ino(), file1.ino());
+        assert_eq!(fs::read_link(dir.join("out/sym")).unwrap(), path::Path::new("sub/file1"));
+        assert_eq!(fs::metadata(dir.join("out/sub")).unwrap().mode() & 0o7777, 0o555);
+    }
+
+    #[test]
+    fn export_rejects_names_that_leave_target() {
+        let dir = TempDir::new("export-escape");
+        let st = attr::SystemTime::new(0, 0);
+
+        for name in ["../escape", "/escape", "..", "a/b", ""] {
+            let mut attr_map = HashMap::new();
+            attr_map.insert(ROOT_INO, attr::Attr::new(ROOT_INO, 1, attr::FileType::Directory, 0o755, 0, 0, st, st, st, 2));
+            attr_map.insert(2, attr::Attr::new(2, 1, attr::FileType::TextFile, 0o644, 0, 0, st, st, st, 1));
+            let mut entry_map = HashMap::new();
+            entry_map.insert(ROOT_INO, vec![entry::Entry::new(2, name.to_string())]);
+            let mut data_map = HashMap::new();
+            data_map.insert(2, data::Data::new(2, b"x".to_vec()));
+            let out = dir.join("out/inner");
+
+            let result = export(&attr::AttrsStruct::new(attr_map), &entry::EntriesStruct::new(entry_map), &data::AllDataStruct::new(data_map), &out);
+
+            assert!(matches!(result.unwrap_err().downcast_ref::<entity::Error>(), Some(entity::Error::InvalidName)), "{:?}", name);
+            assert!(!dir.join("out/escape").exists());
+            assert!(!path::Path::new("/escape").exists());
+            fs::remove_dir_all(&out).unwrap();
+        }
+    }
+}
@@ -0,0 +1,243 @@
+extern crate yaml_rust;
+
+use std::path;
+use std::env;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use yaml_rust::YamlLoader;
+use crate::entity::{
+    self,
+    attr,
+    data,
+    entry,
+    limit,
+    xattr
+};
+use crate::interfaceadapter::worker;
+use super::{yaml_image, yaml_single_image};
+use anyhow::Result;
+
+// image.yamlのformatに従って、3つのファイルに分けたイメージか1つのファイルにまとめたイメージを扱う
+pub struct ImageStruct {
+    file: Option<Box<dyn worker::File>>,
+    read_only: bool
+}
+
+const FORMAT:       &str = "format";
+const SPLIT:        &str = "split";
+const SINGLE:       &str = "single";
+
+impl worker::File for ImageStruct {
+    fn init(&mut self, path: &path::Path) -> Result<(u64, attr::AttrsStruct, entry::EntriesStruct, data::AllDataStruct, xattr::XattrsStruct)> {
+        let mut file: Box<dyn worker::File> = match (format(path)?.as_str(), self.read_only) {
+            (SPLIT, false) => Box::new(yaml_image::YAMLImageStruct::new()),
+            (SPLIT, true) => Box::new(yaml_image::YAMLImageStruct::read_only()),
+            (SINGLE, false) => Box::new(yaml_single_image::YAMLSingleImageStruct::new()),
+            (SINGLE, true) => Box::new(yaml_single_image::YAMLSingleImageStruct::read_only()),
+            _ => return Err(entity::Error::InvalidData.into())
+        };
+        let files_data = file.init(path)?;
+        self.file = Some(file);
+
+        Ok(files_data)
+    }
+
+    fn write_data(&self, ino: u64, data: &[u8]) -> Result<()> {
+        self.file()?.write_data(ino, data)
+    }
+
+    fn update_attr(&self, attr: &attr::Attr) -> Result<()> {
+        self.file()?.update_attr(attr)
+    }
+
+    fn del_attr(&self, ino: u64) -> Result<()> {
+        self.file()?.del_attr(ino)
+    }
+
+    fn del_data(&self, ino: u64) -> Result<()> {
+        self.file()?.del_data(ino)
+    }
+
+    fn update_entry(&self, ino: u64, child_inos: &Vec<entry::Entry>) -> Result<()> {
+        self.file()?.update_entry(ino, child_inos)
+    }
+
+    fn update_xattr(&self, ino: u64, xattrs: &xattr::Xattrs) -> Result<()> {
+        self.file()?.update_xattr(ino, xattrs)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.file()?.sync()
+    }
+
+    fn compact(&self, attrs: &attr::AttrsStruct, entries: &entry::EntriesStruct, all_data: &data::AllDataStruct, xattrs: &xattr::XattrsStruct) -> Result<()> {
+        self.file()?.compact(attrs, entries, all_data, xattrs)
+    }
+
+    fn needs_compaction(&self, live: u64) -> bool {
+        match self.file() {
+            Ok(file) => file.needs_compaction(live),
+            Err(_) => false
+        }
+    }
+
+    fn limits(&self) -> limit::Limits {
+        match self.file() {
+            Ok(file) => file.limits(),
+            Err(_) => limit::Limits::default()
+        }
+    }
+
+    fn available(&self) -> Result<u64> {
+        self.file()?.available()
+    }
+}
+
+impl ImageStruct {
+    pub fn new() -> ImageStruct {
+        ImageStruct{
+            file: None,
+            read_only: false
+        }
+    }
+
+    // イメージのファイルを一切変更せずに読み込む
+    pub fn read_only() -> ImageStruct {
+        ImageStruct{
+            file: None,
+            read_only: true
+        }
+    }
+
+    // マウントせずに、image.yamlが指すファイルへ書き出すためだけに開く
+    // 変換先のイメージとして使う
+    pub fn open(path: &path::Path) -> Result<ImageStruct> {
+        let file: Box<dyn worker::File> = match format(path)?.as_str() {
+            SPLIT => Box::new(yaml_image::YAMLImageStruct::open(path)?),
+            SINGLE => Box::new(yaml_single_image::YAMLSingleImageStruct::open(path)?),
+            _ => return Err(entity::Error::InvalidData.into())
+        };
+
+        Ok(ImageStruct{
+            file: Some(file),
+            read_only: false
+        })
+    }
+
+    // image.yamlがなければ、同じディレクトリに4つのファイルを置く設定で作る
+    pub fn create(path: &path::Path) -> Result<()> {
+        if path.exists() {
+            return Ok(());
+        }
+
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.canonicalize()?,
+            _ => env::current_dir()?
+        };
+        fs::write(path, format!(
+            "{}: {}\nattr: {}\nentry: {}\ndata: {}\nxattr: {}\n",
+            FORMAT,
+            SPLIT,
+            dir.join("attr.yaml").display(),
+            dir.join("entry.yaml").display(),
+            dir.join("data.yaml").display(),
+            dir.join("xattr.yaml").display()
+        ))?;
+
+        Ok(())
+    }
+
+    fn file(&self) -> Result<&Box<dyn worker::File>> {
+        match &self.file {
+            Some(file) => Ok(file),
+            None => Err(entity::Error::InternalError.into())
+        }
+    }
+}
+
+// image.yamlのformatを返す
+// 省略時は3つのファイルに分けたイメージとして扱う
+fn format(path: &path::Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut config = String::new();
+    file.read_to_string(&mut config)?;
+    let docs = YamlLoader::load_from_str(&config)?;
+
+    match docs.get(0).and_then(|doc| doc[FORMAT].as_str()) {
+        Some(format) => Ok(format.to_string()),
+        None => Ok(SPLIT.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::time::Duration;
+    use crate::entity::{credentials, mount};
+    use crate::interfaceadapter::file_repository;
+    use crate::interfaceadapter::worker::File as _;
+    use crate::usecase::{self, Usecase};
+    use crate::testing::TempDir;
+
+    #[test]
+    fn converts_between_split_and_single() {
+        let dir = TempDir::new("image-convert");
+        fs::create_dir_all(dir.join("back")).unwrap();
+        let split = dir.template_image();
+        fs::write(dir.join("single.yaml"), format!("format: single\nimage: {}/hfs.yaml\n", dir.display())).unwrap();
+        fs::write(dir.join("back.yaml"), format!(
+            "format: split\nattr: {0}/back/attr.yaml\nentry: {0}/back/entry.yaml\ndata: {0}/back/data.yaml\n", dir.display()
+        )).unwrap();
+
+        let (_, attrs, entries, data, mut xattrs) = ImageStruct::new().init(&split).unwrap();
+        xattrs.set(1, "user.comment", b"root");
+        ImageStruct::open(&dir.join("single.yaml")).unwrap().compact(&attrs, &entries, &data, &xattrs).unwrap();
+
+        let (_, single_attrs, single_entries, single_data, single_xattrs) = ImageStruct::new().init(&dir.join("single.yaml")).unwrap();
+        ImageStruct::open(&dir.join("back.yaml")).unwrap().compact(&single_attrs, &single_entries, &single_data, &single_xattrs).unwrap();
+
+        let (_, back_attrs, back_entries, back_data, back_xattrs) = ImageStruct::new().init(&dir.join("back.yaml")).unwrap();
+        assert_eq!(back_xattrs.value(1, "user.comment"), Some(&b"root"[..]));
+        assert_eq!(back_attrs.attrs().len(), attrs.attrs().len());
+        for (ino, child_inos) in entries.entries() {
+            let names: Vec<&str> = child_inos.iter().map(|entry| entry.name()).collect();
+            let back_names: Vec<&str> = back_entries.entry(*ino).unwrap().iter().map(|entry| entry.name()).collect();
+            assert_eq!(names, back_names);
+        }
+        for (ino, attr) in attrs.attrs() {
+            assert_eq!(back_attrs.attr(*ino).unwrap().size(), attr.size());
+            if let Some(d) = data.all_data(*ino) {
+                assert_eq!(back_data.all_data(*ino).unwrap().data(), d.data());
+            }
+        }
+    }
+
+    #[test]
+    fn fsync_writes_queued_changes_to_image() {
+        let dir = TempDir::new("image-fsync");
+        let image = dir.template_image();
+        let config = fs::read_to_string(&image).unwrap();
+        fs::write(&image, format!("{}fsync: never\n", config)).unwrap();
+        // 時間や件数では書き込まず、fsyncまで変更をまとめておく
+        let options = mount::Options {
+            write_behind: mount::WriteBehind { interval: Duration::from_secs(60), batch: usize::MAX },
+            ..Default::default()
+        };
+        let mut usecase = usecase::with_options(file_repository::new(ImageStruct::new()), options);
+        usecase.init(&image).unwrap();
+        let cred = credentials::Credentials::root();
+        let child = |data: &data::AllDataStruct, entries: &entry::EntriesStruct| {
+            entries.child(1, "synced").map(|entry| data.all_data(entry.child_ino()).unwrap().data().to_vec())
+        };
+
+        let (attr, _) = usecase.create(&cred, 1, OsStr::new("synced"), 0o644, 0).unwrap();
+        usecase.write(&cred, attr.ino(), None, 0, b"durable").unwrap();
+        let (_, _, entries, data, _) = ImageStruct::read_only().init(&image).unwrap();
+        assert_eq!(child(&data, &entries), None);
+
+        usecase.fsync().unwrap();
+        let (_, _, entries, data, _) = ImageStruct::read_only().init(&image).unwrap();
+        assert_eq!(child(&data, &entries), Some(b"durable".to_vec()));
+    }
+}
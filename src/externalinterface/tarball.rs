@@ -0,0 +1,110 @@
+use std::path;
+use std::fs::File;
+use std::ffi::OsStr;
+use std::collections::HashMap;
+use std::os::unix::ffi::OsStrExt;
+use crate::entity::{
+    self,
+    attr,
+    data,
+    entry
+};
+use super::host_dir;
+use anyhow::Result;
+
+// イメージのツリーをtarファイルに書き出す
+pub fn export(
+    attrs: &attr::AttrsStruct,
+    entries: &entry::EntriesStruct,
+    all_data: &data::AllDataStruct,
+    tar_path: &path::Path
+) -> Result<()> {
+    let mut builder = tar::Builder::new(File::create(tar_path)?);
+    // 2つ目以降のハードリンクは最初に書き出したパスへのリンクにする
+    let mut exported: HashMap<u64, path::PathBuf> = HashMap::new();
+
+    host_dir::walk(attrs, entries, |child_path, attr| {
+        let mut header = header(attr);
+
+        if let Some(first_path) = exported.get(&attr.ino()) {
+            header.set_entry_type(tar::EntryType::Link);
+            builder.append_link(&mut header, child_path, first_path)?;
+            return Ok(());
+        }
+
+        match attr.kind() {
+            attr::FileType::Directory => {
+                header.set_entry_type(tar::EntryType::Directory);
+                builder.append_data(&mut header, child_path, &[][..])?;
+                return Ok(());
+            },
+            attr::FileType::TextFile => {
+                let data = match all_data.all_data(attr.ino()) {
+                    Some(data) => data.data(),
+                    None => &[]
+                };
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(data.len() as u64);
+                builder.append_data(&mut header, child_path, data)?;
+            },
+            attr::FileType::Symlink => {
+                let link = match all_data.all_data(attr.ino()) {
+                    Some(data) => data.data(),
+                    None => return Err(entity::Error::InvalidData.into())
+                };
+                header.set_entry_type(tar::EntryType::Symlink);
+                builder.append_link(&mut header, child_path, OsStr::from_bytes(link))?;
+            }
+        }
+
+        exported.insert(attr.ino(), child_path.to_path_buf());
+        Ok(())
+    })?;
+
+    builder.into_inner()?.sync_all()?;
+    Ok(())
+}
+
+fn header(attr: &attr::Attr) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+
+    header.set_mode(attr.perm() as u32);
+    header.set_uid(attr.uid() as u64);
+    header.set_gid(attr.gid() as u64);
+    header.set_mtime(attr.mtime().as_secs());
+    header.set_size(0);
+
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn export_writes_tree_into_tar() {
+        let dir = TempDir::new("tarball");
+        fs::create_dir_all(dir.join("tree/sub")).unwrap();
+        fs::write(dir.join("tree/sub/file1"), b"hello").unwrap();
+        fs::hard_link(dir.join("tree/sub/file1"), dir.join("tree/link")).unwrap();
+        std::os::unix::fs::symlink("sub/file1", dir.join("tree/sym")).unwrap();
+
+        let (attrs, entries, data) = host_dir::import(&dir.join("tree")).unwrap();
+        export(&attrs, &entries, &data, &dir.join("image.tar")).unwrap();
+
+        let mut archive = tar::Archive::new(File::open(dir.join("image.tar")).unwrap());
+        let mut kinds = HashMap::new();
+        for tar_entry in archive.entries().unwrap() {
+            let tar_entry = tar_entry.unwrap();
+            let path = tar_entry.path().unwrap().to_str().unwrap().trim_end_matches('/').to_string();
+            kinds.insert(path, tar_entry.header().entry_type());
+        }
+
+        assert_eq!(kinds.get("link"), Some(&tar::EntryType::Regular));
+        assert_eq!(kinds.get("sub"), Some(&tar::EntryType::Directory));
+        assert_eq!(kinds.get("sub/file1"), Some(&tar::EntryType::Link));
+        assert_eq!(kinds.get("sym"), Some(&tar::EntryType::Symlink));
+    }
+}
@@ -0,0 +1,80 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// fuseのリクエストを並行して処理するスレッド
+// 空いているスレッドがキューから順に取り出して処理する
+pub struct ThreadPool {
+    sender: Option<mpsc::Sender<Job>>,
+    threads: Vec<thread::JoinHandle<()>>
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut threads = Vec::with_capacity(size);
+
+        for _ in 0..size.max(1) {
+            let receiver = Arc::clone(&receiver);
+            threads.push(thread::spawn(move || loop {
+                let job = match receiver.lock() {
+                    Ok(receiver) => receiver.recv(),
+                    Err(_) => return
+                };
+                match job {
+                    // 1つのリクエストでpanicしてもスレッドは残す
+                    // 返信されなかったリクエストにはfuseがEIOを返す
+                    Ok(job) => { let _ = panic::catch_unwind(AssertUnwindSafe(job)); },
+                    Err(_) => return
+                }
+            }));
+        }
+
+        ThreadPool {
+            sender: Some(sender),
+            threads: threads
+        }
+    }
+
+    pub fn execute<F>(&self, job: F)
+        where F: FnOnce() + Send + 'static
+    {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(Box::new(job));
+        }
+    }
+}
+
+// キューに残っているリクエストを処理し終えてからスレッドを終了する
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.sender.take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn drop_waits_for_queued_jobs() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(4);
+
+        for _ in 0..100 {
+            let count = Arc::clone(&count);
+            pool.execute(move || { count.fetch_add(1, Ordering::SeqCst); });
+        }
+        pool.execute(|| panic!("job failed"));
+        drop(pool);
+
+        assert_eq!(count.load(Ordering::SeqCst), 100);
+    }
+}
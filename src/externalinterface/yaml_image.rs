@@ -1,17 +1,23 @@
 extern crate yaml_rust;
 
 use std::path;
+use std::cell::Cell;
 use std::fs::{self, File};
 use std::io::prelude::*;
 use std::io::Write;
 use std::collections::HashMap;
 use std::env;
+use std::str;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
 use yaml_rust::{YamlLoader, YamlEmitter, Yaml};
 use crate::entity::{
     self,
     attr,
     data,
-    entry
+    entry,
+    limit,
+    xattr
 };
 use crate::interfaceadapter::worker;
 use anyhow::Result;
@@ -20,12 +26,34 @@ use anyhow::Result;
 pub struct YAMLImageStruct {
     entry: path::PathBuf,
     attr: path::PathBuf,
-    data: path::PathBuf
+    data: path::PathBuf,
+    xattr: path::PathBuf,
+    // 各ファイルに追記されているレコードの総数
+    records: Cell<u64>,
+    compaction_ratio: f64,
+    fsync: Fsync,
+    limits: limit::Limits,
+    // 読み込み時も含めてファイルを一切変更しない
+    read_only: bool,
+    // コンパクションの置き換えが途中で失敗し、まだ終えていない
+    unfinished: Cell<bool>
+}
+
+// 追記したレコードをいつディスクに同期するか
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Fsync {
+    // レコードごとに同期する
+    Always,
+    // OSに任せる
+    Never
 }
 
 const ATTR:         &str = "attr";
 const DATA:         &str = "data";
 const ENTRY:        &str = "entry";
+const XATTR:        &str = "xattr";
+const XATTRS:       &str = "xattrs";
+const VALUE:        &str = "value";
 const INO:          &str = "ino";
 const FILE_TYPE:    &str = "file-type";
 const PARENT_INO:   &str = "parent-ino";
@@ -42,133 +70,252 @@ const MTIME:        &str = "mtime";
 const CTIME:        &str = "ctime";
 const NLINK:        &str = "nlink";
 const DEL:          &str = "del";
+const ENCODING:     &str = "encoding";
+const BASE64:       &str = "base64";
+const COMPACTION_RATIO: &str = "compaction-ratio";
+const FSYNC:        &str = "fsync";
+const ALWAYS:       &str = "always";
+const NEVER:        &str = "never";
+const CAPACITY:     &str = "capacity";
+const MAX_INODES:   &str = "max-inodes";
+const MAX_FILE_SIZE: &str = "max-file-size";
+const QUOTAS:       &str = "quotas";
+const BYTES:        &str = "bytes";
+const INODES:       &str = "inodes";
+// コンパクションの一時ファイルと、全ての一時ファイルを書き終えたことを示す印
+const TMP_SUFFIX:   &str = ".tmp";
+const COMMIT_SUFFIX: &str = ".compacting";
+// 各レコードの終わりを示す行
+// これより後ろにあるのは書き込み途中で途切れたレコード
+const RECORD_END:   &str = "# end\n";
 
 const ATTR_DEFAULT_PATH: &str = "/etc/attr.yaml";
 const ENTRY_DEFAULT_PATH: &str = "/etc/entry.yaml";
 const DATA_DEFAULT_PATH: &str = "/etc/data.yaml";
+// 拡張属性のファイルを持たない古いimage.yamlでは、attr.yamlと同じディレクトリに置く
+const XATTR_DEFAULT_NAME: &str = "xattr.yaml";
+// レコード数が生存しているレコード数のこの倍数を超えたらコンパクションする
+pub(super) const COMPACTION_RATIO_DEFAULT: f64 = 4.0;
 
 const DIRECTORY: u64 = 0;
 const TXTFILE: u64 = 1;
+const SYMLINK: u64 = 2;
 
 impl worker::File for YAMLImageStruct {
-    fn init(&mut self, path: &path::Path) -> Result<(u64, attr::AttrsStruct, entry::EntriesStruct, data::AllDataStruct)> {
+    fn init(&mut self, path: &path::Path) -> Result<(u64, attr::AttrsStruct, entry::EntriesStruct, data::AllDataStruct, xattr::XattrsStruct)> {
         self.load_image(path)?;
-        let entries = entry::EntriesStruct::new(self.load_entry()?);
+        self.recover_compaction()?;
         let (attrs_res, next_ino) = self.load_attr();
-        let attrs = attr::AttrsStruct::new(attrs_res?);
+        let (attrs_hash, names) = attrs_res?;
+        let entries = entry::EntriesStruct::new(self.load_entry(&names)?);
+        let attrs = attr::AttrsStruct::new(attrs_hash);
         let data = data::AllDataStruct::new(self.load_data()?);
-        
-        Ok((next_ino, attrs, entries, data))
-    }
-
-    fn write_data(&self, ino: u64, data: &str) -> Result<()> {
-        let data_path = match self.data.to_str() {
-            Some(data_path) => data_path,
-            None => return Err(entity::Error::InternalError.into())
-        };
+        let xattrs = xattr::XattrsStruct::new(self.load_xattr()?);
 
-        let mut file = fs::OpenOptions::new()
-            .append(true)
-            .open(data_path)?;
+        Ok((next_ino, attrs, entries, data, xattrs))
+    }
 
-        file.write_all(format!("- ino: {}\n  data: {:?}\n", ino, data).as_bytes())?;
-        return Ok(());
+    fn write_data(&self, ino: u64, data: &[u8]) -> Result<()> {
+        self.append(&self.data, &data_record(ino, data))
     }
 
     fn update_attr(&self, attr: &attr::Attr) -> Result<()> {
-        let attr_path = match self.attr.to_str() {
-            Some(attr_path) => attr_path,
-            None => return Err(entity::Error::InternalError.into())
-        };
-        let mut file = fs::OpenOptions::new()
-            .append(true)
-            .open(attr_path)?;
-        let file_type = match attr.file_type() {
-            attr::FileType::TextFile => 1,
-            attr::FileType::Directory => 0
-        };
-
-        file.write_all(
-            format!(
-                "- ino: {}\n  name: {}\n  file-type: {}\n  size: {}\n  uid: {}\n  gid: {}\n  perm: 0o{:o}\n  atime: \"{}.{}\"\n  mtime: \"{}.{}\"\n  ctime: \"{}.{}\"\n  nlink: {}\n",
-                attr.ino(),
-                attr.name(),
-                file_type,
-                attr.size(),
-                attr.uid(),
-                attr.gid(),
-                attr.perm(),
-                attr.atime.as_secs(),
-                attr.atime.subsec_nanos(),
-                attr.mtime.as_secs(),
-                attr.mtime.subsec_nanos(),
-                attr.ctime.as_secs(),
-                attr.ctime.subsec_nanos(),
-                attr.nlink()
-            ).as_bytes()
-        )?;
-        return Ok(());
+        self.append(&self.attr, &attr_record(attr))
     }
 
     fn del_attr(&self, ino: u64) -> Result<()> {
-        let attr_path = match self.attr.to_str() {
-            Some(attr_path) => attr_path,
-            None => return Err(entity::Error::InternalError.into())
-        };
-        let mut file = fs::OpenOptions::new()
-            .append(true)
-            .open(attr_path)?;
-        file.write_all(
-            format!(
-                "- ino: {}\n  del: {}\n",
-                ino,
-                true
-            ).as_bytes()
-        )?;
-
-        Ok(())
+        self.append(&self.attr, &format!("- ino: {}\n  del: {}\n", ino, true))
     }
 
     fn del_data(&self, ino: u64) -> Result<()> {
-        let data_path = match self.data.to_str() {
-            Some(data_path) => data_path,
-            None => return Err(entity::Error::InternalError.into())
-        };
+        self.append(&self.data, &format!("- ino: {}\n  del: {}\n", ino, true))
+    }
 
-        let mut file = fs::OpenOptions::new()
-            .append(true)
-            .open(data_path)?;
+    fn update_entry(&self, ino: u64, child_inos: &Vec<entry::Entry>) -> Result<()> {
+        self.append(&self.entry, &entry_record(ino, child_inos))
+    }
 
-        file.write_all(format!("- ino: {}\n  del: {}\n", ino, true).as_bytes())?;
-        return Ok(());    
+    fn update_xattr(&self, ino: u64, xattrs: &xattr::Xattrs) -> Result<()> {
+        self.append(&self.xattr, &xattr_record(ino, xattrs))
     }
 
-    fn update_entry(&self, ino: u64, child_inos: &Vec<entry::Entry>) -> Result<()> {
-        let entry_path = match self.entry.to_str() {
-            Some(entry_path) => entry_path,
-            None => return Err(entity::Error::InternalError.into())
-        };
-        let mut file = fs::OpenOptions::new()
-            .append(true)
-            .open(entry_path)?;
+    // 読み取り専用の場合は書き込んでいないため何もしない
+    fn sync(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        sync(&self.attr)?;
+        sync(&self.entry)?;
+        sync(&self.data)?;
+        sync(&self.xattr)
+    }
 
-        file.write_all(format!("- ino: {}\n  files:\n", ino).as_bytes())?;
-        for entry in child_inos {
-            file.write_all(format!("    - {}\n", entry.child_ino()).as_str().as_bytes())?;
+    fn compact(&self, attrs: &attr::AttrsStruct, entries: &entry::EntriesStruct, all_data: &data::AllDataStruct, xattrs: &xattr::XattrsStruct) -> Result<()> {
+        if self.read_only {
+            return Err(entity::Error::ReadOnly.into());
         }
+        let records = self.stage_compaction(attrs, entries, all_data, xattrs)?;
+        self.finish_compaction()?;
+        self.records.set(records);
 
-        return Ok(());
+        Ok(())
+    }
+
+    fn needs_compaction(&self, live: u64) -> bool {
+        needs_compaction(self.records.get(), live, self.compaction_ratio)
+    }
+
+    fn limits(&self) -> limit::Limits {
+        self.limits.clone()
+    }
+
+    fn available(&self) -> Result<u64> {
+        available(&self.data)
     }
 }
 
 impl YAMLImageStruct {
     pub fn new() -> impl worker::File {
+        YAMLImageStruct::with_mode(false)
+    }
+
+    // 読み取り専用のメディアに置かれたイメージでも読み込めるように、ファイルを変更せずに開く
+    pub fn read_only() -> impl worker::File {
+        YAMLImageStruct::with_mode(true)
+    }
+
+    // マウントせずに、image.yamlが指すファイルへ書き出すためだけに開く
+    pub fn open(path: &path::Path) -> Result<YAMLImageStruct> {
+        let mut image = YAMLImageStruct::with_mode(false);
+        image.load_image(path)?;
+
+        Ok(image)
+    }
+
+    fn with_mode(read_only: bool) -> YAMLImageStruct {
         YAMLImageStruct{
             attr: path::PathBuf::from(ATTR_DEFAULT_PATH),
             entry: path::PathBuf::from(ENTRY_DEFAULT_PATH),
-            data: path::PathBuf::from(DATA_DEFAULT_PATH)
+            data: path::PathBuf::from(DATA_DEFAULT_PATH),
+            xattr: path::PathBuf::from(ATTR_DEFAULT_PATH).with_file_name(XATTR_DEFAULT_NAME),
+            records: Cell::new(0),
+            compaction_ratio: COMPACTION_RATIO_DEFAULT,
+            fsync: Fsync::Always,
+            limits: limit::Limits::default(),
+            read_only: read_only,
+            unfinished: Cell::new(false)
         }
     }
+
+    fn append(&self, path: &path::Path, record: &str) -> Result<()> {
+        if self.read_only {
+            return Err(entity::Error::ReadOnly.into());
+        }
+        // 置き換えていない古いログに追記すると、次の読み込みで一時ファイルに上書きされて失われる
+        if self.unfinished.get() {
+            self.finish_compaction()?;
+        }
+        append(path, record, self.fsync)?;
+        self.records.set(self.records.get() + 1);
+        Ok(())
+    }
+
+    // 古いログとコンパクション後のファイルが混ざると、存在しないinodeを指すエントリが読み込まれる
+    // 全ての一時ファイルを書き終えてから印を作り、印がある間は置き換えを最後まで進める
+    // 印を作る前に止まった場合は古いログのまま、作った後に止まった場合は次の読み込みで置き換えを終える
+    fn stage_compaction(&self, attrs: &attr::AttrsStruct, entries: &entry::EntriesStruct, all_data: &data::AllDataStruct, xattrs: &xattr::XattrsStruct) -> Result<u64> {
+        let (attr_records, entry_records, data_records, xattr_records, records) = snapshot(attrs, entries, all_data, xattrs);
+
+        write_tmp(&self.attr, &attr_records)?;
+        write_tmp(&self.entry, &entry_records)?;
+        write_tmp(&self.data, &data_records)?;
+        write_tmp(&self.xattr, &xattr_records)?;
+        // 印を作り始めたら、以降の追記の前に置き換えを終える
+        self.unfinished.set(true);
+        let commit = self.commit_path();
+        File::create(&commit)?.sync_all()?;
+        sync_dir(&commit)?;
+
+        Ok(records)
+    }
+
+    // 印はattr.yamlと同じディレクトリに置く
+    fn commit_path(&self) -> path::PathBuf {
+        with_suffix(&self.attr, COMMIT_SUFFIX)
+    }
+
+    // 一時ファイルが残っているものを置き換え、最後に印を消す
+    fn finish_compaction(&self) -> Result<()> {
+        for path in [&self.attr, &self.entry, &self.data, &self.xattr] {
+            replace_with_tmp(path)?;
+        }
+        let commit = self.commit_path();
+        if commit.exists() {
+            fs::remove_file(&commit)?;
+            sync_dir(&commit)?;
+        }
+        self.unfinished.set(false);
+
+        Ok(())
+    }
+
+    // 前回のコンパクションが途中で止まっていれば、印の有無で置き換えを終えるか取り消す
+    // 読み取り専用ではファイルを変更せず、置き換えるはずだった一時ファイルから読み込む
+    fn recover_compaction(&mut self) -> Result<()> {
+        let committed = self.commit_path().exists();
+
+        if self.read_only {
+            if committed {
+                for path in [&mut self.attr, &mut self.entry, &mut self.data, &mut self.xattr] {
+                    let tmp = with_suffix(path, TMP_SUFFIX);
+                    if tmp.exists() {
+                        *path = tmp;
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        if committed {
+            eprintln!("Finishing interrupted compaction of {:?}", self.attr);
+            return self.finish_compaction();
+        }
+        // 印がなければ書き途中の一時ファイルなので捨てる
+        for path in [&self.attr, &self.entry, &self.data, &self.xattr] {
+            let tmp = with_suffix(path, TMP_SUFFIX);
+            if tmp.exists() {
+                fs::remove_file(&tmp)?;
+            }
+        }
+        Ok(())
+    }
+
+    // ファイル内のレコードを読み込む
+    // 空のファイルはレコードなしとして扱う
+    fn load_records(&self, path: &path::Path) -> Result<Vec<Yaml>> {
+        let config = read_log(path, self.read_only)?;
+        let docs = YamlLoader::load_from_str(&config)?;
+        let records = match docs.into_iter().next() {
+            Some(Yaml::Array(records)) => records,
+            Some(Yaml::Null) | None => Vec::new(),
+            Some(_) => return Err(entity::Error::InvalidEntry.into())
+        };
+
+        // 終端行を持たない古い形式のファイルは、読み込めた内容の後ろに終端行を付けておく
+        // 以降に追記したレコードが途切れても検出できるようにする
+        // 読み取り専用では追記しないので必要ない
+        if !self.read_only && !records.is_empty() && !config.contains(RECORD_END) {
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .open(path)?;
+            let separator = if config.ends_with('\n') { "" } else { "\n" };
+            file.write_all(format!("{}{}", separator, RECORD_END).as_bytes())?;
+            file.sync_data()?;
+        }
+
+        self.records.set(self.records.get() + records.len() as u64);
+        Ok(records)
+    }
     
     fn load_image(&mut self, path: &path::Path) -> Result<()> {
         let mut file = match File::open(path) {
@@ -185,250 +332,891 @@ impl YAMLImageStruct {
             Err(e) => return Err(e.into())
         };
 
-        let mut attr = match &docs[0][ATTR] {
-            Yaml::String(s) => s.clone(),
-            _ => ATTR_DEFAULT_PATH.to_string()
+        self.attr = image_path(&docs[0], ATTR, ATTR_DEFAULT_PATH)?;
+        self.entry = image_path(&docs[0], ENTRY, ENTRY_DEFAULT_PATH)?;
+        self.data = image_path(&docs[0], DATA, DATA_DEFAULT_PATH)?;
+        self.xattr = match &docs[0][XATTR] {
+            Yaml::String(_) => image_path(&docs[0], XATTR, "")?,
+            _ => self.attr.with_file_name(XATTR_DEFAULT_NAME)
         };
+        self.compaction_ratio = compaction_ratio(&docs[0]);
+        self.fsync = fsync(&docs[0])?;
+        self.limits = limits(&docs[0])?;
+
+        return Ok(());
+    }
+
+    // 名前を持たない古い形式のエントリはattr.yamlのnameを利用する
+    fn load_entry(&self, names: &HashMap<u64, String>) -> Result<HashMap<u64, Vec<entry::Entry>>> {
+        let records = self.load_records(&self.entry)?;
+        let mut entrie_hash = HashMap::new();
+
+        for entry_data in records.iter() {
+            apply_entry(entry_data, &mut entrie_hash, names)?;
+        }
 
-        let mut entry = match &docs[0][ENTRY] {
-            Yaml::String(s) => s.clone(),
-            _ => ENTRY_DEFAULT_PATH.to_string()
+        return Ok(entrie_hash);
+    }
+
+    fn load_attr(&self) -> (Result<(HashMap<u64, attr::Attr>, HashMap<u64, String>)>, u64) {
+        let records = match self.load_records(&self.attr) {
+            Ok(records) => records,
+            Err(e) => return (Err(e), 0)
         };
+        let mut attrs_hash = HashMap::new();
+        let mut names = HashMap::new();
+        let mut next_ino = 0;
         
-        let mut data = match &docs[0][DATA] {
-            Yaml::String(s) => s.clone(),
-            _ => DATA_DEFAULT_PATH.to_string()
-        };
+        for attr_data in records.iter() {
+            match apply_attr(attr_data, &mut attrs_hash, &mut names) {
+                Ok(Some(ino)) => {
+                    if ino >= next_ino {
+                        next_ino = ino + 1;
+                    }
+                },
+                Ok(None) => {},
+                Err(e) => return (Err(e), 0)
+            }
+        }
 
-        let current_dir = match env::current_dir()?.as_os_str().to_str() {
-            Some(path) => String::from(path),
-            None => String::from("/")
-        };
+        return (Ok((attrs_hash, names)), next_ino);
+    }
+    
+    fn load_data(&self) -> Result<HashMap<u64, data::Data>> {
+        let records = self.load_records(&self.data)?;
+        let mut data_hash = HashMap::new();
 
-        if *&attr.chars().nth(0).unwrap() == '.' {
-            attr.replace_range(..1, &current_dir)
+        for data in records.iter() {
+            apply_data(data, &mut data_hash)?;
         }
 
-        if *&entry.chars().nth(0).unwrap() == '.' {
-            entry.replace_range(..1, &current_dir)
+        return Ok(data_hash);
+    }
+
+    // 拡張属性のファイルは後から追加したため、なければ空のファイルを作る
+    // 読み取り専用では作らずに拡張属性なしとして扱う
+    fn load_xattr(&self) -> Result<HashMap<u64, xattr::Xattrs>> {
+        if !self.xattr.exists() {
+            if self.read_only {
+                return Ok(HashMap::new());
+            }
+            File::create(&self.xattr)?.sync_all()?;
         }
+        let records = self.load_records(&self.xattr)?;
+        let mut xattr_hash = HashMap::new();
 
-        if *&data.chars().nth(0).unwrap() == '.' {
-            data.replace_range(..1, &current_dir)
+        for xattr_data in records.iter() {
+            apply_xattr(xattr_data, &mut xattr_hash)?;
         }
 
-        self.attr = path::PathBuf::from(attr);
-        self.entry = path::PathBuf::from(entry);
-        self.data = path::PathBuf::from(data);
+        return Ok(xattr_hash);
+    }
+}
 
-        println!("{:?}\n{:?}\n{:?}\n", self.attr, self.entry, self.data);
+// attr.yamlのレコードを1件反映し、追加したinoを返す
+pub(super) fn apply_attr(
+    attr_data: &Yaml,
+    attrs_hash: &mut HashMap<u64, attr::Attr>,
+    names: &mut HashMap<u64, String>
+) -> Result<Option<u64>> {
+    let ino = match &attr_data[INO] {
+        Yaml::Integer(i) => *i as u64,
+        _ => return Err(entity::Error::InvalidINO.into())
+    };
 
-        return Ok(());
+    match &attr_data[DEL] {
+        Yaml::Boolean(flg) => {
+            if *flg {
+                attrs_hash.remove(&ino);
+                return Ok(None);
+            }
+        },
+        _ => {}
     }
 
-    fn load_entry(&self) -> Result<HashMap<u64, Vec<entry::Entry>>> {
-        let mut file = match File::open(&self.entry) {
-            Ok(file) => file,
-            Err(e) => {
-				return Err(e.into());
-			}
-        };
+    // 古い形式のattr.yamlのみnameを持つ
+    match &attr_data[NAME] {
+        Yaml::String(s) => { names.insert(ino, s.clone()); },
+        Yaml::BadValue => {},
+        _ => return Err(entity::Error::InvalidName.into())
+    };
+    let file_type = match &attr_data[FILE_TYPE] {
+        Yaml::Integer(i) =>{
+            match *i as u64 {
+                DIRECTORY => attr::FileType::Directory,
+                TXTFILE => attr::FileType::TextFile,
+                SYMLINK => attr::FileType::Symlink,
+                _ => attr::FileType::TextFile
+            }
+        },
+        _ => return Err(entity::Error::InvalidFileType.into())
+    };
+    let size = match &attr_data[SIZE] {
+        Yaml::Integer(i) => {
+            *i as u64
+        },
+        _ => return Err(entity::Error::InvalidSize.into())
+    };
+    let uid = match &attr_data[UID] {
+        Yaml::Integer(i) => *i as u32,
+        _ => return Err(entity::Error::InvalidUID.into())
+    };
+    let gid = match &attr_data[GID] {
+        Yaml::Integer(i) => *i as u32,
+        _ => return Err(entity::Error::InvalidGID.into())
+    };
+    let perm = match &attr_data[PERM] {
+        Yaml::Integer(i) => *i as u16,
+        _ => return Err(entity::Error::InvalidPERM.into())
+    };
+    let atime = match parse_time(&attr_data[ATIME]) {
+        Some(time) => time,
+        None => return Err(entity::Error::InvalidAtime.into())
+    };
+    let mtime = match parse_time(&attr_data[MTIME]) {
+        Some(time) => time,
+        None => return Err(entity::Error::InvalidAtime.into())
+    };
+    let ctime = match parse_time(&attr_data[CTIME]) {
+        Some(time) => time,
+        None => return Err(entity::Error::InvalidAtime.into())
+    };
 
-        let mut config = String::new();
-        match file.read_to_string(&mut config) {
-            Ok(_) => {}
-            Err(e) => return Err(e.into())
-        }
-        let docs = match YamlLoader::load_from_str(&config) {
-            Ok(docs) => docs,
-            Err(e) => return Err(e.into())
-        };
-        let mut entrie_hash = HashMap::new();
+    let nlink = match &attr_data[NLINK] {
+        Yaml::Integer(i) => *i as u32,
+        _ => return Err(entity::Error::InvalidNlink.into())
+    };
 
-        for entry_data in docs[0].as_vec().unwrap() {
-            let mut entries = Vec::new();
-            let ino = match &entry_data[INO] {
-                Yaml::Integer(i) => *i as u64,
-                _ => return Err(entity::Error::InvalidINO.into())
-            };
-
-            match &entry_data[FILES] {
-                Yaml::Array(child_inos_data) => {
-                    for child_ino_data in child_inos_data {
-                        let child_ino = match child_ino_data {
-                            Yaml::Integer(i) => *i as u64,
-                            _ => return Err(entity::Error::InvalidINO.into())
-                        };
-
-                        entries.push(entry::Entry::new(child_ino));
-                    }
-                },
-                _ => {}
-            }
+    attrs_hash.insert(ino, attr::Attr::new(ino, size, file_type, perm, uid, gid, atime, mtime, ctime, nlink));
 
-            entrie_hash.insert(ino, entries);
-        }
+    Ok(Some(ino))
+}
 
-        return Ok(entrie_hash);
+// "秒.ナノ秒"の形式の時刻を読み込む
+fn parse_time(time: &Yaml) -> Option<attr::SystemTime> {
+    let (secs, nanos) = match time {
+        Yaml::String(s) => match s.split_once('.') {
+            Some(epoc) => epoc,
+            None => return None
+        },
+        _ => return None
+    };
+
+    match (secs.parse(), nanos.parse()) {
+        (Ok(secs), Ok(nanos)) => Some(attr::SystemTime(secs, nanos)),
+        _ => None
     }
+}
 
-    fn load_attr(&self) -> (Result<HashMap<u64, attr::Attr>>, u64) {
-        let mut file = match File::open(&self.attr) {
-            Ok(file) => file,
-            Err(e) => return (Err(e.into()), 0)
-        };
-        let mut config = String::new();
-        match file.read_to_string(&mut config) {
-            Ok(_) => {}
-            Err(e) => return (Err(e.into()), 0)
-        };
-        let docs = match YamlLoader::load_from_str(&config) {
-            Ok(docs) => docs,
-            Err(e) => return (Err(e.into()), 0)
-        };
-        let mut attrs_hash = HashMap::new();
-        let mut next_ino = 0;
-        
-        for attr_data in docs[0].as_vec().unwrap() {
-            let ino = match &attr_data[INO] {
-                Yaml::Integer(i) => *i as u64,
-                _ => return (Err(entity::Error::InvalidINO.into()), 0)
-            };
-
-            match &attr_data[DEL] {
-                Yaml::Boolean(flg) => {
-                    if *flg {
-                        attrs_hash.remove(&ino);
-                        continue;
-                    }
-                },
-                _ => {}
+pub(super) fn apply_entry(
+    entry_data: &Yaml,
+    entrie_hash: &mut HashMap<u64, Vec<entry::Entry>>,
+    names: &HashMap<u64, String>
+) -> Result<()> {
+    let mut entries = Vec::new();
+    let ino = match &entry_data[INO] {
+        Yaml::Integer(i) => *i as u64,
+        _ => return Err(entity::Error::InvalidINO.into())
+    };
+
+    match &entry_data[FILES] {
+        Yaml::Array(child_inos_data) => {
+            for child_ino_data in child_inos_data {
+                let (child_ino, name) = match child_ino_data {
+                    Yaml::Integer(i) => match names.get(&(*i as u64)) {
+                        Some(name) => (*i as u64, name.clone()),
+                        None => return Err(entity::Error::InvalidName.into())
+                    },
+                    Yaml::Hash(_) => match (&child_ino_data[INO], &child_ino_data[NAME]) {
+                        (Yaml::Integer(i), Yaml::String(name)) => (*i as u64, name.clone()),
+                        (Yaml::Integer(_), _) => return Err(entity::Error::InvalidName.into()),
+                        _ => return Err(entity::Error::InvalidINO.into())
+                    },
+                    _ => return Err(entity::Error::InvalidINO.into())
+                };
+                // 書き出すときにイメージの外を指さないよう、パスとして解釈される名前は読み込まない
+                if !entry::valid_name(&name) {
+                    return Err(entity::Error::InvalidName.into());
+                }
+
+                entries.push(entry::Entry::new(child_ino, name));
             }
+        },
+        _ => {}
+    }
 
-            let name = match &attr_data[NAME] {
-                Yaml::String(s) => s.clone(),
-                _ => return (Err(entity::Error::InvalidName.into()), 0)
-            };
-            let file_type = match &attr_data[FILE_TYPE] {
-                Yaml::Integer(i) =>{
-                    match *i as u64 {
-                        DIRECTORY => attr::FileType::Directory,
-                        TXTFILE => attr::FileType::TextFile,
-                        _ => attr::FileType::TextFile
-                    }
-                },
-                _ => return (Err(entity::Error::InvalidFileType.into()), 0)
-            };
-            let size = match &attr_data[SIZE] {
-                Yaml::Integer(i) => {
-                    *i as u64
-                },
-                _ => return (Err(entity::Error::InvalidSize.into()), 0)
-            };
-            let uid = match &attr_data[UID] {
-                Yaml::Integer(i) => *i as u32,
-                _ => return (Err(entity::Error::InvalidUID.into()), 0)
-            };
-            let gid = match &attr_data[GID] {
-                Yaml::Integer(i) => *i as u32,
-                _ => return (Err(entity::Error::InvalidGID.into()), 0)
-            };
-            let perm = match &attr_data[PERM] {
-                Yaml::Integer(i) => *i as u16,
-                _ => return (Err(entity::Error::InvalidPERM.into()), 0)
-            };
-            let atime = match &attr_data[ATIME] {
-                Yaml::String(s) => {
-                    let epoc_string = s.clone();
-                    let epoc_vec: Vec<&str> = epoc_string.split('.').collect();
-                    let secs: u64 = epoc_vec[0].parse().unwrap();
-                    let nanos: u32 = epoc_vec[1].parse().unwrap();
-
-                    attr::SystemTime(secs,nanos)
-                },
-                _ => return (Err(entity::Error::InvalidAtime.into()), 0)
-            };
-            let mtime = match &attr_data[MTIME] {
-                Yaml::String(s) => {
-                    let epoc_string = s.clone();
-                    let epoc_vec: Vec<&str> = epoc_string.split('.').collect();
-                    let secs: u64 = epoc_vec[0].parse().unwrap();
-                    let nanos: u32 = epoc_vec[1].parse().unwrap();
-
-                    attr::SystemTime(secs,nanos)
-                },
-                _ => return (Err(entity::Error::InvalidAtime.into()), 0)
-            };
-            let ctime = match &attr_data[CTIME] {
-                Yaml::String(s) => {
-                    let epoc_string = s.clone();
-                    let epoc_vec: Vec<&str> = epoc_string.split('.').collect();
-                    let secs: u64 = epoc_vec[0].parse().unwrap();
-                    let nanos: u32 = epoc_vec[1].parse().unwrap();
-
-                    attr::SystemTime(secs,nanos)
-                },
-                _ => return (Err(entity::Error::InvalidAtime.into()), 0)
-            };
+    entrie_hash.insert(ino, entries);
+
+    Ok(())
+}
 
-            let nlink = match &attr_data[NLINK] {
-                Yaml::Integer(i) => *i as u32,
-                _ => return (Err(entity::Error::InvalidNlink.into()), 0)
-            };
+pub(super) fn apply_data(data: &Yaml, data_hash: &mut HashMap<u64, data::Data>) -> Result<()> {
+    let ino = match &data[INO] {
+        Yaml::Integer(i) => *i as u64,
+        _ => return Err(entity::Error::InvalidINO.into())
+    };
 
-            if ino >= next_ino {
-                next_ino = ino + 1;
+    match &data[DEL] {
+        Yaml::Boolean(flg) => {
+            if *flg {
+                data_hash.remove(&ino);
+                return Ok(());
             }
-            
-            attrs_hash.insert(ino, attr::Attr::new(ino, size, name, file_type, perm, uid, gid, atime, mtime, ctime, nlink));
+        },
+        _ => {}
+    }
+
+    let text_data = match (&data[ENCODING], &data[DATA]) {
+        (Yaml::String(encoding), Yaml::String(s)) if encoding == BASE64 => match base64::decode(s) {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(entity::Error::InvalidData.into())
+        },
+        (Yaml::BadValue, Yaml::String(s)) => s.clone().into_bytes(),
+        _ => return Err(entity::Error::InvalidData.into())
+    };
+
+    data_hash.insert(ino, data::Data::new(ino, text_data));
+
+    Ok(())
+}
+
+// 拡張属性のレコードを1件反映する
+// inodeごとに全ての拡張属性を持ち、空のレコードはそのinodeの拡張属性の削除を表す
+pub(super) fn apply_xattr(xattr_data: &Yaml, xattr_hash: &mut HashMap<u64, xattr::Xattrs>) -> Result<()> {
+    let ino = match &xattr_data[INO] {
+        Yaml::Integer(i) => *i as u64,
+        _ => return Err(entity::Error::InvalidINO.into())
+    };
+    let mut xattrs = xattr::Xattrs::new();
+
+    match &xattr_data[XATTRS] {
+        Yaml::Array(records) => {
+            for record in records {
+                let name = match &record[NAME] {
+                    Yaml::String(name) => name.clone(),
+                    _ => return Err(entity::Error::InvalidName.into())
+                };
+                let value = match (&record[ENCODING], &record[VALUE]) {
+                    (Yaml::String(encoding), Yaml::String(s)) if encoding == BASE64 => match base64::decode(s) {
+                        Ok(bytes) => bytes,
+                        Err(_) => return Err(entity::Error::InvalidData.into())
+                    },
+                    (Yaml::BadValue, Yaml::String(s)) => s.clone().into_bytes(),
+                    _ => return Err(entity::Error::InvalidData.into())
+                };
+
+                xattrs.insert(name, value);
+            }
+        },
+        Yaml::Null | Yaml::BadValue => {},
+        _ => return Err(entity::Error::InvalidData.into())
+    }
+
+    if xattrs.is_empty() {
+        xattr_hash.remove(&ino);
+    } else {
+        xattr_hash.insert(ino, xattrs);
+    }
+
+    Ok(())
+}
+
+pub(super) fn attr_record(attr: &attr::Attr) -> String {
+    let file_type = match attr.file_type() {
+        attr::FileType::TextFile => TXTFILE,
+        attr::FileType::Directory => DIRECTORY,
+        attr::FileType::Symlink => SYMLINK
+    };
+
+    format!(
+        "- ino: {}\n  file-type: {}\n  size: {}\n  uid: {}\n  gid: {}\n  perm: 0o{:o}\n  atime: \"{}.{}\"\n  mtime: \"{}.{}\"\n  ctime: \"{}.{}\"\n  nlink: {}\n",
+        attr.ino(),
+        file_type,
+        attr.size(),
+        attr.uid(),
+        attr.gid(),
+        attr.perm(),
+        attr.atime.as_secs(),
+        attr.atime.subsec_nanos(),
+        attr.mtime.as_secs(),
+        attr.mtime.subsec_nanos(),
+        attr.ctime.as_secs(),
+        attr.ctime.subsec_nanos(),
+        attr.nlink()
+    )
+}
+
+pub(super) fn entry_record(ino: u64, child_inos: &Vec<entry::Entry>) -> String {
+    let mut record = format!("- ino: {}\n  files:\n", ino);
+    for entry in child_inos {
+        record.push_str(&format!("    - ino: {}\n      name: {}\n", entry.child_ino(), quote(entry.name())));
+    }
+
+    record
+}
+
+pub(super) fn data_record(ino: u64, data: &[u8]) -> String {
+    // UTF-8として解釈できないデータはbase64で保存する
+    match str::from_utf8(data) {
+        Ok(text) => format!("- ino: {}\n  data: {}\n", ino, quote(text)),
+        Err(_) => format!("- ino: {}\n  {}: {}\n  data: \"{}\"\n", ino, ENCODING, BASE64, base64::encode(data))
+    }
+}
+
+pub(super) fn xattr_record(ino: u64, xattrs: &xattr::Xattrs) -> String {
+    let mut record = format!("- ino: {}\n  xattrs:\n", ino);
+    for (name, value) in xattrs {
+        // UTF-8として解釈できない値はbase64で保存する
+        match str::from_utf8(value) {
+            Ok(text) => record.push_str(&format!("    - name: {}\n      value: {}\n", quote(name), quote(text))),
+            Err(_) => record.push_str(&format!(
+                "    - name: {}\n      {}: {}\n      value: \"{}\"\n", quote(name), ENCODING, BASE64, base64::encode(value)
+            ))
         }
+    }
+
+    record
+}
+
+// 生存しているinodeのレコードだけをattr, entry, data, xattrの順に並べ、レコード数とともに返す
+pub(super) fn snapshot(
+    attrs: &attr::AttrsStruct,
+    entries: &entry::EntriesStruct,
+    all_data: &data::AllDataStruct,
+    xattrs: &xattr::XattrsStruct
+) -> (String, String, String, String, u64) {
+    // nlinkが0のinodeはforget待ちのためイメージには残さない
+    let mut inos: Vec<u64> = attrs.attrs().iter()
+        .filter(|(_, attr)| attr.nlink() > 0)
+        .map(|(ino, _)| *ino)
+        .collect();
+    inos.sort();
+
+    let mut attr_records = String::new();
+    let mut entry_records = String::new();
+    let mut data_records = String::new();
+    let mut xattr_records = String::new();
+    let mut records = 0;
+
+    for ino in inos.iter() {
+        attr_records.push_str(&frame(&attr_record(attrs.attr(*ino).unwrap())));
+        records += 1;
 
-        return (Ok(attrs_hash), next_ino);
+        if let Some(child_inos) = entries.entry(*ino) {
+            entry_records.push_str(&frame(&entry_record(*ino, child_inos)));
+            records += 1;
+        }
+        if let Some(data) = all_data.all_data(*ino) {
+            data_records.push_str(&frame(&data_record(*ino, data.data())));
+            records += 1;
+        }
+        if let Some(xattr) = xattrs.xattr(*ino) {
+            xattr_records.push_str(&frame(&xattr_record(*ino, xattr)));
+            records += 1;
+        }
     }
-    
-    fn load_data(&self) -> Result<HashMap<u64, data::Data>> {
-        let mut file = match File::open(&self.data) {
-            Ok(file) => file,
-            Err(e) => return Err(e.into())
-        };
 
-        let mut config = String::new();
-        match file.read_to_string(&mut config) {
-            Ok(_) => {}
-            Err(e) => return Err(e.into())
-        };
-        
-        let docs = match YamlLoader::load_from_str(&config) {
-            Ok(docs) => docs,
-            Err(e) => return Err(e.into())
-        };
+    (attr_records, entry_records, data_records, xattr_records, records)
+}
 
-        let mut data_hash = HashMap::new();
+pub(super) fn needs_compaction(records: u64, live: u64, compaction_ratio: f64) -> bool {
+    if compaction_ratio <= 0.0 {
+        return false;
+    }
 
-        for data in docs[0].as_vec().unwrap() {
-            let ino = match &data[INO] {
-                Yaml::Integer(i) => *i as u64,
-                _ => return Err(entity::Error::InvalidINO.into())
-            };
-
-            match &data[DEL] {
-                Yaml::Boolean(flg) => {
-                    if *flg {
-                        data_hash.remove(&ino);
-                        continue;
-                    }
-                },
-                _ => {}
+    records as f64 > live.max(1) as f64 * compaction_ratio
+}
+
+// レコードは終端行を付けて1回のwriteで書き込む
+pub(super) fn append(path: &path::Path, record: &str, fsync: Fsync) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(path)?;
+
+    file.write_all(frame(record).as_bytes())?;
+    if fsync == Fsync::Always {
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+// fsync: neverでも、fsync(2)を受けたときはここまでに追記したレコードを同期する
+pub(super) fn sync(path: &path::Path) -> Result<()> {
+    File::open(path)?.sync_data()?;
+    Ok(())
+}
+
+// ファイルを読み込み、最後の終端行より後ろにある途切れたレコードを切り詰める
+// read_onlyの場合はファイルを変更せず、読み込んだ内容からだけ取り除く
+pub(super) fn read_log(path: &path::Path, read_only: bool) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut config = String::new();
+    file.read_to_string(&mut config)?;
+
+    if let Some(len) = torn_record(&config) {
+        eprintln!("Discarded torn record in {:?}: {:?}", path, &config[len..]);
+        if read_only {
+            config.truncate(len);
+            return Ok(config);
+        }
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(path)?;
+        file.set_len(len as u64)?;
+        file.sync_all()?;
+        config.truncate(len);
+    }
+
+    Ok(config)
+}
+
+// image.yamlに書かれたパスを返す
+// 相対パスはカレントディレクトリからのパスとして扱う
+pub(super) fn image_path(doc: &Yaml, key: &str, default: &str) -> Result<path::PathBuf> {
+    let path = match &doc[key] {
+        Yaml::String(s) => path::PathBuf::from(s),
+        _ => path::PathBuf::from(default)
+    };
+
+    if path.is_relative() {
+        return Ok(env::current_dir()?.join(path));
+    }
+
+    Ok(path)
+}
+
+pub(super) fn compaction_ratio(doc: &Yaml) -> f64 {
+    match &doc[COMPACTION_RATIO] {
+        Yaml::Real(_) => doc[COMPACTION_RATIO].as_f64().unwrap(),
+        Yaml::Integer(i) => *i as f64,
+        _ => COMPACTION_RATIO_DEFAULT
+    }
+}
+
+pub(super) fn fsync(doc: &Yaml) -> Result<Fsync> {
+    match doc[FSYNC].as_str() {
+        Some(NEVER) => Ok(Fsync::Never),
+        Some(ALWAYS) | None => Ok(Fsync::Always),
+        Some(_) => Err(entity::Error::InvalidData.into())
+    }
+}
+
+pub(super) fn limits(doc: &Yaml) -> Result<limit::Limits> {
+    let mut quotas = HashMap::new();
+
+    // quotas:
+    //   1000:
+    //     bytes: 1G
+    //     inodes: 1000
+    match &doc[QUOTAS] {
+        Yaml::Hash(hash) => {
+            for (uid, quota) in hash {
+                let uid = match uid {
+                    Yaml::Integer(i) if *i >= 0 && *i <= u32::MAX as i64 => *i as u32,
+                    _ => return Err(entity::Error::InvalidUID.into())
+                };
+                quotas.insert(uid, limit::Quota {
+                    bytes: bytes(&quota[BYTES])?,
+                    inodes: count(&quota[INODES])?
+                });
             }
+        },
+        Yaml::BadValue => {},
+        _ => return Err(entity::Error::InvalidData.into())
+    }
+
+    Ok(limit::Limits {
+        capacity: bytes(&doc[CAPACITY])?,
+        max_inodes: count(&doc[MAX_INODES])?,
+        max_file_size: bytes(&doc[MAX_FILE_SIZE])?,
+        quotas: quotas
+    })
+}
+
+fn count(value: &Yaml) -> Result<Option<u64>> {
+    match value {
+        Yaml::Integer(i) if *i >= 0 => Ok(Some(*i as u64)),
+        Yaml::BadValue => Ok(None),
+        _ => Err(entity::Error::InvalidData.into())
+    }
+}
+
+// バイト数を読み込む
+// 1024の累乗を表すK, M, G, Tを末尾に付けられる
+fn bytes(value: &Yaml) -> Result<Option<u64>> {
+    let text = match value {
+        Yaml::Integer(i) if *i >= 0 => return Ok(Some(*i as u64)),
+        Yaml::String(s) => s.trim(),
+        Yaml::BadValue => return Ok(None),
+        _ => return Err(entity::Error::InvalidData.into())
+    };
+
+    let (number, shift) = match text.chars().last() {
+        Some('K') | Some('k') => (&text[..text.len() - 1], 10),
+        Some('M') | Some('m') => (&text[..text.len() - 1], 20),
+        Some('G') | Some('g') => (&text[..text.len() - 1], 30),
+        Some('T') | Some('t') => (&text[..text.len() - 1], 40),
+        _ => (text, 0)
+    };
+    match number.trim().parse::<u64>() {
+        Ok(n) if n.leading_zeros() >= shift => Ok(Some(n << shift)),
+        _ => Err(entity::Error::InvalidData.into())
+    }
+}
 
-            let text_data = match &data[DATA] {
-                Yaml::String(s) => s.clone(),
-                _ => return Err(entity::Error::InvalidData.into())
-            };
-            
-            data_hash.insert(ino, data::Data::new(ino, text_data));
+// pathを置いているホストのファイルシステムの空き容量を返す
+pub(super) fn available(path: &path::Path) -> Result<u64> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => path::Path::new(".")
+    };
+    let dir = match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(dir) => dir,
+        Err(_) => return Err(entity::Error::InvalidData.into())
+    };
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(dir.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+pub(super) fn frame(record: &str) -> String {
+    format!("{}{}", record, RECORD_END)
+}
+
+// 途切れたレコードがあれば、残すべき先頭からのバイト数を返す
+// 終端行を持たない古い形式のファイルはそのまま読み込む
+fn torn_record(config: &str) -> Option<usize> {
+    let len = match config.rfind(RECORD_END) {
+        Some(pos) => pos + RECORD_END.len(),
+        None => return None
+    };
+
+    if config[len..].trim().is_empty() {
+        return None;
+    }
+
+    Some(len)
+}
+
+// 一時ファイルに書き出してから置き換える
+pub(super) fn rewrite(path: &path::Path, contents: &str) -> Result<()> {
+    write_tmp(path, contents)?;
+    fs::rename(with_suffix(path, TMP_SUFFIX), path)?;
+    sync_dir(path)
+}
+
+// pathを置き換えるための一時ファイルを書き出し、ディスクに同期する
+fn write_tmp(path: &path::Path, contents: &str) -> Result<()> {
+    let mut file = File::create(with_suffix(path, TMP_SUFFIX))?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// 一時ファイルがあればpathと置き換える
+fn replace_with_tmp(path: &path::Path) -> Result<()> {
+    let tmp = with_suffix(path, TMP_SUFFIX);
+    if tmp.exists() {
+        fs::rename(&tmp, path)?;
+        sync_dir(path)?;
+    }
+    Ok(())
+}
+
+fn with_suffix(path: &path::Path, suffix: &str) -> path::PathBuf {
+    let mut with_suffix = path.as_os_str().to_os_string();
+    with_suffix.push(suffix);
+    path::PathBuf::from(with_suffix)
+}
+
+// 作成、置き換え、削除をディスクに残すため、pathを置いているディレクトリを同期する
+fn sync_dir(path: &path::Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        if let Ok(dir) = File::open(if dir.as_os_str().is_empty() { path::Path::new(".") } else { dir }) {
+            dir.sync_all()?;
         }
+    }
+    Ok(())
+}
 
-        return Ok(data_hash);
+// YAMLのダブルクォート文字列としてエスケープする
+pub(super) fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interfaceadapter::worker::File as _;
+    use crate::testing::TempDir;
+
+    // テストごとに別の一時ディレクトリを作る
+    fn temp_dir(name: &str) -> TempDir {
+        TempDir::new(&format!("yaml-{}", name))
+    }
+
+    // dirに置いた4つのファイルを使うイメージ
+    fn image_in(dir: &path::Path) -> YAMLImageStruct {
+        YAMLImageStruct{
+            attr: dir.join("attr.yaml"),
+            entry: dir.join("entry.yaml"),
+            data: dir.join("data.yaml"),
+            xattr: dir.join("xattr.yaml"),
+            records: Cell::new(0),
+            compaction_ratio: COMPACTION_RATIO_DEFAULT,
+            fsync: Fsync::Never,
+            limits: limit::Limits::default(),
+            read_only: false,
+            unfinished: Cell::new(false)
+        }
+    }
+
+    #[test]
+    fn write_data_round_trips_binary_and_text() {
+        let dir = temp_dir("image");
+        let image = image_in(&dir);
+        fs::write(&image.data, "").unwrap();
+
+        image.write_data(2, b"line1\n\"quoted\"\t\\ \x1b").unwrap();
+        image.write_data(3, &[0x00, 0xff, 0xfe, 0x80]).unwrap();
+        let data = image.load_data().unwrap();
+
+        assert_eq!(data.get(&2).unwrap().data(), b"line1\n\"quoted\"\t\\ \x1b");
+        assert_eq!(data.get(&3).unwrap().data(), &[0x00, 0xff, 0xfe, 0x80]);
+    }
+
+    #[test]
+    fn load_entry_reads_names_from_both_formats() {
+        let dir = temp_dir("entry");
+        let image = image_in(&dir);
+        fs::write(&image.entry, "- ino: 1\n  files:\n    - 2\n").unwrap();
+        image.update_entry(3, &vec![entry::Entry::new(2, "link: 2".to_string())]).unwrap();
+
+        let mut names = HashMap::new();
+        names.insert(2, "file1".to_string());
+        let entries = image.load_entry(&names).unwrap();
+
+        assert_eq!(entries.get(&1).unwrap()[0].name(), "file1");
+        assert_eq!(entries.get(&3).unwrap()[0].name(), "link: 2");
+        assert_eq!(entries.get(&3).unwrap()[0].child_ino(), 2);
+    }
+
+    #[test]
+    fn compact_keeps_only_live_records() {
+        let dir = temp_dir("compact");
+        fs::write(dir.join("image.yaml"), format!(
+            "attr: {}\nentry: {}\ndata: {}\ncompaction-ratio: 2\n",
+            dir.join("attr.yaml").display(),
+            dir.join("entry.yaml").display(),
+            dir.join("data.yaml").display()
+        )).unwrap();
+        let st = attr::SystemTime::new(0, 0);
+        fs::write(dir.join("attr.yaml"), [
+            attr_record(&attr::Attr::new(1, 1, attr::FileType::Directory, 0o755, 0, 0, st, st, st, 2)),
+            attr_record(&attr::Attr::new(2, 3, attr::FileType::TextFile, 0o644, 0, 0, st, st, st, 1)),
+            attr_record(&attr::Attr::new(3, 0, attr::FileType::TextFile, 0o644, 0, 0, st, st, st, 1)),
+            "- ino: 3\n  del: true\n".to_string(),
+        ].concat()).unwrap();
+        fs::write(dir.join("entry.yaml"), concat!(
+            "- ino: 1\n  files:\n    - ino: 2\n      name: a\n    - ino: 3\n      name: b\n",
+            "- ino: 1\n  files:\n    - ino: 2\n      name: a\n",
+        )).unwrap();
+        fs::write(dir.join("data.yaml"), concat!(
+            "- ino: 2\n  data: \"x\"\n",
+            "- ino: 2\n  data: \"abc\"\n",
+            "- ino: 3\n  data: \"\"\n",
+            "- ino: 3\n  del: true\n",
+        )).unwrap();
+
+        let mut image = YAMLImageStruct::new();
+        let (_, attrs, entries, data, xattrs) = image.init(&dir.join("image.yaml")).unwrap();
+        assert!(image.needs_compaction(4));
+
+        image.compact(&attrs, &entries, &data, &xattrs).unwrap();
+        assert!(!image.needs_compaction(4));
+        assert!(!fs::read_to_string(dir.join("attr.yaml")).unwrap().contains("del"));
+
+        let (_, attrs, entries, data, _) = YAMLImageStruct::new().init(&dir.join("image.yaml")).unwrap();
+        assert_eq!(attrs.attrs().len(), 2);
+        assert_eq!(entries.entry(1).unwrap().len(), 1);
+        assert_eq!(entries.entry(1).unwrap()[0].name(), "a");
+        assert_eq!(data.all_data(2).unwrap().data(), b"abc");
+        assert!(data.all_data(3).is_none());
+    }
+
+    #[test]
+    fn interrupted_compaction_is_finished_on_load() {
+        let dir = temp_dir("interrupted");
+        fs::write(dir.join("image.yaml"), format!(
+            "attr: {}\nentry: {}\ndata: {}\nxattr: {}\n",
+            dir.join("attr.yaml").display(),
+            dir.join("entry.yaml").display(),
+            dir.join("data.yaml").display(),
+            dir.join("xattr.yaml").display()
+        )).unwrap();
+        let st = attr::SystemTime::new(0, 0);
+        fs::write(dir.join("attr.yaml"), attr_record(&attr::Attr::new(1, 0, attr::FileType::Directory, 0o755, 0, 0, st, st, st, 2))).unwrap();
+        fs::write(dir.join("entry.yaml"), "- ino: 1\n  files:\n").unwrap();
+        fs::write(dir.join("data.yaml"), "").unwrap();
+
+        // 古いログにはないinodeを含む状態でコンパクションし、最初のファイルを置き換えたところで止める
+        let mut image = image_in(&dir);
+        let (_, mut attrs, mut entries, mut data, xattrs) = image.init(&dir.join("image.yaml")).unwrap();
+        attrs.update_attr(attr::Attr::new(2, 3, attr::FileType::TextFile, 0o644, 0, 0, st, st, st, 1));
+        assert!(attrs.inc_size(1).is_ok());
+        entries.insert_child_ino(1, 2, "file");
+        assert!(data.update_data(2, data::Data::new(2, b"new".to_vec())).is_ok());
+        image.stage_compaction(&attrs, &entries, &data, &xattrs).unwrap();
+        replace_with_tmp(&image.attr).unwrap();
+        let image_path = dir.join("image.yaml");
+
+        // 読み取り専用では何も変更せず、置き換えるはずだった内容を読み込む
+        let (_, attrs, entries, data, _) = YAMLImageStruct::read_only().init(&image_path).unwrap();
+        assert_eq!(entries.entry(1).unwrap()[0].child_ino(), 2);
+        assert_eq!(attrs.attr(2).unwrap().size(), 3);
+        assert_eq!(data.all_data(2).unwrap().data(), b"new");
+        assert!(dir.join("attr.yaml.compacting").exists());
+
+        let (_, attrs, entries, data, _) = YAMLImageStruct::new().init(&image_path).unwrap();
+        assert_eq!(entries.entry(1).unwrap()[0].name(), "file");
+        assert_eq!(attrs.attr(2).unwrap().size(), 3);
+        assert_eq!(data.all_data(2).unwrap().data(), b"new");
+        assert!(!dir.join("attr.yaml.compacting").exists());
+        assert!(!dir.join("entry.yaml.tmp").exists());
+
+        // 印を作る前に止まった一時ファイルは捨てる
+        fs::write(dir.join("data.yaml.tmp"), "- ino: 2\n  data: \"torn").unwrap();
+        let (_, _, _, data, _) = YAMLImageStruct::new().init(&image_path).unwrap();
+        assert_eq!(data.all_data(2).unwrap().data(), b"new");
+        assert!(!dir.join("data.yaml.tmp").exists());
+    }
+
+    #[test]
+    fn apply_entry_rejects_path_like_names() {
+        for name in ["\"../x\"", "\"/etc/cron.d/x\"", "\".\"", "\"\"", "\"a/b\""] {
+            let records = YamlLoader::load_from_str(&format!("- ino: 1\n  files:\n    - ino: 2\n      name: {}\n", name)).unwrap();
+            let result = apply_entry(&records[0][0], &mut HashMap::new(), &HashMap::new());
+
+            assert!(matches!(result.unwrap_err().downcast_ref::<entity::Error>(), Some(entity::Error::InvalidName)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn load_records_truncates_torn_record() {
+        let dir = temp_dir("torn");
+        let image = image_in(&dir);
+        // 古い形式のレコードの後ろに、書き込み途中で途切れたレコードが続く
+        fs::write(&image.data, "- ino: 2\n  data: \"old\"\n").unwrap();
+        image.load_data().unwrap();
+        image.write_data(2, b"new").unwrap();
+        let intact = fs::read_to_string(&image.data).unwrap();
+        fs::write(&image.data, format!("{}- ino: 3\n  data: \"ha", intact)).unwrap();
+
+        let data = image.load_data().unwrap();
+
+        assert_eq!(data.get(&2).unwrap().data(), b"new");
+        assert!(data.get(&3).is_none());
+        assert_eq!(fs::read_to_string(&image.data).unwrap(), intact);
+    }
+
+    #[test]
+    fn read_only_init_leaves_files_untouched() {
+        let dir = temp_dir("read-only");
+        fs::write(dir.join("image.yaml"), format!(
+            "attr: {}\nentry: {}\ndata: {}\n",
+            dir.join("attr.yaml").display(),
+            dir.join("entry.yaml").display(),
+            dir.join("data.yaml").display()
+        )).unwrap();
+        let st = attr::SystemTime::new(0, 0);
+        let root = attr::Attr::new(1, 0, attr::FileType::Directory, 0o755, 0, 0, st, st, st, 2);
+        // 終端行を持たない古い形式のファイルと、途切れたレコードを持つファイル
+        let attrs = attr_record(&root);
+        let entries = format!("{}- ino: 1\n  files: [", frame("- ino: 1\n  files: []\n"));
+        fs::write(dir.join("attr.yaml"), &attrs).unwrap();
+        fs::write(dir.join("entry.yaml"), &entries).unwrap();
+        fs::write(dir.join("data.yaml"), "").unwrap();
+
+        let mut image = YAMLImageStruct::read_only();
+        let (_, attrs_struct, entries_struct, data, xattrs) = image.init(&dir.join("image.yaml")).unwrap();
+
+        assert!(attrs_struct.attr(1).is_some());
+        assert!(entries_struct.entry(1).unwrap().is_empty());
+        assert_eq!(fs::read_to_string(dir.join("attr.yaml")).unwrap(), attrs);
+        assert_eq!(fs::read_to_string(dir.join("entry.yaml")).unwrap(), entries);
+        assert!(!dir.join("xattr.yaml").exists());
+
+        let result = image.update_attr(&root);
+        assert!(matches!(result.unwrap_err().downcast_ref::<entity::Error>(), Some(entity::Error::ReadOnly)));
+        let result = image.compact(&attrs_struct, &entries_struct, &data, &xattrs);
+        assert!(matches!(result.unwrap_err().downcast_ref::<entity::Error>(), Some(entity::Error::ReadOnly)));
+        // 書き込んでいないため、同期は何もせずに成功する
+        image.sync().unwrap();
+    }
+
+    #[test]
+    fn apply_attr_rejects_malformed_time() {
+        let records = YamlLoader::load_from_str(
+            "- ino: 2\n  file-type: 1\n  size: 0\n  uid: 0\n  gid: 0\n  perm: 0o644\n  atime: \"12\"\n  mtime: \"0.0\"\n  ctime: \"0.0\"\n  nlink: 1\n"
+        ).unwrap();
+        let result = apply_attr(&records[0][0], &mut HashMap::new(), &mut HashMap::new());
+
+        assert!(matches!(result.unwrap_err().downcast_ref::<entity::Error>(), Some(entity::Error::InvalidAtime)));
+    }
+
+    #[test]
+    fn xattr_records_round_trip_and_delete() {
+        let dir = temp_dir("xattr");
+        let image = image_in(&dir);
+        let mut xattrs = xattr::Xattrs::new();
+        xattrs.insert("user.mime_type".to_string(), b"text/plain".to_vec());
+        xattrs.insert("security.selinux".to_string(), vec![0x00, 0xff]);
+
+        // ファイルがなければ作る
+        assert!(image.load_xattr().unwrap().is_empty());
+        image.update_xattr(2, &xattrs).unwrap();
+        image.update_xattr(3, &xattrs).unwrap();
+        image.update_xattr(3, &xattr::Xattrs::new()).unwrap();
+        let loaded = image.load_xattr().unwrap();
+
+        assert_eq!(loaded.get(&2), Some(&xattrs));
+        assert!(loaded.get(&3).is_none());
+    }
+
+    #[test]
+    fn limits_accept_byte_suffixes() {
+        let docs = YamlLoader::load_from_str(
+            "capacity: 2G\nmax-inodes: 1000\nmax-file-size: 512M\nquotas:\n  1000:\n    bytes: 1k\n  1001:\n    inodes: 3\n"
+        ).unwrap();
+        let loaded = limits(&docs[0]).unwrap();
+        assert_eq!((loaded.capacity, loaded.max_inodes, loaded.max_file_size), (Some(2 << 30), Some(1000), Some(512 << 20)));
+        assert_eq!(loaded.quotas[&1000], limit::Quota { bytes: Some(1024), inodes: None });
+        assert_eq!(loaded.quotas[&1001], limit::Quota { bytes: None, inodes: Some(3) });
+
+        let docs = YamlLoader::load_from_str("capacity: 4096\n").unwrap();
+        assert_eq!(limits(&docs[0]).unwrap(), limit::Limits { capacity: Some(4096), ..Default::default() });
+
+        let docs = YamlLoader::load_from_str("capacity: lots\n").unwrap();
+        assert!(limits(&docs[0]).is_err());
     }
 }
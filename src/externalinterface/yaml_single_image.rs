@@ -0,0 +1,310 @@
+extern crate yaml_rust;
+
+use std::path;
+use std::cell::Cell;
+use std::fs::File;
+use std::io::prelude::*;
+use std::collections::HashMap;
+use yaml_rust::{YamlLoader, Yaml};
+use crate::entity::{
+    self,
+    attr,
+    data,
+    entry,
+    limit,
+    xattr
+};
+use crate::interfaceadapter::worker;
+use super::yaml_image::{self, Fsync};
+use anyhow::Result;
+
+// attr, entry, dataを1つのYAMLファイルにまとめたイメージ
+//
+// attr:
+// - ino: 1
+//   ...
+// entry:
+// - ino: 1
+//   ...
+// data:
+// - ino: 2
+//   ...
+// xattr:
+// - ino: 2
+//   ...
+// log:
+// - section: attr
+//   ino: 2
+//   ...
+//
+// 更新はlogに追記し、コンパクションでattr, entry, data, xattrに畳み込む
+#[derive(Debug)]
+pub struct YAMLSingleImageStruct {
+    image: path::PathBuf,
+    // ファイルに書かれているレコードの総数
+    records: Cell<u64>,
+    compaction_ratio: f64,
+    fsync: Fsync,
+    limits: limit::Limits,
+    // 読み込み時も含めてファイルを一切変更しない
+    read_only: bool
+}
+
+const IMAGE:        &str = "image";
+const ATTR:         &str = "attr";
+const ENTRY:        &str = "entry";
+const DATA:         &str = "data";
+const XATTR:        &str = "xattr";
+const LOG:          &str = "log";
+const SECTION:      &str = "section";
+
+const IMAGE_DEFAULT_PATH: &str = "/etc/hfs-image.yaml";
+
+impl worker::File for YAMLSingleImageStruct {
+    fn init(&mut self, path: &path::Path) -> Result<(u64, attr::AttrsStruct, entry::EntriesStruct, data::AllDataStruct, xattr::XattrsStruct)> {
+        self.load_image(path)?;
+
+        let config = yaml_image::read_log(&self.image, self.read_only)?;
+        let docs = YamlLoader::load_from_str(&config)?;
+        let doc = match docs.into_iter().next() {
+            Some(doc) => doc,
+            None => return Err(entity::Error::InvalidEntry.into())
+        };
+
+        let mut attrs_hash = HashMap::new();
+        let mut names = HashMap::new();
+        let mut entrie_hash = HashMap::new();
+        let mut data_hash = HashMap::new();
+        let mut xattr_hash = HashMap::new();
+        let mut next_ino = 0;
+        let mut records = 0;
+
+        for attr_data in section(&doc, ATTR)?.iter() {
+            if let Some(ino) = yaml_image::apply_attr(attr_data, &mut attrs_hash, &mut names)? {
+                if ino >= next_ino {
+                    next_ino = ino + 1;
+                }
+            }
+            records += 1;
+        }
+
+        for entry_data in section(&doc, ENTRY)?.iter() {
+            yaml_image::apply_entry(entry_data, &mut entrie_hash, &names)?;
+            records += 1;
+        }
+
+        for data in section(&doc, DATA)?.iter() {
+            yaml_image::apply_data(data, &mut data_hash)?;
+            records += 1;
+        }
+
+        for xattr_data in section(&doc, XATTR)?.iter() {
+            yaml_image::apply_xattr(xattr_data, &mut xattr_hash)?;
+            records += 1;
+        }
+
+        // logは書かれた順に反映する
+        for record in section(&doc, LOG)?.iter() {
+            match record[SECTION].as_str() {
+                Some(ATTR) => {
+                    if let Some(ino) = yaml_image::apply_attr(record, &mut attrs_hash, &mut names)? {
+                        if ino >= next_ino {
+                            next_ino = ino + 1;
+                        }
+                    }
+                },
+                Some(ENTRY) => yaml_image::apply_entry(record, &mut entrie_hash, &names)?,
+                Some(DATA) => yaml_image::apply_data(record, &mut data_hash)?,
+                Some(XATTR) => yaml_image::apply_xattr(record, &mut xattr_hash)?,
+                _ => return Err(entity::Error::InvalidEntry.into())
+            }
+            records += 1;
+        }
+
+        self.records.set(records);
+
+        Ok((
+            next_ino,
+            attr::AttrsStruct::new(attrs_hash),
+            entry::EntriesStruct::new(entrie_hash),
+            data::AllDataStruct::new(data_hash),
+            xattr::XattrsStruct::new(xattr_hash)
+        ))
+    }
+
+    fn write_data(&self, ino: u64, data: &[u8]) -> Result<()> {
+        self.append(DATA, &yaml_image::data_record(ino, data))
+    }
+
+    fn update_attr(&self, attr: &attr::Attr) -> Result<()> {
+        self.append(ATTR, &yaml_image::attr_record(attr))
+    }
+
+    fn del_attr(&self, ino: u64) -> Result<()> {
+        self.append(ATTR, &format!("- ino: {}\n  del: {}\n", ino, true))
+    }
+
+    fn del_data(&self, ino: u64) -> Result<()> {
+        self.append(DATA, &format!("- ino: {}\n  del: {}\n", ino, true))
+    }
+
+    fn update_entry(&self, ino: u64, child_inos: &Vec<entry::Entry>) -> Result<()> {
+        self.append(ENTRY, &yaml_image::entry_record(ino, child_inos))
+    }
+
+    fn update_xattr(&self, ino: u64, xattrs: &xattr::Xattrs) -> Result<()> {
+        self.append(XATTR, &yaml_image::xattr_record(ino, xattrs))
+    }
+
+    fn sync(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        yaml_image::sync(&self.image)
+    }
+
+    fn compact(&self, attrs: &attr::AttrsStruct, entries: &entry::EntriesStruct, all_data: &data::AllDataStruct, xattrs: &xattr::XattrsStruct) -> Result<()> {
+        if self.read_only {
+            return Err(entity::Error::ReadOnly.into());
+        }
+        let (attr_records, entry_records, data_records, xattr_records, records) = yaml_image::snapshot(attrs, entries, all_data, xattrs);
+
+        // logの後ろにも終端行を置き、以降に追記したレコードが途切れても検出できるようにする
+        let contents = format!(
+            "{}:\n{}{}:\n{}{}:\n{}{}:\n{}{}:\n{}",
+            ATTR, attr_records,
+            ENTRY, entry_records,
+            DATA, data_records,
+            XATTR, xattr_records,
+            LOG, yaml_image::frame("")
+        );
+        yaml_image::rewrite(&self.image, &contents)?;
+        self.records.set(records);
+
+        Ok(())
+    }
+
+    fn needs_compaction(&self, live: u64) -> bool {
+        yaml_image::needs_compaction(self.records.get(), live, self.compaction_ratio)
+    }
+
+    fn limits(&self) -> limit::Limits {
+        self.limits.clone()
+    }
+
+    fn available(&self) -> Result<u64> {
+        yaml_image::available(&self.image)
+    }
+}
+
+impl YAMLSingleImageStruct {
+    pub fn new() -> impl worker::File {
+        YAMLSingleImageStruct::with_mode(false)
+    }
+
+    // 読み取り専用のメディアに置かれたイメージでも読み込めるように、ファイルを変更せずに開く
+    pub fn read_only() -> impl worker::File {
+        YAMLSingleImageStruct::with_mode(true)
+    }
+
+    // マウントせずに、image.yamlが指すファイルへ書き出すためだけに開く
+    pub fn open(path: &path::Path) -> Result<YAMLSingleImageStruct> {
+        let mut image = YAMLSingleImageStruct::with_mode(false);
+        image.load_image(path)?;
+
+        Ok(image)
+    }
+
+    fn with_mode(read_only: bool) -> YAMLSingleImageStruct {
+        YAMLSingleImageStruct{
+            image: path::PathBuf::from(IMAGE_DEFAULT_PATH),
+            records: Cell::new(0),
+            compaction_ratio: yaml_image::COMPACTION_RATIO_DEFAULT,
+            fsync: Fsync::Always,
+            limits: limit::Limits::default(),
+            read_only: read_only
+        }
+    }
+
+    // logにどのセクションのレコードかを付けて追記する
+    fn append(&self, name: &str, record: &str) -> Result<()> {
+        if self.read_only {
+            return Err(entity::Error::ReadOnly.into());
+        }
+        let record = format!("- {}: {}\n  {}", SECTION, name, &record[2..]);
+
+        yaml_image::append(&self.image, &record, self.fsync)?;
+        self.records.set(self.records.get() + 1);
+        Ok(())
+    }
+
+    fn load_image(&mut self, path: &path::Path) -> Result<()> {
+        let mut file = File::open(path)?;
+        let mut config = String::new();
+        file.read_to_string(&mut config)?;
+        let docs = YamlLoader::load_from_str(&config)?;
+
+        self.image = yaml_image::image_path(&docs[0], IMAGE, IMAGE_DEFAULT_PATH)?;
+        self.compaction_ratio = yaml_image::compaction_ratio(&docs[0]);
+        self.fsync = yaml_image::fsync(&docs[0])?;
+        self.limits = yaml_image::limits(&docs[0])?;
+
+        Ok(())
+    }
+}
+
+// セクションのレコードを返す
+// 空のセクションはレコードなしとして扱う
+fn section<'a>(doc: &'a Yaml, name: &str) -> Result<&'a [Yaml]> {
+    match &doc[name] {
+        Yaml::Array(records) => Ok(records),
+        Yaml::Null | Yaml::BadValue => Ok(&[]),
+        _ => Err(entity::Error::InvalidEntry.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::testing::TempDir;
+    use crate::interfaceadapter::worker::File as _;
+
+    #[test]
+    fn log_is_replayed_and_folded_by_compaction() {
+        let dir = TempDir::new("yaml-single");
+        fs::write(dir.join("image.yaml"), format!("format: single\nimage: {}\n", dir.join("hfs.yaml").display())).unwrap();
+        let st = attr::SystemTime::new(0, 0);
+        fs::write(dir.join("hfs.yaml"), format!(
+            "attr:\n{}entry:\n{}data:\nlog:\n# end\n",
+            yaml_image::frame(&yaml_image::attr_record(&attr::Attr::new(1, 0, attr::FileType::Directory, 0o755, 0, 0, st, st, st, 2))),
+            yaml_image::frame("- ino: 1\n  files:\n")
+        )).unwrap();
+
+        let mut image = YAMLSingleImageStruct::new();
+        image.init(&dir.join("image.yaml")).unwrap();
+        image.update_attr(&attr::Attr::new(2, 3, attr::FileType::TextFile, 0o644, 0, 0, st, st, st, 1)).unwrap();
+        image.write_data(2, &[0xff, b'a', b'b']).unwrap();
+        image.update_entry(1, &vec![entry::Entry::new(2, "file1".to_string())]).unwrap();
+        let mut file_xattrs = xattr::Xattrs::new();
+        file_xattrs.insert("user.tag".to_string(), b"a: b".to_vec());
+        image.update_xattr(2, &file_xattrs).unwrap();
+
+        let (next_ino, attrs, entries, data, xattrs) = YAMLSingleImageStruct::new().init(&dir.join("image.yaml")).unwrap();
+        assert_eq!(next_ino, 3);
+        assert_eq!(xattrs.value(2, "user.tag"), Some(&b"a: b"[..]));
+        assert_eq!(attrs.attr(2).unwrap().size(), 3);
+        assert_eq!(entries.entry(1).unwrap()[0].name(), "file1");
+        assert_eq!(data.all_data(2).unwrap().data(), &[0xff, b'a', b'b']);
+
+        image.compact(&attrs, &entries, &data, &xattrs).unwrap();
+        let compacted = fs::read_to_string(dir.join("hfs.yaml")).unwrap();
+        assert!(!compacted.contains(SECTION));
+
+        let (_, attrs, entries, data, xattrs) = YAMLSingleImageStruct::new().init(&dir.join("image.yaml")).unwrap();
+        assert_eq!(attrs.attrs().len(), 2);
+        assert_eq!(xattrs.value(2, "user.tag"), Some(&b"a: b"[..]));
+        assert_eq!(entries.entry(1).unwrap()[0].child_ino(), 2);
+        assert_eq!(data.all_data(2).unwrap().data(), &[0xff, b'a', b'b']);
+    }
+}
@@ -1,26 +1,31 @@
 use crate::usecase;
 use std::path::Path;
 use std::ffi::OsStr;
-use crate::entity::attr;
+use crate::entity::{self, attr, credentials, handle, limit};
 use anyhow::Result;
 use fuse;
 use time;
-use std::str;
 
 struct ControllerStruct<U: usecase::Usecase> {
     usecase: U
 }
 
-pub trait Controller {
+pub trait Controller: Send + Sync {
     fn init(&mut self, config: &String) -> Result<()>;
-    fn lookup(&mut self, parent: u64, name: &OsStr) -> Option<fuse::FileAttr>;
-    fn getattr(&self, ino: u64) -> Option<fuse::FileAttr>;
-    fn readdir(&mut self, ino: u64) -> Option<Vec<(u64, &str, fuse::FileType)>>;
-    fn read(&mut self, ino: u64, offset: i64, size: u64) -> Option<&[u8]>;
-    fn write(&mut self, ino: u64, offset: u64, data: &[u8]) -> Result<u32>;
+    fn lookup(&self, cred: &credentials::Credentials, parent: u64, name: &OsStr) -> Result<fuse::FileAttr>;
+    fn getattr(&self, ino: u64) -> Result<fuse::FileAttr>;
+    fn readdir(&self, cred: &credentials::Credentials, ino: u64) -> Result<Vec<(u64, String, fuse::FileType)>>;
+    fn open(&self, cred: &credentials::Credentials, ino: u64, flags: u32) -> Result<u64>;
+    fn opendir(&self, cred: &credentials::Credentials, ino: u64, flags: u32) -> Result<u64>;
+    fn release(&self, fh: u64) -> Result<()>;
+    fn releasedir(&self, fh: u64) -> Result<()>;
+    fn read(&self, cred: &credentials::Credentials, ino: u64, fh: Option<u64>, offset: i64, size: u64) -> Result<Vec<u8>>;
+    fn write(&self, cred: &credentials::Credentials, ino: u64, fh: Option<u64>, offset: i64, data: &[u8]) -> Result<u32>;
     fn setattr(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         ino: u64,
+        fh: Option<u64>,
         mode: Option<u32>,
         uid: Option<u32>,
         gid: Option<u32>,
@@ -29,44 +34,90 @@ pub trait Controller {
         mtime: Option<time::Timespec>
     ) -> Result<fuse::FileAttr>;
     fn create(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
         mode: u32,
         flags: u32
-    ) -> Result<fuse::FileAttr>;
+    ) -> Result<(fuse::FileAttr, u64)>;
     fn unlink(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr
     ) -> Result<()>;
 
     fn forget(
-        &mut self,
+        &self,
         ino: u64,
         nlookup: u64
     ) -> Result<()>;
 
     fn mkdir(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
         mode: u32,
     ) -> Result<fuse::FileAttr>;
 
     fn rmdir(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
     ) -> Result<()>;
 
     fn rename (
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
     ) -> Result<()>;
+
+    fn symlink(
+        &self,
+        cred: &credentials::Credentials,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+    ) -> Result<fuse::FileAttr>;
+
+    fn readlink(&self, ino: u64) -> Result<Vec<u8>>;
+
+    fn access(&self, cred: &credentials::Credentials, ino: u64, mask: u32) -> Result<()>;
+
+    fn link(
+        &self,
+        cred: &credentials::Credentials,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<fuse::FileAttr>;
+
+    fn getxattr(&self, cred: &credentials::Credentials, ino: u64, name: &OsStr) -> Result<Vec<u8>>;
+
+    fn listxattr(&self, cred: &credentials::Credentials, ino: u64) -> Result<Vec<u8>>;
+
+    fn setxattr(
+        &self,
+        cred: &credentials::Credentials,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32
+    ) -> Result<()>;
+
+    fn removexattr(&self, cred: &credentials::Credentials, ino: u64, name: &OsStr) -> Result<()>;
+
+    fn statfs(&self) -> Result<limit::StatFs>;
+
+    fn flush(&self) -> Result<()>;
+    fn fsync(&self) -> Result<()>;
+    fn destroy(&self) -> Result<()>;
 }
 
 pub fn new<U>(usecase: U) -> impl Controller
@@ -85,97 +136,59 @@ impl<U: usecase::Usecase> Controller for ControllerStruct<U> {
 		}
     }
 
-    fn lookup(&mut self, parent: u64, name: &OsStr) -> Option<fuse::FileAttr> {
-        let attr = match self.usecase.lookup(parent, name) {
-            Some(attr) => attr,
-            None => return None
-        };
-		let file_type = match attr.file_type() {
-			attr::FileType::Directory => fuse::FileType::Directory,
-			attr::FileType::TextFile => fuse::FileType::RegularFile
-		};
-
-        return Some(fuse::FileAttr {
-            ino: attr.ino(),
-            size: attr.size(),
-            blocks: 0,
-            atime: timespeck(attr.atime()),
-            mtime: timespeck(attr.mtime()),
-            ctime: timespeck(attr.ctime()),
-            crtime: time::now().to_timespec(),
-            kind: file_type,
-            perm: attr.perm(),
-            nlink: attr.nlink(),
-            uid: attr.uid(),
-            gid: attr.gid(),
-            rdev: 0,
-            flags: 0,
-        });
-    }
-
-    fn getattr(&self, ino: u64) -> Option<fuse::FileAttr> {
-        let attr = match self.usecase.attr_from_ino(ino) {
-            Some(attr) => attr,
-            None => return None
-        };
-		let file_type = match attr.file_type() {
-			attr::FileType::Directory => fuse::FileType::Directory,
-			attr::FileType::TextFile => fuse::FileType::RegularFile
-		};
-		
-
-        return Some(fuse::FileAttr {
-            ino: attr.ino(),
-            size: attr.size(),
-            blocks: 0,
-            atime: timespeck(attr.atime()),
-            mtime: timespeck(attr.mtime()),
-            ctime: timespeck(attr.ctime()),
-            crtime: time::now().to_timespec(),
-            kind: file_type,
-            perm: attr.perm(),
-            nlink: attr.nlink(),
-            uid: attr.uid(),
-            gid: attr.gid(),
-            rdev: 0,
-            flags: 0,
-        });
-    }
-
-    fn readdir(&mut self, ino: u64) -> Option<Vec<(u64, &str, fuse::FileType)>> {
+    fn lookup(&self, cred: &credentials::Credentials, parent: u64, name: &OsStr) -> Result<fuse::FileAttr> {
+        let attr = self.usecase.lookup(cred, parent, name)?;
+        return Ok(file_attr(&attr));
+    }
+
+    fn getattr(&self, ino: u64) -> Result<fuse::FileAttr> {
+        let attr = self.usecase.attr_from_ino(ino)?;
+        return Ok(file_attr(&attr));
+    }
+
+    fn readdir(&self, cred: &credentials::Credentials, ino: u64) -> Result<Vec<(u64, String, fuse::FileType)>> {
         let mut return_vec = Vec::new();
-        let files_data = match self.usecase.readdir(ino) {
-            Some(files_data) => files_data,
-            None => return None
-        };
+        let files_data = self.usecase.readdir(cred, ino)?;
 
-        for file_data in files_data.iter() {
+        for file_data in files_data.into_iter() {
 
-            let file_type = match file_data.2 {
-                attr::FileType::Directory => fuse::FileType::Directory,
-                attr::FileType::TextFile => fuse::FileType::RegularFile
-            };
-            return_vec.push((file_data.0, file_data.1, file_type));
+            return_vec.push((file_data.0, file_data.1, file_type(file_data.2)));
         }
 
-        return Some(return_vec);
+        return Ok(return_vec);
     }
     
-    fn read(&mut self, ino: u64, offset: i64, size: u64) -> Option<&[u8]> {
-        let mut ret_data: Vec<u8> = Vec::with_capacity(size as usize);
-        let data = match self.usecase.read(ino, offset, size) {
-            Some(data) => data,
-            None => return None
-        };
-        let ret_data = data.as_bytes();
+    fn open(&self, cred: &credentials::Credentials, ino: u64, flags: u32) -> Result<u64> {
+        // 書き込みのために開く場合は、読み取り専用ならここで拒否する
+        if flags & handle::O_ACCMODE != handle::O_RDONLY {
+            self.writable()?;
+        }
+        self.usecase.open(cred, ino, flags)
+    }
 
-        return Some(ret_data);
+    fn opendir(&self, cred: &credentials::Credentials, ino: u64, flags: u32) -> Result<u64> {
+        self.usecase.opendir(cred, ino, flags)
     }
 
-    fn write(&mut self, ino: u64, offset: u64, data: &[u8]) -> Result<u32>{
-        let data_str = str::from_utf8(data).unwrap();
+    fn release(&self, fh: u64) -> Result<()> {
+        self.usecase.release(fh)
+    }
+
+    fn releasedir(&self, fh: u64) -> Result<()> {
+        self.usecase.release(fh)
+    }
 
-        let size = match self.usecase.write(ino, offset, data_str) { 
+    fn read(&self, cred: &credentials::Credentials, ino: u64, fh: Option<u64>, offset: i64, size: u64) -> Result<Vec<u8>> {
+        self.usecase.read(cred, ino, fh, offset, size)
+    }
+
+    fn write(&self, cred: &credentials::Credentials, ino: u64, fh: Option<u64>, offset: i64, data: &[u8]) -> Result<u32>{
+        self.writable()?;
+        // pwrite(2)と同じく負のoffsetはEINVALにする
+        if offset < 0 {
+            return Err(entity::Error::InvalidArgument.into());
+        }
+        let size = match self.usecase.write(cred, ino, fh, offset as u64, data) { 
             Ok(size) => size,
             Err(e) => return Err(e)
         };
@@ -183,8 +196,10 @@ impl<U: usecase::Usecase> Controller for ControllerStruct<U> {
     }
 
     fn setattr(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         ino: u64,
+        fh: Option<u64>,
         mode: Option<u32>,
         uid: Option<u32>,
         gid: Option<u32>,
@@ -192,6 +207,7 @@ impl<U: usecase::Usecase> Controller for ControllerStruct<U> {
         atime: Option<time::Timespec>,
         mtime: Option<time::Timespec>
     ) -> Result<fuse::FileAttr> {
+        self.writable()?;
         let atime_systime;
         let mtime_systime;
 
@@ -207,81 +223,49 @@ impl<U: usecase::Usecase> Controller for ControllerStruct<U> {
             mtime_systime = None;
         }
 
-        let attr = match self.usecase.setattr(ino, mode, uid, gid, size, atime_systime, mtime_systime) {
+        let attr = match self.usecase.setattr(cred, ino, fh, mode, uid, gid, size, atime_systime, mtime_systime) {
             Ok(attr) => attr,
             Err(e) => return Err(e)
         };
 
-        let file_type = match attr.file_type() {
-			attr::FileType::Directory => fuse::FileType::Directory,
-			attr::FileType::TextFile => fuse::FileType::RegularFile
-		};
-
-        return Ok(fuse::FileAttr {
-            ino: attr.ino(),
-            size: attr.size(),
-            blocks: 0,
-            atime: timespeck(attr.atime()),
-            mtime: timespeck(attr.mtime()),
-            ctime: timespeck(attr.ctime()),
-            crtime: time::now().to_timespec(),
-            kind: file_type,
-            perm: attr.perm(),
-            nlink: attr.nlink(),
-            uid: attr.uid(),
-            gid: attr.gid(),
-            rdev: 0,
-            flags: 0,
-        });
+        return Ok(file_attr(&attr));
     }
 
     fn create(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
         mode: u32,
         flags: u32
-    ) -> Result<fuse::FileAttr> {
-        let attr = match self.usecase.create(parent, name, mode, flags) {
-            Ok(attr) => attr,
+    ) -> Result<(fuse::FileAttr, u64)> {
+        // 既存のファイルを読み込みのために開く場合もあるため、作成するかどうかはusecaseで確かめる
+        if flags & handle::O_ACCMODE != handle::O_RDONLY {
+            self.writable()?;
+        }
+        let (attr, fh) = match self.usecase.create(cred, parent, name, mode, flags) {
+            Ok(created) => created,
             Err(e) => return Err(e) 
         };
-        let file_type = match attr.file_type() {
-			attr::FileType::Directory => fuse::FileType::Directory,
-			attr::FileType::TextFile => fuse::FileType::RegularFile
-		};
-
-        Ok(fuse::FileAttr{
-            ino: attr.ino(),
-            size: attr.size(),
-            blocks: 0,
-            atime: timespeck(attr.atime()),
-            mtime: timespeck(attr.mtime()),
-            ctime: timespeck(attr.ctime()),
-            crtime: time::now().to_timespec(),
-            kind: file_type,
-            perm: attr.perm(),
-            nlink: attr.nlink(),
-            uid: attr.uid(),
-            gid: attr.gid(),
-            rdev: 0,
-            flags: 0,
-        })
+
+        Ok((file_attr(&attr), fh))
     }
 
     fn unlink(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr
     ) -> Result<()> {
-        match self.usecase.unlink(parent, name) {
+        self.writable()?;
+        match self.usecase.unlink(cred, parent, name) {
             Ok(_) => Ok(()),
             Err(e) => Err(e)
         }
     }
 
     fn forget(
-        &mut self,
+        &self,
         ino: u64,
         nlookup: u64,
     ) -> Result<()> {
@@ -289,54 +273,215 @@ impl<U: usecase::Usecase> Controller for ControllerStruct<U> {
     }
 
     fn mkdir(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
         mode: u32,
     ) -> Result<fuse::FileAttr> {
-        let attr = self.usecase.mkdir(parent, name, mode)?;
-        let file_type = match attr.file_type() {
-			attr::FileType::Directory => fuse::FileType::Directory,
-			attr::FileType::TextFile => fuse::FileType::RegularFile
-		};
-
-        Ok(fuse::FileAttr{
-            ino: attr.ino(),
-            size: attr.size(),
-            blocks: 0,
-            atime: timespeck(attr.atime()),
-            mtime: timespeck(attr.mtime()),
-            ctime: timespeck(attr.ctime()),
-            crtime: time::now().to_timespec(),
-            kind: file_type,
-            perm: attr.perm(),
-            nlink: attr.nlink(),
-            uid: attr.uid(),
-            gid: attr.gid(),
-            rdev: 0,
-            flags: 0,
-        })
+        self.writable()?;
+        let attr = self.usecase.mkdir(cred, parent, name, mode)?;
+
+        Ok(file_attr(&attr))
     }
 
     fn rmdir(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
     ) -> Result<()> {
-        self.usecase.rmdir(parent, name)
+        self.writable()?;
+        self.usecase.rmdir(cred, parent, name)
     }
 
     fn rename (
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
     ) -> Result<()> {
-        self.usecase.rename(parent, name, newparent, newname)
+        self.writable()?;
+        self.usecase.rename(cred, parent, name, newparent, newname)
+    }
+
+    fn symlink(
+        &self,
+        cred: &credentials::Credentials,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+    ) -> Result<fuse::FileAttr> {
+        self.writable()?;
+        let attr = self.usecase.symlink(cred, parent, name, link)?;
+        Ok(file_attr(&attr))
+    }
+
+    fn readlink(&self, ino: u64) -> Result<Vec<u8>> {
+        self.usecase.readlink(ino)
+    }
+
+    fn access(&self, cred: &credentials::Credentials, ino: u64, mask: u32) -> Result<()> {
+        if mask & credentials::W_OK != 0 {
+            self.writable()?;
+        }
+        self.usecase.access(cred, ino, mask)
+    }
+
+    fn link(
+        &self,
+        cred: &credentials::Credentials,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<fuse::FileAttr> {
+        self.writable()?;
+        let attr = self.usecase.link(cred, ino, newparent, newname)?;
+        Ok(file_attr(&attr))
+    }
+
+    fn getxattr(&self, cred: &credentials::Credentials, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+        self.usecase.getxattr(cred, ino, name)
+    }
+
+    fn listxattr(&self, cred: &credentials::Credentials, ino: u64) -> Result<Vec<u8>> {
+        self.usecase.listxattr(cred, ino)
+    }
+
+    fn setxattr(
+        &self,
+        cred: &credentials::Credentials,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32
+    ) -> Result<()> {
+        self.writable()?;
+        self.usecase.setxattr(cred, ino, name, value, flags)
+    }
+
+    fn removexattr(&self, cred: &credentials::Credentials, ino: u64, name: &OsStr) -> Result<()> {
+        self.writable()?;
+        self.usecase.removexattr(cred, ino, name)
+    }
+
+    fn statfs(&self) -> Result<limit::StatFs> {
+        self.usecase.statfs()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.usecase.flush()
+    }
+
+    fn fsync(&self) -> Result<()> {
+        self.usecase.fsync()
+    }
+
+    fn destroy(&self) -> Result<()> {
+        // 読み取り専用でマウントした場合はイメージに触れない
+        if self.usecase.options().read_only {
+            return Ok(());
+        }
+        // アンマウント時にイメージをコンパクションする
+        // 失敗しても、それまでに追記したレコードはディスクに同期しておく
+        let compacted = self.usecase.compact();
+        let synced = self.usecase.fsync();
+        compacted.and(synced)
+    }
+}
+
+impl<U: usecase::Usecase> ControllerStruct<U> {
+    // 読み取り専用でマウントした場合は、イメージを変更する操作をusecaseに渡す前に拒否する
+    fn writable(&self) -> Result<()> {
+        if self.usecase.options().read_only {
+            return Err(entity::Error::ReadOnly.into());
+        }
+        Ok(())
     }
 }
 
 fn timespeck(st: attr::SystemTime) -> time::Timespec {
     time::Timespec::new(st.as_secs() as i64, st.subsec_nanos() as i32)
-}
\ No newline at end of file
+}
+
+fn file_type(kind: attr::FileType) -> fuse::FileType {
+    match kind {
+        attr::FileType::Directory => fuse::FileType::Directory,
+        attr::FileType::TextFile => fuse::FileType::RegularFile,
+        attr::FileType::Symlink => fuse::FileType::Symlink
+    }
+}
+
+fn file_attr(attr: &attr::Attr) -> fuse::FileAttr {
+    fuse::FileAttr {
+        ino: attr.ino(),
+        size: attr.size(),
+        blocks: 0,
+        atime: timespeck(attr.atime()),
+        mtime: timespeck(attr.mtime()),
+        ctime: timespeck(attr.ctime()),
+        crtime: time::now().to_timespec(),
+        kind: file_type(attr.kind()),
+        perm: attr.perm(),
+        nlink: attr.nlink(),
+        uid: attr.uid(),
+        gid: attr.gid(),
+        rdev: 0,
+        flags: 0,
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::mount;
+    use crate::usecase::repository::mock::MockFile;
+
+    #[test]
+    fn read_returns_requested_range() {
+        let mut controller = new(usecase::new(MockFile::new(vec![("file1", "0123456789")])));
+        controller.init(&"image.yaml".to_string()).unwrap();
+
+        let cred = credentials::Credentials::root();
+
+        assert_eq!(controller.read(&cred, 2, None, 3, 4).unwrap(), &b"3456"[..]);
+        assert_eq!(controller.read(&cred, 2, None, 8, 4096).unwrap(), &b"89"[..]);
+        assert_eq!(controller.read(&cred, 2, None, 20, 4096).unwrap(), &b""[..]);
+    }
+
+    #[test]
+    fn write_rejects_negative_offset() {
+        let mut controller = new(usecase::new(MockFile::new(vec![("file1", "0123456789")])));
+        controller.init(&"image.yaml".to_string()).unwrap();
+
+        let cred = credentials::Credentials::root();
+        let e = controller.write(&cred, 2, None, -1, b"x").unwrap_err();
+
+        assert!(matches!(e.downcast_ref::<entity::Error>(), Some(entity::Error::InvalidArgument)));
+        assert_eq!(controller.read(&cred, 2, None, 0, 4096).unwrap(), &b"0123456789"[..]);
+    }
+
+    #[test]
+    fn read_only_rejects_changes_and_keeps_atime() {
+        let options = mount::Options { read_only: true, atime: mount::Atime::StrictAtime, ..Default::default() };
+        let mut controller = new(usecase::with_options(MockFile::new(vec![("file1", "0123456789")]), options));
+        controller.init(&"image.yaml".to_string()).unwrap();
+
+        let cred = credentials::Credentials::root();
+        let read_only = |e: anyhow::Error| matches!(e.downcast_ref::<entity::Error>(), Some(entity::Error::ReadOnly));
+
+        assert!(read_only(controller.write(&cred, 2, None, 0, b"x").unwrap_err()));
+        assert!(read_only(controller.mkdir(&cred, 1, OsStr::new("dir"), 0o755).unwrap_err()));
+        assert!(read_only(controller.unlink(&cred, 1, OsStr::new("file1")).unwrap_err()));
+        assert!(read_only(controller.setxattr(&cred, 2, OsStr::new("user.a"), b"1", 0).unwrap_err()));
+        assert!(read_only(controller.access(&cred, 2, credentials::W_OK).unwrap_err()));
+        controller.access(&cred, 2, credentials::R_OK).unwrap();
+
+        assert_eq!(controller.read(&cred, 2, None, 0, 4).unwrap(), &b"0123"[..]);
+        controller.readdir(&cred, 1).unwrap();
+        assert_eq!(controller.getattr(2).unwrap().atime.sec, 0);
+        assert_eq!(controller.getattr(1).unwrap().atime.sec, 0);
+        controller.destroy().unwrap();
+    }
+}
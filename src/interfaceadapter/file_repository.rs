@@ -1,6 +1,6 @@
 use std::path;
 use crate::usecase::repository::File;
-use crate::entity::{self, attr, entry, data};
+use crate::entity::{self, attr, entry, data, limit, xattr};
 use crate::interfaceadapter::{worker};
 use anyhow::Result;
 
@@ -17,11 +17,11 @@ pub fn new<F>(file_worker: F) -> impl File
 }
 
 impl<F: worker::File> File for FileRepositoryStruct<F> {
-    fn init(&mut self, path: &path::Path) -> Result<(u64, attr::AttrsStruct, entry::EntriesStruct, data::AllDataStruct)> {
+    fn init(&mut self, path: &path::Path) -> Result<(u64, attr::AttrsStruct, entry::EntriesStruct, data::AllDataStruct, xattr::XattrsStruct)> {
         self.file_worker.init(path)
     }
 
-    fn write_data(&self, ino: u64, data: &str) -> Result<()> {
+    fn write_data(&self, ino: u64, data: &[u8]) -> Result<()> {
         self.file_worker.write_data(ino, data)
     }
 
@@ -33,6 +33,10 @@ impl<F: worker::File> File for FileRepositoryStruct<F> {
         self.file_worker.update_entry(ino, child_inos)
     }
 
+    fn update_xattr(&self, ino: u64, xattrs: &xattr::Xattrs) -> Result<()> {
+        self.file_worker.update_xattr(ino, xattrs)
+    }
+
     fn del_attr(&self, ino: u64) -> Result<()> {
         self.file_worker.del_attr(ino)
     }
@@ -40,4 +44,24 @@ impl<F: worker::File> File for FileRepositoryStruct<F> {
     fn del_data(&self, ino: u64) -> Result<()> {
         self.file_worker.del_data(ino)
     }
+
+    fn sync(&self) -> Result<()> {
+        self.file_worker.sync()
+    }
+
+    fn compact(&self, attrs: &attr::AttrsStruct, entries: &entry::EntriesStruct, data: &data::AllDataStruct, xattrs: &xattr::XattrsStruct) -> Result<()> {
+        self.file_worker.compact(attrs, entries, data, xattrs)
+    }
+
+    fn needs_compaction(&self, live: u64) -> bool {
+        self.file_worker.needs_compaction(live)
+    }
+
+    fn limits(&self) -> limit::Limits {
+        self.file_worker.limits()
+    }
+
+    fn available(&self) -> Result<u64> {
+        self.file_worker.available()
+    }
 }
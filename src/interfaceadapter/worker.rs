@@ -1,12 +1,19 @@
 use std::path;
-use crate::entity::{self, attr, data, entry};
+use crate::entity::{self, attr, data, entry, limit, xattr};
 use anyhow::Result;
 
-pub trait File {
-    fn init(&mut self, path: &path::Path) -> Result<(u64, attr::AttrsStruct, entry::EntriesStruct, data::AllDataStruct)>;
-    fn write_data(&self, ino: u64, data: &str) -> Result<()>;
+pub trait File: Send {
+    fn init(&mut self, path: &path::Path) -> Result<(u64, attr::AttrsStruct, entry::EntriesStruct, data::AllDataStruct, xattr::XattrsStruct)>;
+    fn write_data(&self, ino: u64, data: &[u8]) -> Result<()>;
     fn update_attr(&self, attr: &attr::Attr) -> Result<()>;
     fn del_attr(&self, ino: u64) -> Result<()>;
     fn del_data(&self, ino: u64) -> Result<()>;
     fn update_entry(&self, ino: u64, child_inos: &Vec<entry::Entry>) -> Result<()>;
+    fn update_xattr(&self, ino: u64, xattrs: &xattr::Xattrs) -> Result<()>;
+    // 追記したレコードをディスクに同期する
+    fn sync(&self) -> Result<()>;
+    fn compact(&self, attrs: &attr::AttrsStruct, entries: &entry::EntriesStruct, data: &data::AllDataStruct, xattrs: &xattr::XattrsStruct) -> Result<()>;
+    fn needs_compaction(&self, live: u64) -> bool;
+    fn limits(&self) -> limit::Limits;
+    fn available(&self) -> Result<u64>;
 }
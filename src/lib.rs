@@ -1,6 +1,7 @@
 pub mod externalinterface;
 mod interfaceadapter;
-mod usecase;
+pub mod usecase;
 pub mod di;
 pub mod config;
-pub mod entity;
\ No newline at end of file
+pub mod entity;#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
@@ -1,21 +1,259 @@
 use clap::Parser;
 use hfs::config;
 use hfs::di;
+use hfs::entity::limit;
 use fuse;
 use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
+use std::ptr;
+use std::thread;
 
 fn main() {
     env_logger::init();
     let config = config::Config::parse();
 
+    match &config.command {
+        Some(config::Command::Compact { image }) => {
+            if let Err(e) = di::compact(image) {
+                eprintln!("Failed compaction: {}", e);
+                process::exit(1);
+            }
+            return;
+        },
+        Some(config::Command::Convert { from, to }) => {
+            if let Err(e) = di::convert(from, to) {
+                eprintln!("Failed conversion: {}", e);
+                process::exit(1);
+            }
+            return;
+        },
+        Some(config::Command::Import { dir, image, force }) => {
+            if let Err(e) = di::import(dir, image, *force) {
+                eprintln!("Failed import: {}", e);
+                process::exit(1);
+            }
+            return;
+        },
+        Some(config::Command::Fsck { image, repair }) => {
+            // fsck(8)と同じく、修復したら1、修復していない不整合があれば4、検査できなければ8で終了する
+            match di::fsck(image, *repair) {
+                Ok(problems) => {
+                    for problem in problems.iter() {
+                        println!("{}", problem);
+                    }
+                    if problems.is_empty() {
+                        println!("clean");
+                        return;
+                    }
+                    if *repair {
+                        println!("repaired {} problems", problems.len());
+                        process::exit(1);
+                    }
+                    process::exit(4);
+                },
+                Err(e) => {
+                    eprintln!("Failed fsck: {}", e);
+                    process::exit(8);
+                }
+            }
+        },
+        Some(config::Command::Export { image, target }) => {
+            if let Err(e) = di::export(image, target) {
+                eprintln!("Failed export: {}", e);
+                process::exit(1);
+            }
+            return;
+        },
+        Some(config::Command::Usage { image }) => {
+            match di::usage(image) {
+                Ok((limits, total, per_uid)) => {
+                    println!("total: {} (limit: {})", usage(&total), quota(limits.capacity, limits.max_inodes));
+                    for (uid, used) in per_uid.iter() {
+                        let limit = match limits.quotas.get(uid) {
+                            Some(q) => quota(q.bytes, q.inodes),
+                            None => quota(None, None)
+                        };
+                        println!("uid {}: {} (quota: {})", uid, usage(used), limit);
+                    }
+                    return;
+                },
+                Err(e) => {
+                    eprintln!("Failed usage: {}", e);
+                    process::exit(1);
+                }
+            }
+        },
+        None => {}
+    }
+
     // 後ほど修正
-    let mountpoint = config.mountpoint.clone();
+    let mountpoint = config.mountpoint.clone().unwrap();
+    let kernel_options = config.kernel_options();
+    let daemon = config.daemon;
+    let pidfile_path = config.pidfile.clone();
+
+    let (fs, sync) = match di::initialize(config) {
+        Ok(initialized) => initialized,
+        Err(e) => {
+            eprintln!("Failed to initialize hfs: {}", e);
+            process::exit(1);
+        }
+    };
 
-    let mut fs = match di::initialize(config) {
-        Ok(fs) => fs,
-        Err(()) => panic!("Initialize error")
+    let joined = kernel_options.join(",");
+    let options: Vec<&OsStr> = if kernel_options.is_empty() {
+        Vec::new()
+    } else {
+        vec![OsStr::new("-o"), OsStr::new(&joined)]
+    };
+    let mut session = match fuse::Session::new(fs, Path::new(&mountpoint), &options) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Failed to mount {}: {}", mountpoint, e);
+            process::exit(1);
+        }
     };
 
 	println!("mounted hfs");
-    fuse::mount(fs, &mountpoint, &[]).expect("failed mount");
+    // マウントが終わってから切り離すことで、マウントの失敗を終了コードで伝える
+    if daemon {
+        if let Err(e) = daemonize() {
+            eprintln!("Failed to daemonize: {}", e);
+            drop(session);
+            process::exit(1);
+        }
+    }
+    // マウントとforkに成功してから作り、失敗した場合に古いpidfileを書き潰したり残したりしない
+    if let Some(path) = &pidfile_path {
+        if let Err(e) = write_pidfile(path) {
+            eprintln!("Failed to write pidfile {}: {}", path, e);
+            drop(session);
+            process::exit(1);
+        }
+    }
+    if daemon {
+        if let Err(e) = detach_stdio() {
+            eprintln!("Failed to detach from terminal: {}", e);
+        }
+    }
+
+    // forkした後、リクエストや書き込みのスレッドを作る前に設定する
+    if let Err(e) = handle_signals(mountpoint.clone(), pidfile_path.clone(), sync) {
+        eprintln!("Failed to handle signals: {}", e);
+    }
+
+    let result = session.run();
+    // まとめている変更をイメージに書き込んでから終了する
+    drop(session);
+    if let Some(path) = &pidfile_path {
+        let _ = fs::remove_file(path);
+    }
+    if let Err(e) = result {
+        eprintln!("Failed mount: {}", e);
+        process::exit(1);
+    }
+}
+
+// forkして親プロセスは終了し、子プロセスは新しいセッションで動き続ける
+// 親プロセスはマウントを残したまま終了するため、デストラクタを実行しない
+fn daemonize() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {},
+        _ => process::exit(0)
+    }
+
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// SIGTERMかSIGINTを受け取ったら、まとめている変更をディスクに同期してからアンマウントする
+// アンマウントするとsession.runから戻り、コンパクションしてから終了する
+// アンマウントできなかった場合も変更は同期済みのため、そのまま終了する
+// 他のスレッドでは受け取らないよう、スレッドを作る前にシグナルを止めておく
+fn handle_signals<S>(mountpoint: String, pidfile: Option<String>, sync: S) -> io::Result<()>
+    where S: Fn() -> anyhow::Result<()> + Send + 'static
+{
+    let mut signals: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+    }
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut()) } {
+        0 => {},
+        errno => return Err(io::Error::from_raw_os_error(errno))
+    }
+
+    thread::spawn(move || {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+            return;
+        }
+        if let Err(e) = sync() {
+            eprintln!("Failed to sync image: {}", e);
+        }
+
+        // 使用中でもマウントを切り離し、最後の参照が閉じられたらsession.runから戻る
+        let error = match process::Command::new("fusermount").args(["-u", "-z", &mountpoint]).status() {
+            Ok(status) if status.success() => return,
+            Ok(status) => format!("fusermount {}", status),
+            Err(e) => e.to_string()
+        };
+        eprintln!("Failed to unmount {}: {}", mountpoint, error);
+        if let Some(path) = &pidfile {
+            let _ = fs::remove_file(path);
+        }
+        process::exit(1);
+    });
+    Ok(())
+}
+
+// 作成してから書き込みに失敗した場合は、空のpidfileを残さない
+fn write_pidfile(path: &str) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    if let Err(e) = writeln!(file, "{}", process::id()) {
+        let _ = fs::remove_file(path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+// 端末から切り離すため、標準入出力を/dev/nullにつなぎ替える
+fn detach_stdio() -> io::Result<()> {
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+
+    for fd in 0..3 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn usage(used: &limit::Usage) -> String {
+    format!("{} bytes, {} inodes", used.bytes, used.inodes)
+}
+
+fn quota(bytes: Option<u64>, inodes: Option<u64>) -> String {
+    let bytes = match bytes {
+        Some(bytes) => format!("{} bytes", bytes),
+        None => "unlimited bytes".to_string()
+    };
+    let inodes = match inodes {
+        Some(inodes) => format!("{} inodes", inodes),
+        None => "unlimited inodes".to_string()
+    };
+
+    format!("{}, {}", bytes, inodes)
 }
@@ -0,0 +1,90 @@
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// テンプレートのイメージを構成するファイル
+const TEMPLATE_DIR: &str = "development/config-template";
+const TEMPLATE_FILES: [&str; 4] = ["attr.yaml", "entry.yaml", "data.yaml", "xattr.yaml"];
+
+// 同じプロセスで同じ名前のディレクトリを作っても重ならないように付ける番号
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// テストで使う一時ディレクトリ
+// テストがパニックしても、破棄するときに中身ごと削除する
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf
+}
+
+impl TempDir {
+    // 前回の実行で同じpidのディレクトリが残っていても、空の状態から始める
+    pub fn new(name: &str) -> TempDir {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("hfs-{}-{}-{}", name, process::id(), id));
+        remove(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        TempDir { path: path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // テンプレートのイメージを複製し、4つのファイルを指すimage.yamlのパスを返す
+    pub fn template_image(&self) -> PathBuf {
+        for file in TEMPLATE_FILES {
+            fs::copy(Path::new(TEMPLATE_DIR).join(file), self.path.join(file)).unwrap();
+        }
+        let image = self.path.join("image.yaml");
+        fs::write(&image, format!(
+            "attr: {0}/attr.yaml\nentry: {0}/entry.yaml\ndata: {0}/data.yaml\nxattr: {0}/xattr.yaml\n", self.path.display()
+        )).unwrap();
+
+        image
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        remove(&self.path);
+    }
+}
+
+// 書き込み権限を外したディレクトリがあっても削除できるよう、権限を戻してから削除する
+fn remove(path: &Path) {
+    if fs::remove_dir_all(path).is_ok() || !path.exists() {
+        return;
+    }
+    make_writable(path);
+    let _ = fs::remove_dir_all(path);
+}
+
+fn make_writable(path: &Path) {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return
+    };
+    if !metadata.is_dir() {
+        return;
+    }
+
+    let _ = fs::set_permissions(path, fs::Permissions::from_mode(0o755));
+    if let Ok(children) = fs::read_dir(path) {
+        for child in children.flatten() {
+            make_writable(&child.path());
+        }
+    }
+}
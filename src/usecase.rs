@@ -1,31 +1,61 @@
 pub mod repository;
+pub mod fsck;
+mod persist;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path;
 use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
 use anyhow::Result;
-use crate::entity::{self, attr, data, entry, lookup_count};
-
+use crate::entity::{self, attr, credentials, data, entry, handle, limit, lookup_count, mount, xattr};
+
+// 複数のスレッドから同時に呼び出せるよう、状態ごとにロックを分ける
+// デッドロックを防ぐため、ロックは必ずnamespace、entry、attr、data、xattr、lookup_count、handles、usageの順に取る
+// usageはattrとdataを書き換えたロックを持ったまま増減させ、実際の状態と食い違わないようにする
+// 名前空間を変える操作(作成、削除、移動、リンク)はnamespaceを書き込みで取り、全ての状態をロックする
+// 途中でロックを外す操作はnamespaceを読み込みで取り、その間にinodeが消えないようにする
+// 同じロックを1つのスレッドで2回取らないよう、内部の処理はロック済みの状態を引数で受け取る
 #[derive(Debug)]
 struct UsecaseStruct<F: repository::File> {
-    next_ino: Option<u64>,
-    attr: Option<attr::AttrsStruct>,
-    entry: Option<entry::EntriesStruct>,
-    data: Option<data::AllDataStruct>,
-    lookup_count: Option<lookup_count::LookupCount>,
-    file_repository: F
+    next_ino: AtomicU64,
+    namespace: RwLock<()>,
+    attr: RwLock<attr::AttrsStruct>,
+    entry: RwLock<entry::EntriesStruct>,
+    data: RwLock<data::AllDataStruct>,
+    xattr: RwLock<xattr::XattrsStruct>,
+    lookup_count: Mutex<lookup_count::LookupCount>,
+    handles: Mutex<handle::Handles>,
+    usage: Mutex<limit::UsageTable>,
+    limits: limit::Limits,
+    options: mount::Options,
+    persister: persist::Persister<F>
+}
+
+// 名前空間を変える操作で書き換える状態
+struct Tree<'a> {
+    entry: RwLockWriteGuard<'a, entry::EntriesStruct>,
+    attr: RwLockWriteGuard<'a, attr::AttrsStruct>,
+    data: RwLockWriteGuard<'a, data::AllDataStruct>,
+    xattr: RwLockWriteGuard<'a, xattr::XattrsStruct>
 }
 
-pub trait Usecase {
+pub trait Usecase: Send + Sync {
     fn init(&mut self, path: &path::Path) -> Result<()>;
-    fn lookup(&mut self, parent: u64, name: &OsStr) -> Option<attr::Attr>;
-    fn attr_from_ino(&self, ino: u64) -> Option<&attr::Attr>;
-    fn readdir(&mut self, ino: u64) -> Option<Vec<(u64, &str, attr::FileType)>>;
-    fn read(&mut self, ino: u64, offset: i64, size: u64) -> Option<&str>;
-    fn write(&mut self, ino: u64, offset: u64, data: &str) -> Result<u64>;
+    fn lookup(&self, cred: &credentials::Credentials, parent: u64, name: &OsStr) -> Result<attr::Attr>;
+    fn attr_from_ino(&self, ino: u64) -> Result<attr::Attr>;
+    fn readdir(&self, cred: &credentials::Credentials, ino: u64) -> Result<Vec<(u64, String, attr::FileType)>>;
+    fn open(&self, cred: &credentials::Credentials, ino: u64, flags: u32) -> Result<u64>;
+    fn opendir(&self, cred: &credentials::Credentials, ino: u64, flags: u32) -> Result<u64>;
+    fn release(&self, fh: u64) -> Result<()>;
+    fn read(&self, cred: &credentials::Credentials, ino: u64, fh: Option<u64>, offset: i64, size: u64) -> Result<Vec<u8>>;
+    fn write(&self, cred: &credentials::Credentials, ino: u64, fh: Option<u64>, offset: u64, data: &[u8]) -> Result<u64>;
     fn setattr(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         ino: u64,
+        fh: Option<u64>,
         mode: Option<u32>,
         uid: Option<u32>,
         gid: Option<u32>,
@@ -34,251 +64,294 @@ pub trait Usecase {
         mtime: Option<attr::SystemTime>
     ) -> Result<attr::Attr>;
     fn create(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
         mode: u32,
         flags: u32
-    ) -> Result<attr::Attr>;
+    ) -> Result<(attr::Attr, u64)>;
     fn unlink(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr
     ) -> Result<()>;
     fn forget(
-        &mut self,
+        &self,
         ino: u64,
         nlookup: u64,
     ) -> Result<()>;
     fn mkdir(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
         mode: u32,
     ) -> Result<attr::Attr>;
     fn rmdir(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
     ) -> Result<()>;
     fn rename (
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<()>;
+    fn symlink(
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
+        link: &path::Path,
+    ) -> Result<attr::Attr>;
+    fn readlink(&self, ino: u64) -> Result<Vec<u8>>;
+    fn access(&self, cred: &credentials::Credentials, ino: u64, mask: u32) -> Result<()>;
+    fn link(
+        &self,
+        cred: &credentials::Credentials,
+        ino: u64,
         newparent: u64,
         newname: &OsStr,
+    ) -> Result<attr::Attr>;
+    fn getxattr(&self, cred: &credentials::Credentials, ino: u64, name: &OsStr) -> Result<Vec<u8>>;
+    fn listxattr(&self, cred: &credentials::Credentials, ino: u64) -> Result<Vec<u8>>;
+    fn setxattr(
+        &self,
+        cred: &credentials::Credentials,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32
     ) -> Result<()>;
-    fn new_ino(&mut self) -> u64;
+    fn removexattr(&self, cred: &credentials::Credentials, ino: u64, name: &OsStr) -> Result<()>;
+    fn statfs(&self) -> Result<limit::StatFs>;
+    fn limits(&self) -> &limit::Limits;
+    fn usage(&self) -> Result<(limit::Usage, BTreeMap<u32, limit::Usage>)>;
+    fn options(&self) -> &mount::Options;
+    fn new_ino(&self) -> u64;
+    fn flush(&self) -> Result<()>;
+    fn fsync(&self) -> Result<()>;
+    fn compact(&self) -> Result<()>;
+    fn fsck(&self, repair: bool) -> Result<Vec<fsck::Problem>>;
+}
+
+pub fn new<F>(file_repository: F) -> impl Usecase
+    where F: repository::File + 'static
+{
+    with_options(file_repository, mount::Options::default())
 }
 
-pub fn new<F>(file_repository: F) -> impl Usecase 
-    where F: repository::File
+pub fn with_options<F>(file_repository: F, options: mount::Options) -> impl Usecase
+    where F: repository::File + 'static
 {
     UsecaseStruct{
-        next_ino: None,
-        attr: None,
-        entry: None,
-        data: None,
-        lookup_count: None,
-        file_repository: file_repository
+        next_ino: AtomicU64::new(0),
+        namespace: RwLock::new(()),
+        attr: RwLock::new(attr::AttrsStruct::new(HashMap::new())),
+        entry: RwLock::new(entry::EntriesStruct::new(HashMap::new())),
+        data: RwLock::new(data::AllDataStruct::new(HashMap::new())),
+        xattr: RwLock::new(xattr::XattrsStruct::new(HashMap::new())),
+        lookup_count: Mutex::new(lookup_count::LookupCount::new()),
+        handles: Mutex::new(handle::Handles::new()),
+        usage: Mutex::new(limit::UsageTable::new()),
+        limits: limit::Limits::default(),
+        options: options,
+        persister: persist::Persister::new(file_repository, options.write_behind)
     }
 }
 
-impl<F: repository::File> Usecase for UsecaseStruct<F> {
+impl<F: repository::File + 'static> Usecase for UsecaseStruct<F> {
     fn init(&mut self, path: &path::Path) -> Result<()> {
-       match self.file_repository.init(path) {
+        let mut file_repository = self.persister.repository()?;
+        match file_repository.init(path) {
             Ok(files_data) => {
-                self.next_ino = Some(files_data.0);
-                self.attr = Some(files_data.1);
-                self.entry = Some(files_data.2);
-                self.data = Some(files_data.3);
-                self.lookup_count = Some(lookup_count::LookupCount::new());
+                self.next_ino = AtomicU64::new(files_data.0);
+                self.usage = Mutex::new(count_usage(&files_data.1, &files_data.3));
+                self.attr = RwLock::new(files_data.1);
+                self.entry = RwLock::new(files_data.2);
+                self.data = RwLock::new(files_data.3);
+                self.xattr = RwLock::new(files_data.4);
+                self.limits = file_repository.limits();
+                self.lookup_count = Mutex::new(lookup_count::LookupCount::new());
+                self.handles = Mutex::new(handle::Handles::new());
                 return Ok(());
             },
             Err(e) => return Err(e)
         };
     }
 
-    fn lookup(&mut self, parent: u64, name: &OsStr) -> Option<attr::Attr> {
-        // 子供のエントリ
-        let entries = match self.entry() {
-            Some(entries) => match entries.entry(parent) {
-                Some(entry) => entry,
-                None => return None
-            },
-            None => return None
-        };
+    fn lookup(&self, cred: &credentials::Credentials, parent: u64, name: &OsStr) -> Result<attr::Attr> {
+        // forgetと数え方が食い違わないよう、lookupcountを増やすまでnamespaceを持つ
+        let _namespace = read_lock(&self.namespace)?;
+        let entry = read_lock(&self.entry)?;
+        let attr = read_lock(&self.attr)?;
+        dir_entries(&attr, &entry, parent)?;
+        check_access(&attr, cred, parent, credentials::X_OK)?;
 
         // 親ディレクトリのエントリからnameの名前を持つ子どをも探索する
-        for entry in entries.iter() {
-            let child_ino = entry.child_ino();
-            let child_attr = match self.attr() {
-                Some(attr) => match attr.attr(child_ino) {
-                    Some(child_attr) => child_attr,
-                    None => return None
-                },
-                None => return None
-            };
-            let file_name = match name.to_str() {
-                Some(file_name) => file_name,
-                None => return None
-            };
-
-            if child_attr.name == file_name {
-                let lookup_attr_data = child_attr.clone();
-
-                // mutable-----------------------------------
-                 match self.lookup_count_mut() {
-                    Some(lookup_count) => {
-                        lookup_count.update_lookupcount(child_ino);
-                        return Some(lookup_attr_data);
-                    },
-                    None => return None
-                };
-                // -------------------------------------------
-            }
+        let child_ino = child_ino_from_parent(&attr, &entry, parent, name)?;
+        let lookup_attr_data = match attr.attr(child_ino) {
+            Some(child_attr) => child_attr.clone(),
+            None => return Err(entity::Error::InternalError.into())
         };
-        None
+
+        // mutable-----------------------------------
+        updated(lock(&self.lookup_count)?.update_lookupcount(child_ino))?;
+        // -------------------------------------------
+        Ok(lookup_attr_data)
     }
 
-    fn attr_from_ino(&self, ino: u64) -> Option<&attr::Attr> {
-        let attr = match self.attr() {
-            Some(attr) => attr,
-            None => return None
-        };
+    fn attr_from_ino(&self, ino: u64) -> Result<attr::Attr> {
+        let attr = read_lock(&self.attr)?;
 
-        attr.attr(ino)
+        Ok(attr_of(&attr, ino)?.clone())
     }
 
-    fn readdir(&mut self, ino: u64) -> Option<Vec<(u64, &str, attr::FileType)>> {
+    fn readdir(&self, cred: &credentials::Credentials, ino: u64) -> Result<Vec<(u64, String, attr::FileType)>> {
+        let _namespace = read_lock(&self.namespace)?;
         let mut ret_vec = Vec::new();
-        let st = attr::SystemTime::now();
 
-        // mutable-----------------------------------
-        // atime属性のタイムスタンプを更新
-        match self.attr_mut() {
-            Some(attr) => attr.update_atime(ino, st),
-            None => return None
-        };
-        // -------------------------------------------
+        {
+            let entry = read_lock(&self.entry)?;
+            let attr = read_lock(&self.attr)?;
+            let entries = dir_entries(&attr, &entry, ino)?;
+            check_access(&attr, cred, ino, credentials::R_OK)?;
 
-        let attr = match self.attr() {
-            Some(attr) => attr,
-            None => return None
-        };
-        // attr.yamlを更新
-        match attr.attr(ino) {
-            Some(attr_data) => self.file_repository.update_attr(&attr_data),
-            None => return None
-        };
-        let entries = match self.entry() {
-            Some(entries) => match entries.entry(ino) {
-                Some(entry) => entry,
-                None => return None
-            },
-            None => return None
-        };
+            for entry in entries.iter() {
+                let child_ino = entry.child_ino();
+                let child_attr = match attr.attr(child_ino) {
+                    Some(child_attr) => child_attr,
+                    None => return Err(entity::Error::InternalError.into())
+                };
+                let file_name = entry.name().to_string();
+                let file_type = child_attr.file_type();
 
-        for entry in entries.iter() {
-            let child_ino = entry.child_ino();
-            let child_attr = match attr.attr(child_ino) {
-                Some(child_attr) => child_attr,
-                None => return None
-            };
-            let file_name = child_attr.name();
-            let file_type = child_attr.file_type();
+                ret_vec.push((child_ino, file_name, file_type));
+            }
+        }
+        self.update_atime(ino)?;
+
+        return Ok(ret_vec);
+    }
+
+    // 開くときにアクセス権を確かめ、以降の読み書きはハンドルのフラグに従う
+    // FUSE_ATOMIC_O_TRUNCを取り決めないため、O_TRUNCはカーネルが取り除き、
+    // 切り詰めは開く前にsetattrでサイズを0にする形で届く
+    fn open(&self, cred: &credentials::Credentials, ino: u64, flags: u32) -> Result<u64> {
+        let attr = read_lock(&self.attr)?;
+        file_attr(&attr, ino)?;
+        check_access(&attr, cred, ino, access_mask(flags))?;
+
+        Ok(lock(&self.handles)?.open(ino, flags))
+    }
 
-            ret_vec.push((child_ino, file_name, file_type));
+    fn opendir(&self, cred: &credentials::Credentials, ino: u64, flags: u32) -> Result<u64> {
+        let attr = read_lock(&self.attr)?;
+        match attr_of(&attr, ino)?.file_type() {
+            attr::FileType::Directory => {},
+            _ => return Err(entity::Error::NotDir.into())
         }
+        check_access(&attr, cred, ino, access_mask(flags))?;
 
-        return Some(ret_vec);
+        Ok(lock(&self.handles)?.open(ino, flags))
     }
-    
-    fn read(&mut self, ino: u64, offset: i64, size: u64) -> Option<&str> {
-        // mutable-----------------------------------
-        // atime属性を更新
-        match self.attr_mut() {
-            Some(attr) => attr.update_atime(ino, attr::SystemTime::now()),
-            None => return None
+
+    // 最後のハンドルを閉じたとき、削除を遅延していたinodeを削除する
+    fn release(&self, fh: u64) -> Result<()> {
+        let ino = match lock(&self.handles)?.release(fh) {
+            Some(handle) => handle.ino(),
+            None => return Err(entity::Error::BadHandle.into())
         };
-        // -------------------------------------------
-        // attr.yamを更新
-        match self.attr() {
-            Some(attr) => match attr.attr(ino) {
-                Some(attr_data) => {self.file_repository.update_attr(attr_data);},
-                None => return None
-            },
-            None => return None
+
+        // 閉じるたびに全ての状態をロックしないよう、まだリンクされていれば何もしない
+        match read_lock(&self.attr)?.attr(ino) {
+            Some(attr) if attr.nlink() == 0 => {},
+            _ => return Ok(())
         }
+        self.reclaim(ino)
+    }
 
-        match self.data() {
-            Some(data) => match data.all_data(ino) {
-                Some(data) => {
-                    // TODO: offsetを考慮して文字列を抜き出す
-                    let end = offset as u64 + size;
-                    return Some(data.data());
-                },
-                None => return None
-            },
-            None => return None
+    fn read(&self, cred: &credentials::Credentials, ino: u64, fh: Option<u64>, offset: i64, size: u64) -> Result<Vec<u8>> {
+        let _namespace = read_lock(&self.namespace)?;
+        let read_data = {
+            let attr = read_lock(&self.attr)?;
+            file_attr(&attr, ino)?;
+            self.check_handle(&attr, cred, ino, fh, credentials::R_OK)?;
+
+            match read_lock(&self.data)?.all_data(ino) {
+                Some(data) => read_range(data.data(), offset, size).to_vec(),
+                None => return Err(entity::Error::InternalError.into())
+            }
         };
+        self.update_atime(ino)?;
 
-        // return Some(&text_data[(offset as usize)..(end as usize)]);
-    }
-
-    fn write(&mut self, ino: u64, offset: u64, data: &str) -> Result<u64> {
-        let mut new_text_data_len: u64 = 0;
-        // dataを更新
-        // mutable: self.data-----------------------------------
-        match self.data_mut() {
-            Some(all_data) => match all_data.all_data(ino) {
-                Some(old_data) => {
-                    let new_text_data = match merge_str(offset, data, old_data.data()) {
-                        Ok(new_text_data) => new_text_data,
-                        Err(e) => return Err(e.into())
-                    };
-                    new_text_data_len = new_text_data.len() as u64;
-                    all_data.update_data(ino, data::Data::new(ino, new_text_data));
-                },
+        Ok(read_data)
+    }
+
+    fn write(&self, cred: &credentials::Credentials, ino: u64, fh: Option<u64>, offset: u64, data: &[u8]) -> Result<u64> {
+        {
+            // 同じinodeへの書き込みはattrとdataのロックで順番に行う
+            let mut attr = write_lock(&self.attr)?;
+            let mut all_data = write_lock(&self.data)?;
+            let (owner, size) = {
+                let current = file_attr(&attr, ino)?;
+                (current.uid(), current.size())
+            };
+            // O_APPENDで開いたハンドルは、カーネルが渡したoffsetではなくロックを取った時点の末尾に書き込む
+            let offset = match self.check_handle(&attr, cred, ino, fh, credentials::W_OK)? {
+                Some(handle) if handle.append() => size,
+                _ => offset
+            };
+            // offsetとdataの長さの和が溢れる場合も大きすぎるファイルとして扱う
+            let end = match offset.checked_add(data.len() as u64) {
+                Some(end) => end,
+                None => return Err(entity::Error::FileTooBig.into())
+            };
+            self.check_resize(&all_data, ino, owner, end)?;
+
+            // dataを更新
+            // mutable: self.data-----------------------------------
+            let (old_len, new_text_data) = match all_data.all_data(ino) {
+                Some(old_data) => (old_data.data().len() as u64, merge_data(offset, data, old_data.data())),
                 None => return Err(entity::Error::InternalError.into())
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
-        // -------------------------------------------
-        // atimeを更新
-        match self.attr_mut() {
-            Some(attr) => {
-                let st = attr::SystemTime::now();
-                attr.update_size(ino, new_text_data_len);
-                attr.update_mtime(ino, st);
-                attr.update_ctime(ino, st);
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
-        // attr.yamlファイルを更新
-        match self.attr() {
-            Some(attr) => match attr.attr(ino) {
-                Some(attr_data) => {self.file_repository.update_attr(&attr_data);},
+            };
+            let new_text_data_len = new_text_data.len() as u64;
+            updated(all_data.update_data(ino, data::Data::new(ino, new_text_data)))?;
+            lock(&self.usage)?.resize(owner, old_len, new_text_data_len);
+            // -------------------------------------------
+            // mtimeとctimeを更新
+            let st = attr::SystemTime::now();
+            updated(attr.update_size(ino, new_text_data_len))?;
+            updated(attr.update_mtime(ino, st))?;
+            updated(attr.update_ctime(ino, st))?;
+
+            // attr.yamlとdata.yamlへの書き込みを、変更した順に積むためロックを持ったまま積む
+            match attr.attr(ino) {
+                Some(attr_data) => self.persister.update_attr(attr_data)?,
                 None => return Err(entity::Error::InternalError.into())
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
-        // data.yamlファイルを更新
-        match self.data() {
-            Some(all_data) => match all_data.all_data(ino) {
-                Some(data) => {self.file_repository.write_data(ino, data.data());},
+            }
+            match all_data.all_data(ino) {
+                Some(data) => self.persister.write_data(ino, data.data())?,
                 None => return Err(entity::Error::InternalError.into())
-            },
-            None => return Err(entity::Error::InternalError.into())
+            }
         }
+        self.compact_if_needed();
 
-
-        Ok(new_text_data_len)
+        Ok(data.len() as u64)
     }
 
+    // fhはftruncate(2)のように開いたファイルの大きさを変える場合に渡される
     fn setattr(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         ino: u64,
+        fh: Option<u64>,
         mode: Option<u32>,
         uid: Option<u32>,
         gid: Option<u32>,
@@ -286,747 +359,1939 @@ impl<F: repository::File> Usecase for UsecaseStruct<F> {
         atime: Option<attr::SystemTime>,
         mtime: Option<attr::SystemTime>
     ) -> Result<attr::Attr> {
-         // TODO:
-        // sizeが元のファイルサイズより小さい0以外の値が指定された場合、
-        // 残すべきデータは残しつつ、いらないデータがきちんと破壊されるようにする
-
-        // TODO:
-        // 元のファイルサイズより大きい値が指定された場合、
-        // 間のデータが0(\0)で埋められるようにする
-        // let imu_entity = match &self.entity {
-        //     Some(entity) => entity,
-        //     None => return Err(entity::Error::InternalError.into()) 
-        // };
-
-        let mut com_data_size: Result<attr::Compare, attr::Error> = Err(attr::Error::InternalError);
-
-        // atimeを更新
-        match self.attr_mut() {
-            Some(attr)=> {
-                if let Some(n) = mode { attr.update_perm(ino, n as u16); };
-                if let Some(n) = uid { attr.update_uid(ino, n); };
-                if let Some(n) = gid { attr.update_gid(ino, n); };
-                if let Some(n) = size { 
-                    com_data_size = attr.cmp_data_size(ino, n as u64);
-                    attr.update_size(ino, n); 
-                };
-                if let Some(n) = atime { attr.update_atime(ino, n); };
-                if let Some(n) = mtime { attr.update_mtime(ino, n); };
-
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
-        // dataを更新
-        let mut new_data = String::new();
-
-        // 更新後のdata内のテキストを取得
-        match self.data() {
-            Some(all_data) => {
-                if let Some(n) = size {
-                    match com_data_size {
-                        Ok(c) =>  {
-                            match c {
-                                Smaller => {
-                                    // ずるしてます
-                                    new_data = smaller_data(all_data.all_data(ino).unwrap().data(), n);
-                                },
-                                Begger => {
-                                    // ずるしてます
-                                    new_data = begger_data(all_data.all_data(ino).unwrap().data(), n);
-                                },
-                                Equal => {
-                                    // 無駄な処理
-                                    // ずるしてます
-                                    new_data = all_data.all_data(ino).unwrap().data().to_string();
-                                }
-                            }
-                        },
-                        Err(_) => return Err(entity::Error::InternalError.into())
+        let attr_data = {
+            let mut attr = write_lock(&self.attr)?;
+            let current = attr_of(&attr, ino)?.clone();
+            let is_dir = match current.file_type() {
+                attr::FileType::Directory => true,
+                _ => false
+            };
+            if is_dir && size.is_some() {
+                return Err(entity::Error::IsDir.into());
+            }
 
-                    }
+            // パーミッションを確認する
+            let mut mode = mode.map(|n| n & 0o7777);
+            if let Some(n) = mode {
+                // パーミッションを変更できるのは所有者とrootのみ
+                if !cred.is_owner(&current) {
+                    return Err(entity::Error::NotPermitted.into());
                 }
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
-
-        // dataの更新
-        match self.data_mut() {
-            Some(all_data) => {all_data.update_data(ino, data::Data::new(ino, new_data));},
-            None => return Err(entity::Error::InternalError.into())
-        }
-
-        // atime.yamlを更新
-        match self.attr() {
-            Some(attr) => match attr.attr(ino) {
-                Some(attr_data) => {self.file_repository.update_attr(&attr_data);},
-                None => return Err(entity::Error::InternalError.into())
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
-        // data.yamlを更新
-        match self.data() {
-            Some(all_data) => match all_data.all_data(ino) {
-                Some(data) => {self.file_repository.write_data(ino, data.data());},
-                None => return Err(entity::Error::InternalError.into())
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
+                // 所属していないグループのファイルにSGIDは設定できない
+                if !cred.is_root() && !is_dir && cred.gid() != gid.unwrap_or(current.gid()) {
+                    mode = Some(n & !(credentials::S_ISGID as u32));
+                }
+            }
+            if let Some(n) = uid {
+                // 所有者を変更できるのはrootのみ
+                if !cred.is_root() && n != current.uid() {
+                    return Err(entity::Error::NotPermitted.into());
+                }
+            }
+            if let Some(n) = gid {
+                // 所有者は自分が所属するグループにのみ変更できる
+                if !cred.is_root() && n != current.gid() && (!cred.is_owner(&current) || n != cred.gid()) {
+                    return Err(entity::Error::NotPermitted.into());
+                }
+            }
+            // sizeが指定された場合だけdataをロックする
+            let mut all_data = match size {
+                Some(n) => {
+                    self.check_handle(&attr, cred, ino, fh, credentials::W_OK)?;
+                    let all_data = write_lock(&self.data)?;
+                    self.check_resize(&all_data, ino, current.uid(), n)?;
+                    Some(all_data)
+                },
+                None => None
+            };
+            if (atime.is_some() || mtime.is_some()) && !cred.is_owner(&current) {
+                check_access(&attr, cred, ino, credentials::W_OK)?;
+            }
 
+            // attrを更新
+            if let Some(n) = mode { updated(attr.update_perm(ino, n as u16))?; };
+            if let Some(n) = uid { updated(attr.update_uid(ino, n))?; };
+            if let Some(n) = gid { updated(attr.update_gid(ino, n))?; };
+            if let Some(n) = size { updated(attr.update_size(ino, n))?; };
+            if let Some(n) = atime { updated(attr.update_atime(ino, n))?; };
+            if let Some(n) = mtime { updated(attr.update_mtime(ino, n))?; };
+
+            // sizeが指定された場合のみdataを切り詰める
+            // 元のサイズより大きい場合は\0で埋める
+            if let (Some(n), Some(all_data)) = (size, all_data.as_mut()) {
+                let (old_len, new_data) = match all_data.all_data(ino) {
+                    Some(old_data) => (old_data.data().len() as u64, resize_data(old_data.data(), n)),
+                    None => return Err(entity::Error::InternalError.into())
+                };
+                updated(all_data.update_data(ino, data::Data::new(ino, new_data)))?;
+                lock(&self.usage)?.resize(current.uid(), old_len, n);
+            }
+            // 所有者が変わった場合は使用量を新しい所有者に移す
+            if let Some(n) = uid {
+                let bytes = match all_data.as_ref() {
+                    Some(all_data) => data_len(all_data, ino),
+                    None => {
+                        let all_data = read_lock(&self.data)?;
+                        data_len(&all_data, ino)
+                    }
+                };
+                lock(&self.usage)?.chown(current.uid(), n, bytes);
+            }
 
-        // 返却
-        let attr_data = match self.attr() {
-            Some(attr) => match attr.attr(ino) {
+            // attr.yamlを更新
+            let attr_data = match attr.attr(ino) {
                 Some(attr_data) => attr_data.clone(),
                 None => return Err(entity::Error::InternalError.into())
-            },
-            None => return Err(entity::Error::InternalError.into())
+            };
+            self.persister.update_attr(&attr_data)?;
+            // data.yamlを更新
+            if let Some(all_data) = all_data.as_ref() {
+                match all_data.all_data(ino) {
+                    Some(data) => self.persister.write_data(ino, data.data())?,
+                    None => return Err(entity::Error::InternalError.into())
+                }
+            }
+
+            attr_data
         };
-        Ok(attr_data)
+        self.compact_if_needed();
 
+        Ok(attr_data)
     }
 
     fn create(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
         mode: u32,
         flags: u32
-    ) -> Result<attr::Attr> {
-        let new_ino = self.new_ino();
-        // attrの更新
-        match self.attr_mut() {
-            Some(attr) => {
-                let name_string = match name.to_str() {
-                    Some(name) => name.to_string(),
-                    None => return Err(entity::Error::InternalError.into())
-                };
-                let new_attr = attr::Attr::new(
-                    new_ino,
-                    0,
-                    name_string,
-                    attr::FileType::TextFile,
-                    mode as u16,
-                    // TODO::ユーザID グループID固定値
-                    1000,
-                    1000,
-                    attr::SystemTime::now(),
-                    attr::SystemTime::now(),
-                    attr::SystemTime::now(),
-                    1
-                );
-                attr.inc_size(parent);
-                attr.update_attr(new_attr);
-            },
-            None => return Err(entity::Error::InternalError.into()) 
-        }
-        // dataの更新
-        match self.data_mut() {
-            Some(data) => { data.update_data(new_ino, data::Data::new(new_ino, "".to_string())); },
-            None => return Err(entity::Error::InternalError.into())
-        }
-        // entryの更新
-        match self.entry_mut() {
-            Some(entry) => { entry.insert_child_ino(parent, new_ino); },
-            None => return Err(entity::Error::InternalError.into())
+    ) -> Result<(attr::Attr, u64)> {
+        let _namespace = write_lock(&self.namespace)?;
+        // 同じ名前のファイルがあれば、名前空間をロックしたままそのファイルを開く
+        // 確かめてから開くまでの間に他の操作で作られたり削除されたりしないようにする
+        if let Some(opened) = self.open_existing(cred, parent, name, flags)? {
+            return Ok(opened);
         }
-        // attr.yamlの更新
-        match self.attr() {
-            Some(attr) => {
-                self.file_repository.update_attr(attr.attr(parent).unwrap())?;
-                self.file_repository.update_attr(attr.attr(new_ino).unwrap())?;
-            },
-            None => return Err(entity::Error::InternalError.into())
+        if self.options.read_only {
+            return Err(entity::Error::ReadOnly.into());
         }
-        // data.yamlの更新
-        match self.data() {
-            Some(all_data) => match all_data.all_data(new_ino) {
-                Some(data) => {self.file_repository.write_data(new_ino, data.data());},
+        let (attr_data, fh) = {
+            let mut tree = self.tree()?;
+            check_access(&tree.attr, cred, parent, credentials::W_OK | credentials::X_OK)?;
+            let name_string = new_child_name(&tree.attr, &tree.entry, parent, name)?;
+            self.check_limits(cred.uid(), 0, 1)?;
+            let (new_mode, new_gid) = new_mode_and_gid(&tree.attr, cred, parent, mode, false)?;
+            let new_ino = self.new_ino();
+
+            // attrの更新
+            let new_attr = attr::Attr::new(
+                new_ino,
+                0,
+                attr::FileType::TextFile,
+                new_mode,
+                cred.uid(),
+                new_gid,
+                attr::SystemTime::now(),
+                attr::SystemTime::now(),
+                attr::SystemTime::now(),
+                1
+            );
+            updated(tree.attr.inc_size(parent))?;
+            tree.attr.update_attr(new_attr);
+            lock(&self.usage)?.add(cred.uid(), 0);
+            // dataの更新
+            updated(tree.data.update_data(new_ino, data::Data::new(new_ino, Vec::new())))?;
+            // entryの更新
+            tree.entry.insert_child_ino(parent, new_ino, &name_string);
+
+            // attr.yamlの更新
+            self.persister.update_attr(tree.attr.attr(parent).unwrap())?;
+            self.persister.update_attr(tree.attr.attr(new_ino).unwrap())?;
+            // data.yamlの更新
+            match tree.data.all_data(new_ino) {
+                Some(data) => self.persister.write_data(new_ino, data.data())?,
                 None => return Err(entity::Error::InternalError.into())
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
-        // entry.yamlの更新
-        match self.entry() {
-            Some(entry) => {
-                self.file_repository.update_entry(new_ino, entry.entry(parent).unwrap())?;
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
+            }
+            // entry.yamlの更新
+            self.persister.update_entry(parent, tree.entry.entry(parent).unwrap())?;
 
-        let attr_data = match self.attr() {
-            Some(attr) => match attr.attr(parent) {
+            let attr_data = match tree.attr.attr(new_ino) {
                 Some(attr_data) => attr_data.clone(),
                 None => return Err(entity::Error::InternalError.into())
-            },
-            None => return Err(entity::Error::InternalError.into())
+            };
+            updated(lock(&self.lookup_count)?.update_lookupcount(new_ino))?;
+            // 作成したファイルはパーミッションによらずflagsで開く
+            let fh = lock(&self.handles)?.open(new_ino, flags);
+
+            (attr_data, fh)
         };
 
-        Ok(attr_data)
+        self.compact_if_needed();
+
+        Ok((attr_data, fh))
     }
 
     fn unlink(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr
     ) -> Result<()> {
-        // unlinkするファイルのino
-        let unlink_child_ino = match self.child_ino_from_parent(parent, name) {
-            Some(child_ino) => child_ino,
-            None => return Err(entity::Error::InternalError.into())
-        };
-        
-        // entryから当該のエントリを削除する
-        match self.entry_mut() {
-            Some(entry) => entry.remove_child_ino(parent, unlink_child_ino),
-            None => return Err(entity::Error::InternalError.into())
-        }
-
-        // 親attrのサイズを変更する
-        match self.attr_mut() {
-            Some(attr) => { attr.dec_size(parent); },
-            None => return Err(entity::Error::InternalError.into())
-        }
-
-        // lookupcountをチェックし、
-        // lookupcountが0ならば子attr,dataを削除する
-        // 0ではないならばLookupCountayに記録する
-        match self.lookup_count_mut() {
-            Some(lookup_count) => match lookup_count.lookup_count(unlink_child_ino) {
-                0 => {
-                    // メモリ上から任意のinoを持つattr::Attrを削除する
-                    match self.attr_mut() {
-                        Some(attr) => { attr.del(unlink_child_ino); },
-                        None => return Err(entity::Error::InternalError.into())
-                    }
+        let _namespace = write_lock(&self.namespace)?;
+        {
+            let mut tree = self.tree()?;
+            check_access(&tree.attr, cred, parent, credentials::W_OK | credentials::X_OK)?;
+            // unlinkするファイルのino
+            let unlink_child_ino = child_ino_from_parent(&tree.attr, &tree.entry, parent, name)?;
+            if let attr::FileType::Directory = attr_of(&tree.attr, unlink_child_ino)?.file_type() {
+                return Err(entity::Error::IsDir.into());
+            }
+            check_delete(&tree.attr, cred, parent, unlink_child_ino)?;
 
-                    // メモリ上から任意のinoを持つdata::Dataを削除する
-                    match self.data_mut() {
-                        Some(data) => { data.del(unlink_child_ino); },
-                        None => return Err(entity::Error::InternalError.into())
-                    }
-                },
-                _ => {
-                    lookup_count.delay(unlink_child_ino); 
-                }
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
+            // entryから当該のエントリを削除する
+            tree.entry.remove_child(parent, name.to_str().unwrap());
+            // 親attrのサイズを変更する
+            updated(tree.attr.dec_size(parent))?;
 
-        // entry.yamlを更新する
-        match self.entry() {
-            Some(entry) => {
-                self.file_repository.update_entry(parent, entry.entry(parent).unwrap())?;
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
+            // entry.yamlを更新する
+            self.persister.update_entry(parent, tree.entry.entry(parent).unwrap())?;
+            // attr.yamlの更新
+            self.persister.update_attr(tree.attr.attr(parent).unwrap())?;
 
-        // attr.yamlの更新
-        match self.attr() {
-            Some(attr) => {
-                self.file_repository.update_attr(attr.attr(parent).unwrap())?;
-            },
-            None => return Err(entity::Error::InternalError.into())
+            // nlinkを減らし、nlinkとlookupcountが0ならば子attr,dataを削除する
+            self.drop_link(&mut tree, unlink_child_ino)?;
         }
-        self.file_repository.del_attr(unlink_child_ino)?;
-
-        // data.yamlを更新する
-        self.file_repository.del_data(unlink_child_ino)?;
 
+        self.compact_if_needed();
         Ok(())
     }
 
     fn forget(
-        &mut self,
+        &self,
         ino: u64,
         nlookup: u64,
     ) -> Result<()> {
-        if let Some(lookup_count) = self.lookup_count_mut() {
-            match lookup_count.forget(ino, nlookup) {
-                Some(num) => {
-                    if num == 0 {
-                        let file_type;
-
-                        // メモリ上から任意のinoを持つattr::Attrを削除する
-                        match self.attr_mut() {
-                            Some(attr) => { 
-                                file_type = attr.attr(ino).unwrap().file_type();
-
-                                attr.del(ino);
-                            },
-                            None => return Err(entity::Error::InternalError.into())
-                        }
+        {
+            let _namespace = read_lock(&self.namespace)?;
+            let forgotten = match lock(&self.lookup_count)?.forget(ino, nlookup) {
+                Some(0) => true,
+                _ => false
+            };
+            if !forgotten {
+                return Ok(());
+            }
 
-                        // メモリ上から任意のinoを持つdata::Dataを削除する
-                        match file_type {
-                            attr::FileType::Directory => match self.entry_mut() {
-                                Some(entry) => { entry.del(ino); },
-                                None => return Err(entity::Error::InternalError.into())
-                            },
-                            attr::FileType::TextFile => match self.data_mut() {
-                                Some(data) => { data.del(ino); },
-                                None => return Err(entity::Error::InternalError.into())
-                            }
-                        }
-                        
-                    }
-                },
-                None => {}
+            // lookupcountが0になり、どこからもリンクされていない場合だけ削除に進む
+            match read_lock(&self.attr)?.attr(ino) {
+                Some(attr) if attr.nlink() == 0 => {},
+                _ => return Ok(())
             }
         }
-        Ok(())
+
+        self.reclaim(ino)
     }
 
     fn mkdir(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
         mode: u32,
     ) -> Result<attr::Attr> {
-        let new_ino = self.new_ino();
-
-        // attrの更新
-        match self.attr_mut() {
-            Some(attr) => {
-                let name_string = match name.to_str() {
-                    Some(name) => name.to_string(),
-                    None => return Err(entity::Error::InternalError.into())
-                };
-                let new_attr = attr::Attr::new(
-                    new_ino,
-                    0,
-                    name_string,
-                    attr::FileType::Directory,
-                    mode as u16,
-                    1000,
-                    1000,
-                    attr::SystemTime::now(),
-                    attr::SystemTime::now(),
-                    attr::SystemTime::now(),
-                    1
-                );
-                attr.inc_size(parent);
-                attr.update_attr(new_attr);
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
-
-        // entryの更新
-        match self.entry_mut() {
-            Some(entry) => {
-                entry.insert_child_ino(parent, new_ino);
-                entry.insert_entry(new_ino);
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
-
-        // attr.yamlの更新
-        match self.attr() {
-            Some(attr) => {
-                self.file_repository.update_attr(attr.attr(parent).unwrap())?;
-                self.file_repository.update_attr(attr.attr(new_ino).unwrap())?;
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
-
-        // entry.yamlの更新
-        match self.entry() {
-            Some(entry) => {
-                self.file_repository.update_entry(new_ino, entry.entry(parent).unwrap())?;
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
-
-        let attr_data = match self.attr() {
-            Some(attr) => match attr.attr(new_ino) {
+        let _namespace = write_lock(&self.namespace)?;
+        let attr_data = {
+            let mut tree = self.tree()?;
+            check_access(&tree.attr, cred, parent, credentials::W_OK | credentials::X_OK)?;
+            let name_string = new_child_name(&tree.attr, &tree.entry, parent, name)?;
+            self.check_limits(cred.uid(), 0, 1)?;
+            let (new_mode, new_gid) = new_mode_and_gid(&tree.attr, cred, parent, mode, true)?;
+            let new_ino = self.new_ino();
+
+            // attrの更新
+            let new_attr = attr::Attr::new(
+                new_ino,
+                0,
+                attr::FileType::Directory,
+                new_mode,
+                cred.uid(),
+                new_gid,
+                attr::SystemTime::now(),
+                attr::SystemTime::now(),
+                attr::SystemTime::now(),
+                2
+            );
+            updated(tree.attr.inc_size(parent))?;
+            updated(tree.attr.inc_nlink(parent))?;
+            tree.attr.update_attr(new_attr);
+            lock(&self.usage)?.add(cred.uid(), 0);
+
+            // entryの更新
+            tree.entry.insert_child_ino(parent, new_ino, &name_string);
+            tree.entry.insert_entry(new_ino);
+
+            // attr.yamlの更新
+            self.persister.update_attr(tree.attr.attr(parent).unwrap())?;
+            self.persister.update_attr(tree.attr.attr(new_ino).unwrap())?;
+
+            // entry.yamlの更新
+            self.persister.update_entry(parent, tree.entry.entry(parent).unwrap())?;
+            self.persister.update_entry(new_ino, tree.entry.entry(new_ino).unwrap())?;
+
+            let attr_data = match tree.attr.attr(new_ino) {
                 Some(attr_data) => attr_data.clone(),
                 None => return Err(entity::Error::InternalError.into())
-            },
-            None => return Err(entity::Error::InternalError.into())
+            };
+            updated(lock(&self.lookup_count)?.update_lookupcount(new_ino))?;
+
+            attr_data
         };
 
+        self.compact_if_needed();
 
-        match self.lookup_count_mut() {
-            Some(lookup_count) => { lookup_count.update_lookupcount(new_ino); },
-            None => return Err(entity::Error::InternalError.into())
-        }
-    
         Ok(attr_data)
     }
 
     fn rmdir(
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
     ) -> Result<()> {
-        // unlinkするディレクトリのino
-        let child_ino = match self.child_ino_from_parent(parent, name) {
-            Some(child_ino) => child_ino,
-            None => return Err(entity::Error::InternalError.into())
-        };
-
-        // ディレクトリがからかどうかを確認
-        match self.entry() {
-            Some(entry) => {
-                match entry.entry(child_ino) {
-                    Some(entry) => {
-                        if entry.len() != 0 {
-                            return Err(entity::Error::InternalError.into());
-                        }
-                    },
-                    None => return Err(entity::Error::InternalError.into())
-                }
-            },
-            None => return Err(entity::Error::InternalError.into())
-        };
-
-
-        // 親entryから当該のエントリを削除する
-        match self.entry_mut() {
-            Some(entry) => entry.remove_child_ino(parent, child_ino),
-            None => return Err(entity::Error::InternalError.into())
-        }
-
-        // 親attrのサイズを変更する
-        match self.attr_mut() {
-            Some(attr) => { attr.dec_size(parent); },
-            None => return Err(entity::Error::InternalError.into())
-        }
+        let _namespace = write_lock(&self.namespace)?;
+        {
+            let mut tree = self.tree()?;
+            check_access(&tree.attr, cred, parent, credentials::W_OK | credentials::X_OK)?;
+            // unlinkするディレクトリのino
+            let child_ino = child_ino_from_parent(&tree.attr, &tree.entry, parent, name)?;
+            check_delete(&tree.attr, cred, parent, child_ino)?;
+
+            // ディレクトリがからかどうかを確認
+            if dir_entries(&tree.attr, &tree.entry, child_ino)?.len() != 0 {
+                return Err(entity::Error::NotEmpty.into());
+            }
 
-        // lookupcountを確認し、entryから削除
-        match self.lookup_count_mut() {
-            Some(lookup_count) => match lookup_count.lookup_count(child_ino) {
-                0 => {
-                    match self.attr_mut() {
-                        Some(attr) => { attr.del(child_ino); },
-                        None => return Err(entity::Error::InternalError.into())
-                    }
+            // 親entryから当該のエントリを削除する
+            tree.entry.remove_child(parent, name.to_str().unwrap());
 
-                    match self.entry_mut() {
-                        Some(entry) => entry.del(child_ino),
-                        None => return Err(entity::Error::InternalError.into())
-                    }
-                },
-                _ => {
-                    lookup_count.delay(child_ino);
-                }
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
+            // 親attrのサイズとnlinkを変更する
+            updated(tree.attr.dec_size(parent))?;
+            updated(tree.attr.dec_nlink(parent))?;
 
-        // entry.yamlからディレクトリを削除
-        match self.entry() {
-            Some(entry) => {
-                self.file_repository.update_entry(parent, entry.entry(parent).unwrap())?;
-            },
-            None => return Err(entity::Error::InternalError.into())
-        }
+            // entry.yamlからディレクトリを削除
+            self.persister.update_entry(parent, tree.entry.entry(parent).unwrap())?;
+            // attr.yamlの更新
+            self.persister.update_attr(tree.attr.attr(parent).unwrap())?;
 
-        // attr.yamlの更新
-        match self.attr() {
-            Some(attr) => {
-                self.file_repository.update_attr(attr.attr(parent).unwrap())?;
-            },
-            None => return Err(entity::Error::InternalError.into())
+            // lookupcountを確認し、entryから削除
+            self.drop_link(&mut tree, child_ino)?;
         }
-        self.file_repository.del_attr(child_ino)?;
 
+        self.compact_if_needed();
         Ok(())
     }
 
     fn rename (
-        &mut self,
+        &self,
+        cred: &credentials::Credentials,
         parent: u64,
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
     ) -> Result<()> {
-        // 親ディレクトリが同じの場合
-        // 名前だけ変更
-        let file_type;
-
-        let ino = match self.child_ino_from_parent(parent, name) {
-            Some(ino) => ino,
-            None => return Err(entity::Error::InternalError.into())
-        };
+        let _namespace = write_lock(&self.namespace)?;
+        {
+            let mut tree = self.tree()?;
+            check_access(&tree.attr, cred, parent, credentials::W_OK | credentials::X_OK)?;
+            check_access(&tree.attr, cred, newparent, credentials::W_OK | credentials::X_OK)?;
+            let ino = child_ino_from_parent(&tree.attr, &tree.entry, parent, name)?;
+            let new_name = valid_name(newname)?.to_string();
+            check_delete(&tree.attr, cred, parent, ino)?;
+            // 別のディレクトリへ移動するディレクトリは".."を書き換えるため書き込み権限が必要
+            // 自分自身やその下のディレクトリへは移動できない
+            if let attr::FileType::Directory = attr_of(&tree.attr, ino)?.file_type() {
+                if parent != newparent {
+                    check_access(&tree.attr, cred, ino, credentials::W_OK)?;
+                    if is_under(&tree.entry, ino, newparent) {
+                        return Err(entity::Error::InvalidArgument.into());
+                    }
+                }
+            }
 
-        if parent == newparent {
-            match self.attr_mut() {
-                Some(attr) => {
-                    let new_name_str = match newname.to_str() {
-                        Some(name) => name,
-                        None => return Err(entity::Error::InternalError.into())
-                    };
-                    attr.update_name(ino, new_name_str);
-                },
-                None => return Err(entity::Error::InternalError.into())
+            // 変更先にファイル、ディレクトリがある場合は、自動で上書き
+            // ディレクトリを上書きする際、変更先がからでない場合は、エラーを返却
+            let move_ino = find_child_ino(&tree.attr, &tree.entry, newparent, &new_name)?;
+            if move_ino == Some(ino) {
+                return Ok(());
             }
-        }
 
-        // 変更先にファイル、ディレクトリがある場合は、自動で上書き
-        // ディレクトリを上書きする際、変更先がからでない場合は、エラーを返却
+            let is_dir = |attr: &attr::Attr| match attr.file_type() {
+                attr::FileType::Directory => true,
+                _ => false
+            };
+            if let Some(move_ino) = move_ino {
+                check_delete(&tree.attr, cred, newparent, move_ino)?;
+                match (is_dir(attr_of(&tree.attr, ino)?), is_dir(attr_of(&tree.attr, move_ino)?)) {
+                    (true, false) => return Err(entity::Error::NotDir.into()),
+                    (false, true) => return Err(entity::Error::IsDir.into()),
+                    (true, true) => {
+                        if dir_entries(&tree.attr, &tree.entry, move_ino)?.len() != 0 {
+                            return Err(entity::Error::NotEmpty.into());
+                        }
+                    },
+                    (false, false) => {}
+                }
 
-        // 上書きが現状できてない
-        // 同じファイルが2つある状態になる
+                // 上書きされるエントリを変更先の親から外す
+                tree.entry.remove_child(newparent, &new_name);
+                updated(tree.attr.dec_size(newparent))?;
+                if is_dir(tree.attr.attr(move_ino).unwrap()) {
+                    updated(tree.attr.dec_nlink(newparent))?;
+                }
 
-        match self.child_ino_from_parent(newparent, newname) {
-            Some(move_ino) => {
-                // 上書きする場合
                 // lookupを確認したあと
                 // 上書きされるエントリのattrと、data or entryを削除
-                match self.lookup_count_mut() {
-                    Some(lookup_count) => match lookup_count.lookup_count(move_ino) {
-                        0 => {
-                            match self.attr_mut() {
-                                // 削除するエントリのファイルタイプを取得
-                                Some(attrs) => {
-                                    match attrs.attr(move_ino) {
-                                        Some(attr) => {
-                                            file_type = attr.file_type();
-                                        },
-                                        None => return Err(entity::Error::InternalError.into())
-                                    }
-                                    // attrを削除
-                                    attrs.del(move_ino);
-
-                                    // file_typeをもとにdirectoryやtextfileを削除
-                                    match file_type {
-                                        attr::FileType::Directory => match self.entry_mut() {
-                                            Some(entry) => {
-                                                entry.del(move_ino)
-                                            },
-                                            None => return Err(entity::Error::InternalError.into())
-                                        },
-                                        attr::FileType::TextFile => match self.data_mut() {
-                                            Some(data) => { data.del(move_ino); },
-                                            None => return Err(entity::Error::InternalError.into())
-                                        }
-                                    }
-                                },
-                                None => return Err(entity::Error::InternalError.into())
-                            }
-                            // entryを削除
-
-                            match self.entry_mut() {
-                                Some(entry) => {
-                                    entry.remove_child_ino(newparent, ino);
-                                    entry.mov(ino, parent, newparent);
-                                },
-                                None => return Err(entity::Error::InternalError.into())
-                            }
-
-                            self.file_repository.del_attr(move_ino)?;
-                        },
-                        _ => {
-                            lookup_count.delay(move_ino);
-                            match self.entry_mut() {
-                                Some(entry) => {
-                                    entry.remove_child_ino(newparent, move_ino);
-                                    entry.mov(ino, parent, newparent);
-                                },
-                                None => return Err(entity::Error::InternalError.into())
-                            }
-
-                            self.file_repository.del_attr(move_ino)?;
-                        }
-                    },
-                    None => return Err(entity::Error::InternalError.into())
+                self.drop_link(&mut tree, move_ino)?;
+            }
+
+            tree.entry.mov(parent, name.to_str().unwrap(), newparent, &new_name);
+
+            let moved_dir = is_dir(attr_of(&tree.attr, ino)?);
+            updated(tree.attr.update_ctime(ino, attr::SystemTime::now()))?;
+            if parent != newparent {
+                updated(tree.attr.dec_size(parent))?;
+                updated(tree.attr.inc_size(newparent))?;
+                if moved_dir {
+                    updated(tree.attr.dec_nlink(parent))?;
+                    updated(tree.attr.inc_nlink(newparent))?;
                 }
+            }
+
+            self.persister.update_attr(tree.attr.attr(parent).unwrap())?;
+            self.persister.update_attr(tree.attr.attr(newparent).unwrap())?;
+            self.persister.update_attr(tree.attr.attr(ino).unwrap())?;
+
+            self.persister.update_entry(parent, tree.entry.entry(parent).unwrap())?;
+            self.persister.update_entry(newparent, tree.entry.entry(newparent).unwrap())?;
+        }
+
+        self.compact_if_needed();
+
+        Ok(())
+    }
+
+    fn symlink(
+        &self,
+        cred: &credentials::Credentials,
+        parent: u64,
+        name: &OsStr,
+        link: &path::Path,
+    ) -> Result<attr::Attr> {
+        let _namespace = write_lock(&self.namespace)?;
+        let attr_data = {
+            let mut tree = self.tree()?;
+            check_access(&tree.attr, cred, parent, credentials::W_OK | credentials::X_OK)?;
+            let name_string = new_child_name(&tree.attr, &tree.entry, parent, name)?;
+            let target = link.as_os_str().as_bytes().to_vec();
+            self.check_limits(cred.uid(), target.len() as u64, 1)?;
+            let (_, new_gid) = new_mode_and_gid(&tree.attr, cred, parent, 0o777, false)?;
+            let new_ino = self.new_ino();
+            let st = attr::SystemTime::now();
+
+            // attrの更新
+            let new_attr = attr::Attr::new(
+                new_ino,
+                target.len() as u64,
+                attr::FileType::Symlink,
+                0o777,
+                cred.uid(),
+                new_gid,
+                st,
+                st,
+                st,
+                1
+            );
+            updated(tree.attr.inc_size(parent))?;
+            tree.attr.update_attr(new_attr);
+            lock(&self.usage)?.add(cred.uid(), target.len() as u64);
+            // リンク先のパスをdataとして保存する
+            updated(tree.data.update_data(new_ino, data::Data::new(new_ino, target)))?;
+            // entryの更新
+            tree.entry.insert_child_ino(parent, new_ino, &name_string);
+
+            // attr.yamlの更新
+            self.persister.update_attr(tree.attr.attr(parent).unwrap())?;
+            self.persister.update_attr(tree.attr.attr(new_ino).unwrap())?;
+            // data.yamlの更新
+            match tree.data.all_data(new_ino) {
+                Some(data) => self.persister.write_data(new_ino, data.data())?,
+                None => return Err(entity::Error::InternalError.into())
+            }
+            // entry.yamlの更新
+            self.persister.update_entry(parent, tree.entry.entry(parent).unwrap())?;
+
+            let attr_data = match tree.attr.attr(new_ino) {
+                Some(attr_data) => attr_data.clone(),
+                None => return Err(entity::Error::InternalError.into())
+            };
+            updated(lock(&self.lookup_count)?.update_lookupcount(new_ino))?;
+
+            attr_data
+        };
+
+        self.compact_if_needed();
+
+        Ok(attr_data)
+    }
+
+    fn access(&self, cred: &credentials::Credentials, ino: u64, mask: u32) -> Result<()> {
+        let attr = read_lock(&self.attr)?;
+        // F_OKは存在だけを確認する
+        attr_of(&attr, ino)?;
+        if mask == 0 {
+            return Ok(());
+        }
+
+        check_access(&attr, cred, ino, mask)
+    }
+
+    fn readlink(&self, ino: u64) -> Result<Vec<u8>> {
+        let attr = read_lock(&self.attr)?;
+        match attr_of(&attr, ino)?.file_type() {
+            attr::FileType::Symlink => {},
+            _ => return Err(entity::Error::NotSymlink.into())
+        }
+
+        match read_lock(&self.data)?.all_data(ino) {
+            Some(data) => Ok(data.data().to_vec()),
+            None => Err(entity::Error::InternalError.into())
+        }
+    }
+
+    fn link(
+        &self,
+        cred: &credentials::Credentials,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<attr::Attr> {
+        let _namespace = write_lock(&self.namespace)?;
+        let attr_data = {
+            let mut tree = self.tree()?;
+            // ディレクトリへのハードリンクは作成できない
+            if let attr::FileType::Directory = attr_of(&tree.attr, ino)?.file_type() {
+                return Err(entity::Error::NotPermitted.into());
+            }
+            check_access(&tree.attr, cred, newparent, credentials::W_OK | credentials::X_OK)?;
+            let name_string = new_child_name(&tree.attr, &tree.entry, newparent, newname)?;
+
+            // attrの更新
+            updated(tree.attr.inc_nlink(ino))?;
+            updated(tree.attr.update_ctime(ino, attr::SystemTime::now()))?;
+            updated(tree.attr.inc_size(newparent))?;
+            // entryの更新
+            tree.entry.insert_child_ino(newparent, ino, &name_string);
+            // attr.yamlの更新
+            self.persister.update_attr(tree.attr.attr(ino).unwrap())?;
+            self.persister.update_attr(tree.attr.attr(newparent).unwrap())?;
+            // entry.yamlの更新
+            self.persister.update_entry(newparent, tree.entry.entry(newparent).unwrap())?;
+
+            let attr_data = attr_of(&tree.attr, ino)?.clone();
+            updated(lock(&self.lookup_count)?.update_lookupcount(ino))?;
+
+            attr_data
+        };
+
+        self.compact_if_needed();
+
+        Ok(attr_data)
+    }
+
+    fn getxattr(&self, cred: &credentials::Credentials, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+        let attr = read_lock(&self.attr)?;
+        let name = check_xattr(&attr, cred, ino, name, false)?;
+
+        match read_lock(&self.xattr)?.value(ino, name) {
+            Some(value) => Ok(value.to_vec()),
+            None => Err(entity::Error::NoXattr.into())
+        }
+    }
+
+    // 名前をNULで区切って並べて返す
+    // rootでなければtrusted名前空間の名前は見えない
+    fn listxattr(&self, cred: &credentials::Credentials, ino: u64) -> Result<Vec<u8>> {
+        let attr = read_lock(&self.attr)?;
+        attr_of(&attr, ino)?;
+        let mut names = Vec::new();
+
+        let xattr = read_lock(&self.xattr)?;
+        let xattrs = match xattr.xattr(ino) {
+            Some(xattrs) => xattrs,
+            None => return Ok(names)
+        };
+        for name in xattrs.keys() {
+            if name.starts_with(XATTR_TRUSTED) && !cred.is_root() {
+                continue;
+            }
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+
+        Ok(names)
+    }
+
+    fn setxattr(
+        &self,
+        cred: &credentials::Credentials,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32
+    ) -> Result<()> {
+        {
+            let mut attr = write_lock(&self.attr)?;
+            let mut xattr = write_lock(&self.xattr)?;
+            let name = check_xattr(&attr, cred, ino, name, true)?.to_string();
+            if value.len() > xattr::XATTR_SIZE_MAX {
+                return Err(entity::Error::XattrTooBig.into());
+            }
+
+            // XATTR_CREATEは既にある場合、XATTR_REPLACEはない場合に失敗する
+            let exists = xattr.value(ino, &name).is_some();
+            match flags & (xattr::XATTR_CREATE | xattr::XATTR_REPLACE) {
+                0 => {},
+                xattr::XATTR_CREATE if exists => return Err(entity::Error::Exists.into()),
+                xattr::XATTR_CREATE => {},
+                xattr::XATTR_REPLACE if !exists => return Err(entity::Error::NoXattr.into()),
+                xattr::XATTR_REPLACE => {},
+                _ => return Err(entity::Error::InvalidArgument.into())
+            }
+
+            // mutable-----------------------------------
+            xattr.set(ino, &name, value);
+            // -------------------------------------------
+
+            self.save_xattr(&mut attr, &xattr, ino)?;
+        }
+        self.compact_if_needed();
+
+        Ok(())
+    }
+
+    fn removexattr(&self, cred: &credentials::Credentials, ino: u64, name: &OsStr) -> Result<()> {
+        {
+            let mut attr = write_lock(&self.attr)?;
+            let mut xattr = write_lock(&self.xattr)?;
+            let name = check_xattr(&attr, cred, ino, name, true)?.to_string();
+
+            // mutable-----------------------------------
+            match xattr.remove(ino, &name) {
+                Some(_) => {},
+                None => return Err(entity::Error::NoXattr.into())
+            }
+            // -------------------------------------------
+
+            self.save_xattr(&mut attr, &xattr, ino)?;
+        }
+        self.compact_if_needed();
+
+        Ok(())
+    }
+
+    // ブロック数はdataの大きさ、inode数はattrの数から求める
+    // 容量の上限がなければ、イメージを置いているホストの空き容量を空きブロックとする
+    fn statfs(&self) -> Result<limit::StatFs> {
+        let limits = &self.limits;
+        // 書き込みスレッドを待つ間は状態をロックしない
+        let available = match limits.capacity {
+            Some(_) => 0,
+            None => self.persister.repository()?.available()?
+        };
+        let attr = read_lock(&self.attr)?;
+        let data = read_lock(&self.data)?;
+        let next_ino = self.next_ino.load(Ordering::SeqCst);
+
+        let used_blocks: u64 = data.data().values()
+            .map(|data| limit::blocks(data.data().len() as u64))
+            .sum();
+        let (blocks, bfree) = match limits.capacity {
+            Some(capacity) => {
+                let blocks = capacity / limit::BLOCK_SIZE;
+                (blocks, blocks.saturating_sub(used_blocks))
             },
             None => {
-                // 上書きせずにそのまま移動
-                match self.entry_mut() {
-                    Some(entry) => {
-                        entry.mov(ino, parent, newparent);
-                    },
-                    None => return Err(entity::Error::InternalError.into())
+                let bfree = available / limit::BLOCK_SIZE;
+                (used_blocks + bfree, bfree)
+            }
+        };
+
+        // inoは使い回さないため、残りのino番号の数も空きinode数の上限になる
+        let used_inodes = attr.attrs().len() as u64;
+        let remaining_inos = u64::MAX - next_ino;
+        let files = match limits.max_inodes {
+            Some(max_inodes) => max_inodes,
+            None => used_inodes.saturating_add(remaining_inos)
+        };
+
+        Ok(limit::StatFs {
+            blocks: blocks,
+            bfree: bfree,
+            bavail: bfree,
+            files: files,
+            ffree: files.saturating_sub(used_inodes).min(remaining_inos),
+            bsize: limit::BLOCK_SIZE as u32,
+            namelen: entity::NAME_MAX as u32
+        })
+    }
+
+    fn limits(&self) -> &limit::Limits {
+        &self.limits
+    }
+
+    fn usage(&self) -> Result<(limit::Usage, BTreeMap<u32, limit::Usage>)> {
+        let usage = lock(&self.usage)?;
+
+        Ok((usage.total(), usage.per_uid().clone()))
+    }
+
+    fn options(&self) -> &mount::Options {
+        &self.options
+    }
+
+    fn new_ino(&self) -> u64 {
+        self.next_ino.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    // メモリ上の状態からイメージを書き直し、書き終わるまで待つ
+    // まとめている変更をイメージに書き込むまで待つ
+    fn flush(&self) -> Result<()> {
+        self.persister.flush()
+    }
+
+    // まとめている変更を書き込み、イメージのファイルをディスクに同期するまで待つ
+    // イメージ全体を同期するため、どのinodeのfsyncでも同じことを行う
+    fn fsync(&self) -> Result<()> {
+        self.persister.sync()
+    }
+
+    fn compact(&self) -> Result<()> {
+        if self.options.read_only {
+            return Err(entity::Error::ReadOnly.into());
+        }
+
+        {
+            let entry = read_lock(&self.entry)?;
+            let attr = read_lock(&self.attr)?;
+            let data = read_lock(&self.data)?;
+            let xattr = read_lock(&self.xattr)?;
+            self.persister.compact(&attr, &entry, &data, &xattr)?;
+        }
+        self.persister.flush()
+    }
+
+    // 不整合を調べ、repairがtrueなら修復した状態でイメージを書き直す
+    fn fsck(&self, repair: bool) -> Result<Vec<fsck::Problem>> {
+        let _namespace = write_lock(&self.namespace)?;
+        let problems = {
+            let mut tree = self.tree()?;
+            let problems = fsck::check(&mut tree.attr, &mut tree.entry, &mut tree.data, &mut tree.xattr, repair, || self.new_ino());
+
+            if repair && !problems.is_empty() {
+                if self.options.read_only {
+                    return Err(entity::Error::ReadOnly.into());
                 }
+                // 修復でinodeやdataが変わるため、使用量を数え直す
+                *lock(&self.usage)? = count_usage(&tree.attr, &tree.data);
+                self.persister.compact(&tree.attr, &tree.entry, &tree.data, &tree.xattr)?;
             }
+            problems
+        };
+        self.persister.flush()?;
+
+        Ok(problems)
+    }
+}
+
+impl<F: repository::File + 'static> UsecaseStruct<F> {
+    // createで指定された名前のファイルがあれば、lookupと同じくlookupcountを増やして開く
+    // 名前が無い場合だけNoneを返して作成に進み、それ以外のエラーはそのまま返す
+    // namespaceを書き込みでロックしてから呼ぶ
+    fn open_existing(&self, cred: &credentials::Credentials, parent: u64, name: &OsStr, flags: u32) -> Result<Option<(attr::Attr, u64)>> {
+        let entry = read_lock(&self.entry)?;
+        let attr = read_lock(&self.attr)?;
+        dir_entries(&attr, &entry, parent)?;
+        check_access(&attr, cred, parent, credentials::X_OK)?;
+
+        let ino = match child_ino_from_parent(&attr, &entry, parent, name) {
+            Ok(ino) => ino,
+            Err(e) => match e.downcast_ref::<entity::Error>() {
+                Some(entity::Error::NotFound) => return Ok(None),
+                _ => return Err(e)
+            }
+        };
+        if flags & handle::O_EXCL != 0 {
+            return Err(entity::Error::Exists.into());
         }
+        let attr_data = file_attr(&attr, ino)?.clone();
+        check_access(&attr, cred, ino, access_mask(flags))?;
+
+        updated(lock(&self.lookup_count)?.update_lookupcount(ino))?;
+        let fh = lock(&self.handles)?.open(ino, flags);
+
+        Ok(Some((attr_data, fh)))
+    }
+
+    // 名前空間を変える操作のために、全ての状態を決まった順に書き込みでロックする
+    // namespaceを書き込みでロックしてから呼ぶ
+    // その間は全ての読み込みが待たされるが、ロックを持つのはメモリ上の更新と変更を積む間だけで、
+    // イメージへの書き込みは書き込みスレッドが行うため待たない
+    // unlinkやrenameは最後のリンクを外すとdataと拡張属性も削除するため、状態ごとに分けて取ることはしない
+    fn tree(&self) -> Result<Tree<'_>> {
+        Ok(Tree {
+            entry: write_lock(&self.entry)?,
+            attr: write_lock(&self.attr)?,
+            data: write_lock(&self.data)?,
+            xattr: write_lock(&self.xattr)?
+        })
+    }
 
-        match self.attr() {
-            Some(attr) => {
-                self.file_repository.update_attr(attr.attr(parent).unwrap())?;
-                self.file_repository.update_attr(attr.attr(newparent).unwrap())?;
+    // リンクを1つ外し、nlinkとlookupcountがともに0で開かれてもいない場合は
+    // attrと、data or entryを削除する
+    fn drop_link(&self, tree: &mut Tree, ino: u64) -> Result<()> {
+        let file_type = attr_of(&tree.attr, ino)?.file_type();
+        updated(tree.attr.update_ctime(ino, attr::SystemTime::now()))?;
+        let nlink = match file_type {
+            // ディレクトリは親からのリンクが外れた時点で削除される
+            attr::FileType::Directory => {
+                updated(tree.attr.update_nlink(ino, 0))?;
+                0
             },
-            None => return Err(entity::Error::InternalError.into())
+            _ => match tree.attr.dec_nlink(ino) {
+                Ok(nlink) => nlink,
+                Err(_) => return Err(entity::Error::InternalError.into())
+            }
+        };
+
+        // 他のディレクトリからリンクされている場合はattrのみ更新する
+        if nlink > 0 {
+            self.persister.update_attr(tree.attr.attr(ino).unwrap())?;
+            return Ok(());
         }
 
-        match self.entry() {
-            Some(entry) => {
-                self.file_repository.update_entry(parent, entry.entry(parent).unwrap())?;
-                self.file_repository.update_entry(newparent, entry.entry(newparent).unwrap())?;
-            },
-            None => return Err(entity::Error::InternalError.into())
+        let has_xattr = tree.xattr.xattr(ino).is_some();
+
+        // lookupcountが0で、開いているハンドルもなければメモリ上から削除する
+        // そうでなければforgetかreleaseされるまで削除を遅延する
+        {
+            let mut lookup_count = lock(&self.lookup_count)?;
+            let open = lock(&self.handles)?.is_open(ino);
+            match (lookup_count.lookup_count(ino), open) {
+                (0, false) => {
+                    let mut usage = lock(&self.usage)?;
+                    del_inode(tree, &mut usage, ino)?
+                },
+                _ => lookup_count.delay(ino)
+            }
+        }
+
+        self.persister.del_attr(ino)?;
+        match file_type {
+            attr::FileType::Directory => {},
+            _ => self.persister.del_data(ino)?
+        }
+        if has_xattr {
+            self.persister.update_xattr(ino, &xattr::Xattrs::new())?;
         }
 
         Ok(())
     }
 
-    fn new_ino(&mut self) -> u64 {
-        let next_ino = match self.next_ino {
-            Some(next_ino) => {
-                self.next_ino = Some(next_ino + 1);
-                next_ino + 1
-            },
-            None => return 0
+    // nlinkが0で、lookupcountもハンドルも残っていないinodeをメモリ上から削除する
+    // 削除は名前空間を変えるため、書き込みでロックし直してから確かめ直す
+    // 削除を遅延している間の書き込みやatimeの更新でレコードが積まれ直しているため、
+    // イメージからの削除もここで積み直す
+    fn reclaim(&self, ino: u64) -> Result<()> {
+        let _namespace = write_lock(&self.namespace)?;
+        let mut tree = self.tree()?;
+        let (nlink, file_type) = match tree.attr.attr(ino) {
+            Some(attr) => (attr.nlink(), attr.file_type()),
+            None => return Ok(())
         };
-        next_ino
+
+        let pinned = lock(&self.lookup_count)?.lookup_count(ino) > 0 || lock(&self.handles)?.is_open(ino);
+        if nlink > 0 || pinned {
+            return Ok(());
+        }
+
+        let has_xattr = tree.xattr.xattr(ino).is_some();
+        {
+            let mut usage = lock(&self.usage)?;
+            del_inode(&mut tree, &mut usage, ino)?;
+        }
+
+        self.persister.del_attr(ino)?;
+        match file_type {
+            attr::FileType::Directory => {},
+            _ => self.persister.del_data(ino)?
+        }
+        if has_xattr {
+            self.persister.update_xattr(ino, &xattr::Xattrs::new())?;
+        }
+        Ok(())
     }
-}
 
-impl<F: repository::File>  UsecaseStruct<F> {
-    fn entry(&self) -> Option<&entry::EntriesStruct> {
-        match &self.entry {
-            Some(entry) => Some(entry),
-            None => None
+    // ハンドルを渡された場合は、開いたときのフラグで読み書きできるかを確かめる
+    // 渡されない場合はその時点のパーミッションを確かめる
+    // 書き込み位置をフラグで決められるよう、確かめたハンドルを返す
+    fn check_handle(&self, attrs: &attr::AttrsStruct, cred: &credentials::Credentials, ino: u64, fh: Option<u64>, mask: u32) -> Result<Option<handle::Handle>> {
+        let fh = match fh {
+            Some(fh) => fh,
+            None => return check_access(attrs, cred, ino, mask).map(|_| None)
+        };
+        let handles = lock(&self.handles)?;
+        let handle = match handles.handle(fh) {
+            Some(handle) if handle.ino() == ino => handle,
+            _ => return Err(entity::Error::BadHandle.into())
+        };
+
+        if (mask & credentials::R_OK != 0 && !handle.readable()) || (mask & credentials::W_OK != 0 && !handle.writable()) {
+            return Err(entity::Error::BadHandle.into());
         }
+        Ok(Some(*handle))
     }
 
-    fn entry_mut(&mut self) -> Option<&mut entry::EntriesStruct> {
-        match &mut self.entry {
-            Some(entry) => Some(entry),
-            None => None
+    // readとreaddirでatimeを更新し、attr.yamlに書き込む
+    // マウント時のatimeの設定が更新を求める場合だけ書き込み、読み取り専用では更新しない
+    // 多くの場合は更新しないため、読み込みのロックで確かめてから書き込みでロックする
+    fn update_atime(&self, ino: u64) -> Result<()> {
+        if self.options.read_only {
+            return Ok(());
         }
+        let now = attr::SystemTime::now();
+        let needs_update = {
+            let attr = read_lock(&self.attr)?;
+            self.options.atime.needs_update(attr_of(&attr, ino)?, now)
+        };
+        if !needs_update {
+            return Ok(());
+        }
+
+        {
+            // mutable-----------------------------------
+            let mut attr = write_lock(&self.attr)?;
+            if attr.update_atime(ino, now).is_err() {
+                return Err(entity::Error::NotFound.into());
+            }
+            // -------------------------------------------
+
+            match attr.attr(ino) {
+                Some(attr_data) => self.persister.update_attr(attr_data)?,
+                None => return Err(entity::Error::NotFound.into())
+            }
+        }
+        self.compact_if_needed();
+
+        Ok(())
     }
 
-    fn attr(&self) -> Option<&attr::AttrsStruct> {
-        match &self.attr {
-            Some(attr) => Some(attr),
-            None => None
+    // ログが生存しているレコードに比べて大きくなりすぎていればコンパクションする
+    // 書き込みのたびに状態をロックしないよう、生存しているレコードの数は使用量から見積もる
+    // inodeごとにattrと、entryかdataのどちらかが1つずつ残る(拡張属性は数えないため少し早めに書き直す)
+    // 書き直すのは書き込みスレッドが求めたときだけで、ログがcompaction_ratio倍に育つまで次は起きない
+    // 状態を読み込みでロックしたまま書き直すため、その間は変更を行うリクエストが待たされる
+    // 呼び出し側は状態のロックを外してから呼ぶ
+    fn compact_if_needed(&self) {
+        if self.options.read_only {
+            return;
+        }
+
+        let live = match lock(&self.usage) {
+            Ok(usage) => usage.total().inodes * 2,
+            Err(_) => return
+        };
+        if !self.persister.needs_compaction(live) {
+            return;
+        }
+
+        let (entry, attr, data, xattr) = match (read_lock(&self.entry), read_lock(&self.attr), read_lock(&self.data), read_lock(&self.xattr)) {
+            (Ok(entry), Ok(attr), Ok(data), Ok(xattr)) => (entry, attr, data, xattr),
+            _ => return
+        };
+        if let Err(e) = self.persister.compact(&attr, &entry, &data, &xattr) {
+            // コンパクションに失敗してもログは正しいままなので処理は続ける
+            eprintln!("Failed compaction: {}", e);
         }
     }
 
-    fn attr_mut(&mut self) -> Option<&mut attr::AttrsStruct> {
-        match &mut self.attr {
-            Some(attr) => Some(attr),
-            None => None
+    // uidのinodeが増えても、イメージ全体と所有者ごとの上限を超えないか確かめる
+    // 使用量は変更のたびに更新している値と比べる
+    fn check_limits(&self, uid: u32, bytes: u64, inodes: u64) -> Result<()> {
+        let quota = self.limits.quotas.get(&uid).copied();
+        if self.limits.capacity.is_none() && self.limits.max_inodes.is_none() && quota.is_none() {
+            return Ok(());
+        }
+        if bytes == 0 && inodes == 0 {
+            return Ok(());
+        }
+
+        let usage = lock(&self.usage)?;
+        let total = usage.total();
+        let exceeds = |limit: Option<u64>, used: u64, added: u64| added > 0 && limit.map_or(false, |limit| used + added > limit);
+
+        if exceeds(self.limits.capacity, total.bytes, bytes) || exceeds(self.limits.max_inodes, total.inodes, inodes) {
+            return Err(entity::Error::NoSpace.into());
+        }
+        if let Some(quota) = quota {
+            let used = usage.of(uid);
+            if exceeds(quota.bytes, used.bytes, bytes) || exceeds(quota.inodes, used.inodes, inodes) {
+                return Err(entity::Error::QuotaExceeded.into());
+            }
         }
+
+        Ok(())
     }
 
-    fn data(&self) -> Option<&data::AllDataStruct> {
-        match &self.data {
-            Some(data) => Some(data),
-            None => None
+    // ファイルをsizeバイトまで大きくできるか確かめる
+    // 小さくする場合は常に許可する
+    fn check_resize(&self, all_data: &data::AllDataStruct, ino: u64, owner: u32, size: u64) -> Result<()> {
+        let current = data_len(all_data, ino);
+        if size <= current {
+            return Ok(());
         }
+
+        if size > self.limits.file_size() {
+            return Err(entity::Error::FileTooBig.into());
+        }
+        self.check_limits(owner, size - current, 0)
     }
 
-    fn data_mut(&mut self) -> Option<&mut data::AllDataStruct> {
-        match &mut self.data {
-            Some(data) => Some(data),
-            None => None
+    // 拡張属性の変更をctimeとともにイメージに書き込む
+    fn save_xattr(&self, attrs: &mut attr::AttrsStruct, xattrs: &xattr::XattrsStruct, ino: u64) -> Result<()> {
+        updated(attrs.update_ctime(ino, attr::SystemTime::now()))?;
+
+        self.persister.update_attr(attrs.attr(ino).unwrap())?;
+        match xattrs.xattr(ino) {
+            Some(xattr) => self.persister.update_xattr(ino, xattr),
+            None => self.persister.update_xattr(ino, &xattr::Xattrs::new())
         }
     }
+}
+
+fn read_lock<T>(rwlock: &RwLock<T>) -> Result<RwLockReadGuard<'_, T>> {
+    match rwlock.read() {
+        Ok(guard) => Ok(guard),
+        Err(_) => Err(entity::Error::InternalError.into())
+    }
+}
+
+fn write_lock<T>(rwlock: &RwLock<T>) -> Result<RwLockWriteGuard<'_, T>> {
+    match rwlock.write() {
+        Ok(guard) => Ok(guard),
+        Err(_) => Err(entity::Error::InternalError.into())
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>> {
+    match mutex.lock() {
+        Ok(guard) => Ok(guard),
+        Err(_) => Err(entity::Error::InternalError.into())
+    }
+}
+
+// エンティティの更新に失敗した場合は内部エラーとして返す
+// 呼び出す前にinodeが存在することを確かめているため、通常は失敗しない
+fn updated<T, E>(result: std::result::Result<T, E>) -> Result<T> {
+    match result {
+        Ok(value) => Ok(value),
+        Err(_) => Err(entity::Error::InternalError.into())
+    }
+}
+
+fn attr_of(attrs: &attr::AttrsStruct, ino: u64) -> Result<&attr::Attr> {
+    match attrs.attr(ino) {
+        Some(attr) => Ok(attr),
+        None => Err(entity::Error::NotFound.into())
+    }
+}
+
+// open(2)のフラグから、開くために必要なアクセス権を求める
+fn access_mask(flags: u32) -> u32 {
+    match flags & handle::O_ACCMODE {
+        handle::O_RDONLY => credentials::R_OK,
+        handle::O_WRONLY => credentials::W_OK,
+        _ => credentials::R_OK | credentials::W_OK
+    }
+}
+
+// maskで指定した操作が許可されていなければPermissionDeniedを返す
+fn check_access(attrs: &attr::AttrsStruct, cred: &credentials::Credentials, ino: u64, mask: u32) -> Result<()> {
+    if cred.permits(attr_of(attrs, ino)?, mask) {
+        return Ok(());
+    }
 
-    fn lookup_count(&self) -> Option<&lookup_count::LookupCount> {
-        match &self.lookup_count {
-            Some(lookup_count) => Some(lookup_count),
-            None => None
+    Err(entity::Error::PermissionDenied.into())
+}
+
+// 新しく作るinodeのmodeとgidを決める
+// modeはカーネルがumaskを落とした値をそのまま使い、親ディレクトリのSGIDがONの場合は親のgidを引き継ぐ
+// ディレクトリは親のSGIDとスティッキービットも引き継ぐ
+fn new_mode_and_gid(attrs: &attr::AttrsStruct, cred: &credentials::Credentials, parent: u64, mode: u32, is_dir: bool) -> Result<(u16, u32)> {
+    let parent_attr = attr_of(attrs, parent)?;
+    let mut new_mode = (mode & 0o7777) as u16;
+    let mut new_gid = cred.gid();
+
+    if parent_attr.perm() & credentials::S_ISGID != 0 {
+        new_gid = parent_attr.gid();
+        if is_dir {
+            new_mode |= credentials::S_ISGID;
         }
     }
+    if is_dir {
+        new_mode |= parent_attr.perm() & credentials::S_ISVTX;
+    } else if new_gid != cred.gid() && !cred.is_root() {
+        // 所属していないグループのSGIDは付けられない
+        new_mode &= !credentials::S_ISGID;
+    }
 
-    fn lookup_count_mut(&mut self) -> Option<&mut lookup_count::LookupCount> {
-        match &mut self.lookup_count {
-            Some(lookup_count) => Some(lookup_count),
-            None => None
+    Ok((new_mode, new_gid))
+}
+
+// スティッキービットを持つディレクトリからは、所有者しか削除・移動できない
+fn check_delete(attrs: &attr::AttrsStruct, cred: &credentials::Credentials, parent: u64, ino: u64) -> Result<()> {
+    if cred.may_delete(attr_of(attrs, parent)?, attr_of(attrs, ino)?) {
+        return Ok(());
+    }
+
+    Err(entity::Error::NotPermitted.into())
+}
+
+fn child_ino_from_parent(attrs: &attr::AttrsStruct, entries: &entry::EntriesStruct, parent_ino: u64, name: &OsStr) -> Result<u64> {
+    let file_name = valid_name(name)?;
+
+    match find_child_ino(attrs, entries, parent_ino, file_name)? {
+        Some(child_ino) => Ok(child_ino),
+        None => Err(entity::Error::NotFound.into())
+    }
+}
+
+fn find_child_ino(attrs: &attr::AttrsStruct, entries: &entry::EntriesStruct, parent_ino: u64, name: &str) -> Result<Option<u64>> {
+    let entries = dir_entries(attrs, entries, parent_ino)?;
+
+    // 親ディレクトリのエントリからnameの名前を持つ子どをも探索する
+    for entry in entries.iter() {
+        if entry.name() == name {
+            return Ok(Some(entry.child_ino()));
+        }
+    };
+
+    Ok(None)
+}
+
+// inoがancestorか、その下にあるディレクトリであればtrueを返す
+// ディレクトリは1つの親からしかリンクされないため、親をたどってルートまで上る
+fn is_under(entries: &entry::EntriesStruct, ancestor: u64, ino: u64) -> bool {
+    let mut current = ino;
+
+    // 壊れたイメージで親がループしていても止まるよう、ディレクトリの数だけたどる
+    for _ in 0..=entries.entries().len() {
+        if current == ancestor {
+            return true;
+        }
+        let parent = entries.entries().iter()
+            .find(|(_, children)| children.iter().any(|child| child.child_ino() == current))
+            .map(|(parent, _)| *parent);
+        match parent {
+            Some(parent) => current = parent,
+            None => return false
         }
     }
 
-    fn child_ino_from_parent(&self, parent_ino: u64, name: &OsStr) -> Option<u64> {
-        let entries = match self.entry() {
-            Some(entries) => match entries.entry(parent_ino) {
-                Some(entry) => entry,
-                None => return None
-            },
-            None => return None
-        };
+    false
+}
 
-        // 親ディレクトリのエントリからnameの名前を持つ子どをも探索する
-        for entry in entries.iter() {
-            let child_ino = entry.child_ino();
-            let child_attr = match self.attr() {
-                Some(attr) => match attr.attr(child_ino) {
-                    Some(child_attr) => child_attr,
-                    None => return None
-                },
-                None => return None
-            };
-            let file_name = match name.to_str() {
-                Some(file_name) => file_name,
-                None => return None
-            };
+// 親ディレクトリに新しく作成するエントリの名前を検証する
+fn new_child_name(attrs: &attr::AttrsStruct, entries: &entry::EntriesStruct, parent_ino: u64, name: &OsStr) -> Result<String> {
+    let file_name = valid_name(name)?;
 
-            if child_attr.name == file_name {
-                return Some(child_ino);
-            }
-        };
+    match find_child_ino(attrs, entries, parent_ino, file_name)? {
+        Some(_) => Err(entity::Error::Exists.into()),
+        None => Ok(file_name.to_string())
+    }
+}
 
-        None
+// inoがディレクトリであればそのエントリを返す
+fn dir_entries<'a>(attrs: &attr::AttrsStruct, entries: &'a entry::EntriesStruct, ino: u64) -> Result<&'a Vec<entry::Entry>> {
+    match attr_of(attrs, ino)?.file_type() {
+        attr::FileType::Directory => {},
+        _ => return Err(entity::Error::NotDir.into())
+    }
+
+    match entries.entry(ino) {
+        Some(entry) => Ok(entry),
+        None => Err(entity::Error::InternalError.into())
     }
 }
 
+// inoがディレクトリでなければそのattrを返す
+fn file_attr(attrs: &attr::AttrsStruct, ino: u64) -> Result<&attr::Attr> {
+    let attr = attr_of(attrs, ino)?;
 
-// この関数テストコード欲しい
-// バグが多い気がする
-fn merge_str(offset: u64, data: &str, old_str: &str) -> Result<String> {
-    let mut new_string_len = offset + data.len() as u64;
-    if new_string_len < old_str.len() as u64 {
-        new_string_len = old_str.len() as u64;
+    match attr.file_type() {
+        attr::FileType::Directory => Err(entity::Error::IsDir.into()),
+        _ => Ok(attr)
     }
-    let mut new_string_vec = Vec::with_capacity(new_string_len as usize);
+}
 
-    let data_bytes = data.as_bytes();
-    let old_str_bytes = old_str.as_bytes();
+// メモリ上から任意のinoを持つattrと、data or entry、拡張属性を削除する
+// 削除したinodeの分だけ使用量も減らす
+fn del_inode(tree: &mut Tree, usage: &mut limit::UsageTable, ino: u64) -> Result<()> {
+    let bytes = data_len(&tree.data, ino);
+    let file_type = match tree.attr.del(ino) {
+        Ok(attr) => {
+            usage.remove(attr.uid(), bytes);
+            attr.file_type()
+        },
+        Err(_) => return Err(entity::Error::NotFound.into())
+    };
+
+    match file_type {
+        attr::FileType::Directory => tree.entry.del(ino),
+        attr::FileType::TextFile | attr::FileType::Symlink => { tree.data.del(ino).ok(); }
+    }
+    tree.xattr.del(ino);
+
+    Ok(())
+}
 
-    for _ in 0..new_string_len {
-        new_string_vec.push(0);
+// イメージを読み込んだときと修復したときに、全てのinodeから使用量を数える
+// dataのバイト数はハードリンクがあってもinodeごとに1回だけ数える
+fn count_usage(attrs: &attr::AttrsStruct, all_data: &data::AllDataStruct) -> limit::UsageTable {
+    let mut usage = limit::UsageTable::new();
+
+    for (ino, attr) in attrs.attrs() {
+        usage.add(attr.uid(), data_len(all_data, *ino));
     }
 
-    
-    for (i, &data) in old_str_bytes.iter().enumerate() {
-        new_string_vec[i] = data;
+    usage
+}
+
+// inoのdataのバイト数を返す
+// ディレクトリのようにdataを持たないinodeは0とする
+fn data_len(all_data: &data::AllDataStruct, ino: u64) -> u64 {
+    match all_data.all_data(ino) {
+        Some(data) => data.data().len() as u64,
+        None => 0
     }
+}
 
-    for (i, &data) in data_bytes.iter().enumerate() {
-        new_string_vec[i + offset as usize] = data;
+// 拡張属性の名前を検査し、名前空間ごとの権限を確かめる
+// writeがtrueならsetxattrとremovexattr、falseならgetxattrとして扱う
+fn check_xattr<'a>(attrs: &attr::AttrsStruct, cred: &credentials::Credentials, ino: u64, name: &'a OsStr, write: bool) -> Result<&'a str> {
+    let attr = attr_of(attrs, ino)?;
+    let name = match name.to_str() {
+        Some(name) => name,
+        None => return Err(entity::Error::InvalidName.into())
+    };
+    if name.is_empty() || name.len() > xattr::XATTR_NAME_MAX {
+        return Err(entity::Error::XattrRange.into());
     }
 
-    match String::from_utf8(new_string_vec) {
-        Ok(string) => Ok(string),
-        Err(e) => Err(e.into())
+    if name.starts_with(XATTR_USER) {
+        // user名前空間は通常のファイルとディレクトリのみが持てる
+        match attr.file_type() {
+            attr::FileType::TextFile | attr::FileType::Directory => {},
+            attr::FileType::Symlink if write => return Err(entity::Error::NotPermitted.into()),
+            attr::FileType::Symlink => return Err(entity::Error::NoXattr.into())
+        }
+        // スティッキービットを持つディレクトリは所有者しか変更できない
+        if write && attr.perm() & credentials::S_ISVTX != 0 && !cred.is_owner(attr) {
+            return Err(entity::Error::NotPermitted.into());
+        }
+        let mask = if write { credentials::W_OK } else { credentials::R_OK };
+        check_access(attrs, cred, ino, mask)?;
+    } else if name.starts_with(XATTR_TRUSTED) {
+        // rootにしか見えない
+        if !cred.is_root() {
+            return Err(if write { entity::Error::NotPermitted.into() } else { entity::Error::NoXattr.into() });
+        }
+    } else if name.starts_with(XATTR_SECURITY) {
+        // 誰でも読めるが、変更できるのはrootのみ
+        if write && !cred.is_root() {
+            return Err(entity::Error::NotPermitted.into());
+        }
+    } else {
+        // system名前空間(ACL)と未知の名前空間は扱わない
+        return Err(entity::Error::NotSupported.into());
     }
+
+    Ok(name)
 }
 
-fn begger_data(data: &str, size: u64) -> String {
-    let mut new_string_vec = Vec::with_capacity(size as usize);
-    let data_bytes = data.as_bytes();
+// 拡張属性の名前空間
+const XATTR_USER: &str = "user.";
+const XATTR_TRUSTED: &str = "trusted.";
+const XATTR_SECURITY: &str = "security.";
 
-    for &data in data_bytes.iter() {
-        new_string_vec.push(data);
+fn valid_name(name: &OsStr) -> Result<&str> {
+    let file_name = match name.to_str() {
+        Some(file_name) => file_name,
+        None => return Err(entity::Error::InvalidName.into())
+    };
+
+    if file_name.len() > entity::NAME_MAX {
+        return Err(entity::Error::NameTooLong.into());
     }
-    for _ in data.len()..(size as usize) {
-        new_string_vec.push(0);
+    if !entry::valid_name(file_name) {
+        return Err(entity::Error::InvalidName.into());
     }
 
-    match String::from_utf8(new_string_vec) {
-        Ok(string) => string,
-        Err(e) => String::new()
+    Ok(file_name)
+}
+
+// offsetからsizeバイト分を切り出す
+// EOFを超える部分は返却しない
+fn read_range(data: &[u8], offset: i64, size: u64) -> &[u8] {
+    let len = data.len() as u64;
+    let start = if offset < 0 { 0 } else { offset as u64 };
+
+    if start >= len {
+        return &[];
     }
+
+    let end = match start.checked_add(size) {
+        Some(end) if end < len => end,
+        _ => len
+    };
+
+    &data[(start as usize)..(end as usize)]
 }
 
-fn smaller_data(data: &str,  size: u64) -> String {
+// offset+dataの長さはcheck_resizeで上限内であることを確かめてから呼ぶ
+fn merge_data(offset: u64, data: &[u8], old_data: &[u8]) -> Vec<u8> {
+    let mut new_data_len = offset + data.len() as u64;
+    if new_data_len < old_data.len() as u64 {
+        new_data_len = old_data.len() as u64;
+    }
+    let mut new_data = Vec::with_capacity(new_data_len as usize);
+
+    new_data.extend_from_slice(old_data);
+    new_data.resize(new_data_len as usize, 0);
+    new_data[(offset as usize)..(offset as usize + data.len())].copy_from_slice(data);
+
+    new_data
+}
+
+fn resize_data(data: &[u8], size: u64) -> Vec<u8> {
+    let mut new_data = data.to_vec();
+    new_data.resize(size as usize, 0);
+
+    new_data
+}
 
-    let mut new_string_vec = Vec::with_capacity(size as usize);
-    let data_bytes = data.as_bytes();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+    use crate::usecase::repository::mock::{self, MockFile};
+
+    fn root() -> credentials::Credentials {
+        credentials::Credentials::root()
+    }
+
+    // モックのファイルを所有するユーザ
+    fn owner() -> credentials::Credentials {
+        credentials::Credentials::new(1000, 1000)
+    }
+
+    fn other() -> credentials::Credentials {
+        credentials::Credentials::new(2000, 2000)
+    }
+
+    fn usecase_with(data: &str) -> impl Usecase {
+        let mut usecase = new(MockFile::new(vec![("file1", data)]));
+        usecase.init(Path::new("image.yaml")).unwrap();
+        usecase
+    }
+
+    #[test]
+    fn read_honors_offset_and_size() {
+        let usecase = usecase_with("0123456789");
+
+        assert_eq!(usecase.read(&root(), 2, None, 0, 4).unwrap(), &b"0123"[..]);
+        assert_eq!(usecase.read(&root(), 2, None, 4, 4).unwrap(), &b"4567"[..]);
+    }
+
+    #[test]
+    fn atime_policy_decides_update() {
+        let st = |sec| attr::SystemTime::new(sec, 0);
+        let accessed = attr::Attr::new(2, 0, attr::FileType::TextFile, 0o644, 0, 0, st(100), st(50), st(50), 1);
+        let modified = attr::Attr::new(2, 0, attr::FileType::TextFile, 0o644, 0, 0, st(50), st(50), st(50), 1);
+
+        assert!(!mount::Atime::NoAtime.needs_update(&modified, st(200)));
+        assert!(mount::Atime::StrictAtime.needs_update(&accessed, st(200)));
+        assert!(!mount::Atime::RelAtime.needs_update(&accessed, st(200)));
+        assert!(mount::Atime::RelAtime.needs_update(&accessed, st(100 + 24 * 60 * 60)));
+        assert!(mount::Atime::RelAtime.needs_update(&modified, st(200)));
+    }
+
+    #[test]
+    fn read_updates_atime_per_policy() {
+        let options = mount::Options { atime: mount::Atime::NoAtime, ..Default::default() };
+        let mut usecase = with_options(MockFile::new(vec![("file1", "0123")]), options);
+        usecase.init(Path::new("image.yaml")).unwrap();
+        usecase.read(&root(), 2, None, 0, 4).unwrap();
+        usecase.readdir(&root(), 1).unwrap();
+        assert_eq!(usecase.attr_from_ino(2).unwrap().atime().as_secs(), 0);
+        assert_eq!(usecase.attr_from_ino(1).unwrap().atime().as_secs(), 0);
+
+        // relatimeでもmtime以前のatimeは更新する
+        let usecase = usecase_with("0123");
+        usecase.read(&root(), 2, None, 0, 4).unwrap();
+        assert!(usecase.attr_from_ino(2).unwrap().atime().as_secs() > 0);
+    }
+
+    #[test]
+    fn read_returns_partial_final_chunk() {
+        let usecase = usecase_with("0123456789");
+
+        assert_eq!(usecase.read(&root(), 2, None, 8, 4096).unwrap(), &b"89"[..]);
+    }
 
-    for (i, &data) in data_bytes.iter().enumerate() {
-        if size  == i as u64 {
-            break;
+    #[test]
+    fn read_past_eof_returns_empty() {
+        let usecase = usecase_with("0123456789");
+
+        assert_eq!(usecase.read(&root(), 2, None, 10, 4096).unwrap(), &b""[..]);
+        assert_eq!(usecase.read(&root(), 2, None, 131072, 131072).unwrap(), &b""[..]);
+    }
+
+    #[test]
+    fn read_in_chunks_reassembles_file() {
+        let text = "abcdefghij".repeat(1000);
+        let usecase = usecase_with(&text);
+        let mut buf = Vec::new();
+        let mut offset = 0;
+
+        loop {
+            let chunk = usecase.read(&root(), 2, None, offset, 4096).unwrap().to_vec();
+            if chunk.is_empty() {
+                break;
+            }
+            offset += chunk.len() as i64;
+            buf.extend(chunk);
         }
-        new_string_vec.push(data);
+
+        assert_eq!(buf, text.as_bytes());
+    }
+
+    #[test]
+    fn write_stores_non_utf8_bytes() {
+        let usecase = usecase_with("");
+        let bytes = [0x89, b'P', b'N', b'G', 0x00, 0xff, 0xfe];
+
+        assert_eq!(usecase.write(&root(), 2, None, 0, &bytes).unwrap(), bytes.len() as u64);
+        assert_eq!(usecase.write(&root(), 2, None, 7, &[0xc3]).unwrap(), 1);
+        assert_eq!(usecase.read(&root(), 2, None, 0, 4096).unwrap(), &[0x89, b'P', b'N', b'G', 0x00, 0xff, 0xfe, 0xc3][..]);
+        assert_eq!(usecase.attr_from_ino(2).unwrap().size(), 8);
+    }
+
+    #[test]
+    fn setattr_truncates_and_extends_data() {
+        let usecase = usecase_with("0123456789");
+
+        usecase.setattr(&root(), 2, None, None, None, None, Some(4), None, None).unwrap();
+        assert_eq!(usecase.read(&root(), 2, None, 0, 4096).unwrap(), &b"0123"[..]);
+
+        usecase.setattr(&root(), 2, None, None, None, None, Some(6), None, None).unwrap();
+        assert_eq!(usecase.read(&root(), 2, None, 0, 4096).unwrap(), &b"0123\0\0"[..]);
+    }
+
+    #[test]
+    fn setattr_without_size_keeps_data() {
+        let usecase = usecase_with("0123456789");
+
+        usecase.setattr(&root(), 2, None, Some(0o600), None, None, None, None, None).unwrap();
+        assert_eq!(usecase.read(&root(), 2, None, 0, 4096).unwrap(), &b"0123456789"[..]);
+    }
+
+    #[test]
+    fn read_unknown_ino_is_not_found() {
+        let usecase = usecase_with("0123456789");
+
+        assert!(matches!(error_of(usecase.read(&root(), 100, None, 0, 4096)), entity::Error::NotFound));
+    }
+
+    fn error_of<T>(result: Result<T>) -> entity::Error {
+        result.err().unwrap().downcast::<entity::Error>().unwrap()
+    }
+
+    #[test]
+    fn lookup_missing_name_is_not_found() {
+        let usecase = usecase_with("");
+
+        assert!(matches!(error_of(usecase.lookup(&root(), 1, OsStr::new("nothing"))), entity::Error::NotFound));
+        assert!(matches!(error_of(usecase.lookup(&root(), 2, OsStr::new("file1"))), entity::Error::NotDir));
+    }
+
+    #[test]
+    fn create_opens_existing_name_unless_excl() {
+        let usecase = usecase_with("0123");
+
+        let (attr, fh) = usecase.create(&root(), 1, OsStr::new("file1"), 0o600, handle::O_RDWR).unwrap();
+        assert_eq!(attr.ino(), 2);
+        assert_eq!(attr.size(), 4);
+        assert_eq!(usecase.read(&root(), 2, Some(fh), 0, 4096).unwrap(), b"0123");
+        usecase.release(fh).unwrap();
+
+        assert!(matches!(error_of(usecase.create(&root(), 1, OsStr::new("file1"), 0o644, handle::O_EXCL)), entity::Error::Exists));
+        assert!(matches!(error_of(usecase.create(&other(), 1, OsStr::new("file1"), 0o644, handle::O_WRONLY)), entity::Error::PermissionDenied));
+        assert!(matches!(error_of(usecase.create(&root(), 1, OsStr::new(&"a".repeat(256)), 0o644, 0)), entity::Error::NameTooLong));
+    }
+
+    #[test]
+    fn create_returns_new_attr() {
+        let usecase = usecase_with("");
+
+        let (attr, _) = usecase.create(&root(), 1, OsStr::new("file2"), 0o644, 0).unwrap();
+        assert_eq!(attr.nlink(), 1);
+        assert_eq!(usecase.lookup(&root(), 1, OsStr::new("file2")).unwrap().ino(), attr.ino());
+    }
+
+    #[test]
+    fn symlink_stores_target() {
+        let usecase = usecase_with("");
+
+        let attr = usecase.symlink(&root(), 1, OsStr::new("link"), Path::new("../target/file")).unwrap();
+        assert!(matches!(attr.file_type(), attr::FileType::Symlink));
+        assert_eq!(attr.size(), 14);
+        assert_eq!(usecase.readlink(attr.ino()).unwrap(), b"../target/file");
+        assert!(matches!(usecase.lookup(&root(), 1, OsStr::new("link")).unwrap().file_type(), attr::FileType::Symlink));
+        assert!(matches!(error_of(usecase.readlink(2)), entity::Error::NotSymlink));
+        assert!(matches!(error_of(usecase.symlink(&root(), 1, OsStr::new("file1"), Path::new("x"))), entity::Error::Exists));
+    }
+
+    #[test]
+    fn link_shares_inode_across_directories() {
+        let usecase = usecase_with("0123");
+        let dir = usecase.mkdir(&root(), 1, OsStr::new("dir"), 0o755).unwrap();
+
+        let attr = usecase.link(&root(), 2, dir.ino(), OsStr::new("other")).unwrap();
+        assert_eq!(attr.ino(), 2);
+        assert_eq!(attr.nlink(), 2);
+        assert_eq!(usecase.lookup(&root(), dir.ino(), OsStr::new("other")).unwrap().ino(), 2);
+
+        usecase.unlink(&root(), 1, OsStr::new("file1")).unwrap();
+        assert_eq!(usecase.attr_from_ino(2).unwrap().nlink(), 1);
+        assert_eq!(usecase.read(&root(), 2, None, 0, 4096).unwrap(), b"0123");
+        assert_eq!(usecase.readdir(&root(), dir.ino()).unwrap()[0].1, "other");
+    }
+
+    #[test]
+    fn link_to_directory_is_not_permitted() {
+        let usecase = usecase_with("");
+        let dir = usecase.mkdir(&root(), 1, OsStr::new("dir"), 0o755).unwrap();
+
+        assert!(matches!(error_of(usecase.link(&root(), dir.ino(), 1, OsStr::new("dir2"))), entity::Error::NotPermitted));
+        assert!(matches!(error_of(usecase.link(&root(), 2, 1, OsStr::new("dir"))), entity::Error::Exists));
+    }
+
+    #[test]
+    fn unlink_keeps_inode_until_forgotten() {
+        let usecase = usecase_with("0123");
+
+        usecase.lookup(&root(), 1, OsStr::new("file1")).unwrap();
+        usecase.unlink(&root(), 1, OsStr::new("file1")).unwrap();
+        assert_eq!(usecase.attr_from_ino(2).unwrap().nlink(), 0);
+        assert_eq!(usecase.read(&root(), 2, None, 0, 4096).unwrap(), b"0123");
+
+        usecase.forget(2, 1).unwrap();
+        assert!(matches!(error_of(usecase.attr_from_ino(2)), entity::Error::NotFound));
+    }
+
+    #[test]
+    fn forget_keeps_linked_inode() {
+        let usecase = usecase_with("0123");
+
+        usecase.lookup(&root(), 1, OsStr::new("file1")).unwrap();
+        usecase.forget(2, 1).unwrap();
+        assert_eq!(usecase.read(&root(), 2, None, 0, 4096).unwrap(), b"0123");
+    }
+
+    #[test]
+    fn unlinked_file_stays_readable_until_released() {
+        let usecase = usecase_with("0123");
+
+        usecase.lookup(&root(), 1, OsStr::new("file1")).unwrap();
+        let fh = usecase.open(&root(), 2, handle::O_RDWR).unwrap();
+        usecase.unlink(&root(), 1, OsStr::new("file1")).unwrap();
+        usecase.forget(2, 1).unwrap();
+        assert_eq!(usecase.write(&root(), 2, Some(fh), 4, b"45").unwrap(), 2);
+        assert_eq!(usecase.read(&root(), 2, Some(fh), 0, 4096).unwrap(), b"012345");
+
+        usecase.release(fh).unwrap();
+        assert!(matches!(error_of(usecase.attr_from_ino(2)), entity::Error::NotFound));
+        assert!(matches!(error_of(usecase.release(fh)), entity::Error::BadHandle));
     }
 
-    match String::from_utf8(new_string_vec) {
-        Ok(string) => string,
-        Err(e) => String::new()
+    #[test]
+    fn handle_access_mode_is_enforced() {
+        let usecase = usecase_with("0123");
+
+        let reader = usecase.open(&root(), 2, handle::O_RDONLY).unwrap();
+        let writer = usecase.open(&root(), 2, handle::O_WRONLY).unwrap();
+        assert!(matches!(error_of(usecase.write(&root(), 2, Some(reader), 0, b"x")), entity::Error::BadHandle));
+        assert!(matches!(error_of(usecase.read(&root(), 2, Some(writer), 0, 4096)), entity::Error::BadHandle));
+        assert!(matches!(error_of(usecase.setattr(&root(), 2, Some(reader), None, None, None, Some(0), None, None)), entity::Error::BadHandle));
+        let (file2, _) = usecase.create(&root(), 1, OsStr::new("file2"), 0o644, 0).unwrap();
+        assert!(matches!(error_of(usecase.read(&root(), file2.ino(), Some(reader), 0, 4096)), entity::Error::BadHandle));
+        assert!(matches!(error_of(usecase.read(&root(), 2, Some(100), 0, 4096)), entity::Error::BadHandle));
+        assert_eq!(usecase.write(&root(), 2, Some(writer), 0, b"x").unwrap(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn open_checks_permission_and_type() {
+        let usecase = usecase_with("0123");
+
+        assert!(usecase.open(&other(), 2, handle::O_RDONLY).is_ok());
+        assert!(matches!(error_of(usecase.open(&other(), 2, handle::O_WRONLY)), entity::Error::PermissionDenied));
+        assert!(matches!(error_of(usecase.opendir(&root(), 2, 0)), entity::Error::NotDir));
+
+        let fh = usecase.opendir(&root(), 1, 0).unwrap();
+        usecase.release(fh).unwrap();
+    }
+
+    #[test]
+    fn mkdir_and_rmdir_update_parent_nlink() {
+        let usecase = usecase_with("");
+
+        let dir = usecase.mkdir(&root(), 1, OsStr::new("dir"), 0o755).unwrap();
+        assert_eq!(dir.nlink(), 2);
+        assert_eq!(usecase.attr_from_ino(1).unwrap().nlink(), 3);
+
+        usecase.rmdir(&root(), 1, OsStr::new("dir")).unwrap();
+        assert_eq!(usecase.attr_from_ino(1).unwrap().nlink(), 2);
+    }
+
+    #[test]
+    fn rmdir_non_empty_is_not_empty() {
+        let usecase = usecase_with("");
+        let dir = usecase.mkdir(&root(), 1, OsStr::new("dir"), 0o755).unwrap();
+        usecase.create(&root(), dir.ino(), OsStr::new("file"), 0o644, 0).unwrap();
+
+        assert!(matches!(error_of(usecase.rmdir(&root(), 1, OsStr::new("dir"))), entity::Error::NotEmpty));
+        assert!(matches!(error_of(usecase.rmdir(&root(), 1, OsStr::new("file1"))), entity::Error::NotDir));
+        assert!(matches!(error_of(usecase.unlink(&root(), 1, OsStr::new("dir"))), entity::Error::IsDir));
+    }
+
+    #[test]
+    fn rename_replaces_target_and_keeps_new_name() {
+        let usecase = usecase_with("0123");
+        let dir = usecase.mkdir(&root(), 1, OsStr::new("dir"), 0o755).unwrap();
+        usecase.create(&root(), dir.ino(), OsStr::new("file2"), 0o644, 0).unwrap();
+
+        usecase.rename(&root(), 1, OsStr::new("file1"), dir.ino(), OsStr::new("file2")).unwrap();
+
+        let attr = usecase.lookup(&root(), dir.ino(), OsStr::new("file2")).unwrap();
+        assert_eq!(attr.ino(), 2);
+        assert_eq!(usecase.readdir(&root(), dir.ino()).unwrap().len(), 1);
+        assert!(matches!(error_of(usecase.lookup(&root(), 1, OsStr::new("file1"))), entity::Error::NotFound));
+        usecase.mkdir(&root(), 1, OsStr::new("dir2"), 0o755).unwrap();
+        assert!(matches!(error_of(usecase.rename(&root(), 1, OsStr::new("dir2"), dir.ino(), OsStr::new("file2"))), entity::Error::NotDir));
+    }
+
+    #[test]
+    fn rename_into_own_subdirectory_is_invalid() {
+        let usecase = usecase_with("");
+        let dir = usecase.mkdir(&root(), 1, OsStr::new("dir"), 0o755).unwrap();
+        let sub = usecase.mkdir(&root(), dir.ino(), OsStr::new("sub"), 0o755).unwrap();
+
+        assert!(matches!(error_of(usecase.rename(&root(), 1, OsStr::new("dir"), dir.ino(), OsStr::new("x"))), entity::Error::InvalidArgument));
+        assert!(matches!(error_of(usecase.rename(&root(), 1, OsStr::new("dir"), sub.ino(), OsStr::new("x"))), entity::Error::InvalidArgument));
+        assert_eq!(usecase.lookup(&root(), 1, OsStr::new("dir")).unwrap().ino(), dir.ino());
+
+        // 下から上への移動はできる
+        usecase.rename(&root(), dir.ino(), OsStr::new("sub"), 1, OsStr::new("sub")).unwrap();
+        usecase.rename(&root(), 1, OsStr::new("dir"), sub.ino(), OsStr::new("dir")).unwrap();
+        assert_eq!(usecase.lookup(&root(), sub.ino(), OsStr::new("dir")).unwrap().ino(), dir.ino());
+    }
+
+    #[test]
+    fn other_user_is_denied_by_mode_bits() {
+        let usecase = usecase_with("0123");
+
+        assert_eq!(usecase.read(&other(), 2, None, 0, 4096).unwrap(), b"0123");
+        assert!(matches!(error_of(usecase.write(&other(), 2, None, 0, b"x")), entity::Error::PermissionDenied));
+        assert!(matches!(error_of(usecase.create(&other(), 1, OsStr::new("file2"), 0o644, 0)), entity::Error::PermissionDenied));
+        assert!(matches!(error_of(usecase.unlink(&other(), 1, OsStr::new("file1"))), entity::Error::PermissionDenied));
+        assert_eq!(usecase.write(&owner(), 2, None, 0, b"x").unwrap(), 1);
+
+        usecase.setattr(&owner(), 2, None, Some(0o600), None, None, None, None, None).unwrap();
+        assert!(matches!(error_of(usecase.read(&other(), 2, None, 0, 4096)), entity::Error::PermissionDenied));
+        assert_eq!(usecase.read(&root(), 2, None, 0, 4096).unwrap(), b"x123");
+    }
+
+    #[test]
+    fn only_owner_or_root_may_change_mode_and_owner() {
+        let usecase = usecase_with("");
+
+        assert!(matches!(error_of(usecase.setattr(&other(), 2, None, Some(0o777), None, None, None, None, None)), entity::Error::NotPermitted));
+        assert!(matches!(error_of(usecase.setattr(&owner(), 2, None, None, Some(2000), None, None, None, None)), entity::Error::NotPermitted));
+        assert!(matches!(error_of(usecase.setattr(&owner(), 2, None, None, None, Some(2000), None, None, None)), entity::Error::NotPermitted));
+
+        let attr = usecase.setattr(&root(), 2, None, None, Some(2000), Some(2000), None, None, None).unwrap();
+        assert_eq!((attr.uid(), attr.gid()), (2000, 2000));
+    }
+
+    #[test]
+    fn sticky_directory_protects_other_users_files() {
+        let usecase = usecase_with("");
+        let dir = usecase.mkdir(&root(), 1, OsStr::new("tmp"), 0o1777).unwrap();
+        usecase.create(&owner(), dir.ino(), OsStr::new("mine"), 0o644, 0).unwrap();
+
+        assert!(matches!(error_of(usecase.unlink(&other(), dir.ino(), OsStr::new("mine"))), entity::Error::NotPermitted));
+        assert!(matches!(
+            error_of(usecase.rename(&other(), dir.ino(), OsStr::new("mine"), dir.ino(), OsStr::new("theirs"))),
+            entity::Error::NotPermitted
+        ));
+        usecase.unlink(&owner(), dir.ino(), OsStr::new("mine")).unwrap();
+    }
+
+    #[test]
+    fn access_checks_requested_mask() {
+        let usecase = usecase_with("");
+
+        assert!(usecase.access(&other(), 2, credentials::R_OK).is_ok());
+        assert!(matches!(error_of(usecase.access(&other(), 2, credentials::W_OK)), entity::Error::PermissionDenied));
+        assert!(matches!(error_of(usecase.access(&root(), 2, credentials::X_OK)), entity::Error::PermissionDenied));
+        assert!(usecase.access(&other(), 2, 0).is_ok());
+        assert!(matches!(error_of(usecase.access(&other(), 100, 0)), entity::Error::NotFound));
+    }
+
+    #[test]
+    fn new_inodes_take_caller_ids_and_mode() {
+        let usecase = usecase_with("");
+        let cred = other();
+        usecase.setattr(&root(), 1, None, Some(0o777), None, None, None, None, None).unwrap();
+
+        let (file, _) = usecase.create(&cred, 1, OsStr::new("file2"), 0o100640, 0).unwrap();
+        assert_eq!((file.uid(), file.gid(), file.perm()), (2000, 2000, 0o640));
+
+        let link = usecase.symlink(&cred, 1, OsStr::new("link"), Path::new("file2")).unwrap();
+        assert_eq!((link.uid(), link.gid(), link.perm()), (2000, 2000, 0o777));
+    }
+
+    #[test]
+    fn directories_inherit_sgid_and_sticky_bits() {
+        let usecase = usecase_with("");
+        let shared = usecase.mkdir(&root(), 1, OsStr::new("shared"), 0o3777).unwrap();
+        assert_eq!((shared.gid(), shared.perm()), (0, 0o3777));
+
+        let dir = usecase.mkdir(&other(), shared.ino(), OsStr::new("dir"), 0o755).unwrap();
+        assert_eq!((dir.uid(), dir.gid(), dir.perm()), (2000, 0, 0o3755));
+
+        let (file, _) = usecase.create(&other(), shared.ino(), OsStr::new("file"), 0o2644, 0).unwrap();
+        assert_eq!((file.gid(), file.perm()), (0, 0o644));
+    }
+
+    #[test]
+    fn setxattr_honors_create_and_replace_flags() {
+        let usecase = usecase_with("");
+        let name = OsStr::new("user.comment");
+
+        assert!(matches!(error_of(usecase.setxattr(&owner(), 2, name, b"a", xattr::XATTR_REPLACE)), entity::Error::NoXattr));
+        usecase.setxattr(&owner(), 2, name, b"a", xattr::XATTR_CREATE).unwrap();
+        assert!(matches!(error_of(usecase.setxattr(&owner(), 2, name, b"b", xattr::XATTR_CREATE)), entity::Error::Exists));
+        usecase.setxattr(&owner(), 2, name, b"b", xattr::XATTR_REPLACE).unwrap();
+        assert!(matches!(
+            error_of(usecase.setxattr(&owner(), 2, name, b"c", xattr::XATTR_CREATE | xattr::XATTR_REPLACE)),
+            entity::Error::InvalidArgument
+        ));
+
+        assert_eq!(usecase.getxattr(&other(), 2, name).unwrap(), b"b");
+        assert_eq!(usecase.listxattr(&other(), 2).unwrap(), b"user.comment\0");
+        usecase.removexattr(&owner(), 2, name).unwrap();
+        assert!(matches!(error_of(usecase.getxattr(&owner(), 2, name)), entity::Error::NoXattr));
+        assert!(matches!(error_of(usecase.removexattr(&owner(), 2, name)), entity::Error::NoXattr));
+    }
+
+    #[test]
+    fn xattr_namespaces_restrict_access() {
+        let usecase = usecase_with("");
+
+        assert!(matches!(error_of(usecase.setxattr(&other(), 2, OsStr::new("user.a"), b"x", 0)), entity::Error::PermissionDenied));
+        assert!(matches!(error_of(usecase.setxattr(&owner(), 2, OsStr::new("trusted.a"), b"x", 0)), entity::Error::NotPermitted));
+        assert!(matches!(error_of(usecase.setxattr(&owner(), 2, OsStr::new("security.a"), b"x", 0)), entity::Error::NotPermitted));
+        assert!(matches!(error_of(usecase.setxattr(&root(), 2, OsStr::new("system.posix_acl_access"), b"x", 0)), entity::Error::NotSupported));
+        assert!(matches!(error_of(usecase.setxattr(&root(), 2, OsStr::new("user.a"), &[0; xattr::XATTR_SIZE_MAX + 1], 0)), entity::Error::XattrTooBig));
+
+        usecase.setxattr(&root(), 2, OsStr::new("trusted.a"), b"x", 0).unwrap();
+        usecase.setxattr(&root(), 2, OsStr::new("security.a"), b"y", 0).unwrap();
+        assert!(matches!(error_of(usecase.getxattr(&owner(), 2, OsStr::new("trusted.a"))), entity::Error::NoXattr));
+        assert_eq!(usecase.getxattr(&owner(), 2, OsStr::new("security.a")).unwrap(), b"y");
+        assert_eq!(usecase.listxattr(&owner(), 2).unwrap(), b"security.a\0");
+        assert_eq!(usecase.listxattr(&root(), 2).unwrap(), b"security.a\0trusted.a\0");
+
+        let link = usecase.symlink(&root(), 1, OsStr::new("link"), Path::new("file1")).unwrap();
+        assert!(matches!(error_of(usecase.setxattr(&root(), link.ino(), OsStr::new("user.a"), b"x", 0)), entity::Error::NotPermitted));
+    }
+
+    #[test]
+    fn xattrs_are_dropped_with_inode() {
+        let usecase = usecase_with("");
+        usecase.setxattr(&owner(), 2, OsStr::new("user.a"), b"x", 0).unwrap();
+
+        usecase.unlink(&owner(), 1, OsStr::new("file1")).unwrap();
+        let (file, _) = usecase.create(&owner(), 1, OsStr::new("file1"), 0o644, 0).unwrap();
+        assert_eq!(usecase.listxattr(&owner(), file.ino()).unwrap(), b"");
+        assert!(matches!(error_of(usecase.listxattr(&owner(), 2)), entity::Error::NotFound));
+    }
+
+    #[test]
+    fn statfs_reports_usage_against_limits() {
+        let mut usecase = new(MockFile::new(vec![("file1", &"a".repeat(5000)), ("file2", "b")]).with_limits(limit::Limits {
+            capacity: Some(10 * limit::BLOCK_SIZE),
+            max_inodes: Some(8),
+            ..Default::default()
+        }));
+        usecase.init(Path::new("image.yaml")).unwrap();
+
+        let st = usecase.statfs().unwrap();
+        assert_eq!((st.blocks, st.bfree, st.bavail), (10, 7, 7));
+        assert_eq!((st.files, st.ffree), (8, 5));
+        assert_eq!(st.namelen, 255);
+    }
+
+    #[test]
+    fn statfs_without_capacity_uses_host_space() {
+        let usecase = usecase_with("0123");
+
+        let st = usecase.statfs().unwrap();
+        assert_eq!(st.bfree, mock::AVAILABLE / limit::BLOCK_SIZE);
+        assert_eq!(st.blocks, st.bfree + 1);
+    }
+
+    fn usecase_with_limits(data: &str, limits: limit::Limits) -> impl Usecase {
+        let mut usecase = new(MockFile::new(vec![("file1", data)]).with_limits(limits));
+        usecase.init(Path::new("image.yaml")).unwrap();
+        usecase
+    }
+
+    #[test]
+    fn capacity_and_file_size_limit_growth() {
+        let usecase = usecase_with_limits("0123", limit::Limits {
+            capacity: Some(9),
+            max_inodes: Some(3),
+            max_file_size: Some(8),
+            ..Default::default()
+        });
+
+        assert_eq!(usecase.write(&owner(), 2, None, 4, b"4567").unwrap(), 4);
+        assert!(matches!(error_of(usecase.write(&owner(), 2, None, 8, b"8")), entity::Error::FileTooBig));
+        assert!(matches!(error_of(usecase.setattr(&owner(), 2, None, None, None, None, Some(9), None, None)), entity::Error::FileTooBig));
+        // 上書きと切り詰めは使用量を増やさない
+        assert_eq!(usecase.write(&owner(), 2, None, 0, b"abcd").unwrap(), 4);
+        usecase.setattr(&owner(), 2, None, None, None, None, Some(2), None, None).unwrap();
+
+        usecase.create(&owner(), 1, OsStr::new("file2"), 0o644, 0).unwrap();
+        assert!(matches!(error_of(usecase.mkdir(&owner(), 1, OsStr::new("dir"), 0o755)), entity::Error::NoSpace));
+        let file2 = usecase.lookup(&owner(), 1, OsStr::new("file2")).unwrap().ino();
+        assert!(matches!(error_of(usecase.write(&owner(), file2, None, 0, b"01234567")), entity::Error::NoSpace));
+        assert_eq!(usecase.write(&owner(), file2, None, 0, b"0123456").unwrap(), 7);
+
+        let (total, per_uid) = usecase.usage().unwrap();
+        assert_eq!(total, limit::Usage { bytes: 9, inodes: 3 });
+        assert_eq!(per_uid[&1000], limit::Usage { bytes: 9, inodes: 3 });
+    }
+
+    #[test]
+    fn file_size_is_capped_without_limits() {
+        let usecase = usecase_with("0123");
+
+        assert!(matches!(error_of(usecase.write(&root(), 2, None, u64::MAX, b"x")), entity::Error::FileTooBig));
+        assert!(matches!(error_of(usecase.write(&root(), 2, None, limit::DEFAULT_MAX_FILE_SIZE, b"x")), entity::Error::FileTooBig));
+        assert!(matches!(error_of(usecase.setattr(&root(), 2, None, None, None, None, Some(u64::MAX), None, None)), entity::Error::FileTooBig));
+        assert_eq!(usecase.read(&root(), 2, None, 0, 4096).unwrap(), b"0123");
+    }
+
+    #[test]
+    fn quotas_apply_to_file_owner() {
+        let mut quotas = HashMap::new();
+        quotas.insert(2000, limit::Quota { bytes: Some(3), inodes: Some(2) });
+        let usecase = usecase_with_limits("", limit::Limits { quotas: quotas, ..Default::default() });
+        usecase.setattr(&root(), 1, None, Some(0o777), None, None, None, None, None).unwrap();
+
+        let (file, _) = usecase.create(&other(), 1, OsStr::new("a"), 0o666, 0).unwrap();
+        usecase.mkdir(&other(), 1, OsStr::new("b"), 0o777).unwrap();
+        assert!(matches!(error_of(usecase.create(&other(), 1, OsStr::new("c"), 0o666, 0)), entity::Error::QuotaExceeded));
+        assert!(matches!(error_of(usecase.symlink(&other(), 1, OsStr::new("c"), Path::new("a"))), entity::Error::QuotaExceeded));
+
+        // 他のユーザが書き込んでも所有者の使用量として数える
+        assert!(matches!(error_of(usecase.write(&owner(), file.ino(), None, 0, b"abcd")), entity::Error::QuotaExceeded));
+        assert_eq!(usecase.write(&owner(), file.ino(), None, 0, b"abc").unwrap(), 3);
+        usecase.create(&owner(), 1, OsStr::new("c"), 0o644, 0).unwrap();
+    }
+
+    #[test]
+    fn usage_follows_chown_and_unlink() {
+        let usecase = usecase_with("0123");
+        let before = usecase.usage().unwrap();
+
+        usecase.setattr(&root(), 2, None, None, Some(2000), None, None, None, None).unwrap();
+        let (total, per_uid) = usecase.usage().unwrap();
+        assert_eq!(total, before.0);
+        assert_eq!(per_uid[&2000], limit::Usage { bytes: 4, inodes: 1 });
+
+        // 削除したinodeの所有者は使用量の一覧からも消える
+        usecase.unlink(&root(), 1, OsStr::new("file1")).unwrap();
+        let (total, per_uid) = usecase.usage().unwrap();
+        assert_eq!(total, limit::Usage { bytes: before.0.bytes - 4, inodes: before.0.inodes - 1 });
+        assert!(!per_uid.contains_key(&2000));
+    }
+
+    #[test]
+    fn parallel_readers_and_writers_keep_state_consistent() {
+        let usecase = Arc::new(usecase_with("0123456789"));
+        let mut threads = Vec::new();
+
+        for i in 0..4u8 {
+            let usecase = Arc::clone(&usecase);
+            threads.push(thread::spawn(move || {
+                let (file, _) = usecase.create(&root(), 1, OsStr::new(&format!("w{}", i)), 0o644, 0).unwrap();
+                for offset in 0..100 {
+                    usecase.write(&root(), file.ino(), None, offset, &[b'a' + i]).unwrap();
+
+                    // 作成と削除を他のスレッドの読み書きと交互に行う
+                    let name = format!("tmp{}", i);
+                    let (tmp, fh) = usecase.create(&root(), 1, OsStr::new(&name), 0o644, 0).unwrap();
+                    usecase.unlink(&root(), 1, OsStr::new(&name)).unwrap();
+                    usecase.forget(tmp.ino(), 1).unwrap();
+                    usecase.release(fh).unwrap();
+                }
+            }));
+        }
+        for _ in 0..4 {
+            let usecase = Arc::clone(&usecase);
+            threads.push(thread::spawn(move || {
+                for _ in 0..100 {
+                    assert_eq!(usecase.read(&root(), 2, None, 0, 4096).unwrap(), b"0123456789");
+                    assert!(usecase.readdir(&root(), 1).unwrap().len() >= 1);
+                    usecase.statfs().unwrap();
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        for i in 0..4u8 {
+            let ino = usecase.lookup(&root(), 1, OsStr::new(&format!("w{}", i))).unwrap().ino();
+            assert_eq!(usecase.read(&root(), ino, None, 0, 4096).unwrap(), vec![b'a' + i; 100]);
+        }
+        assert_eq!(usecase.attr_from_ino(1).unwrap().size(), 5);
+        assert_eq!(usecase.readdir(&root(), 1).unwrap().len(), 5);
+        assert_eq!(usecase.usage().unwrap().0, limit::Usage { bytes: 410, inodes: 6 });
+    }
+}
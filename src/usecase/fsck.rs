@@ -0,0 +1,443 @@
+use std::fmt;
+use std::collections::{HashMap, HashSet};
+use crate::entity::{attr, data, entry, xattr};
+
+// fsckで見つかった不整合
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    // ino 1がないか、ディレクトリではない
+    MissingRoot,
+    // attrのないinoを指すエントリ
+    DanglingChild { parent: u64, name: String, child_ino: u64 },
+    // 同じディレクトリ内の重複した名前
+    DuplicateName { parent: u64, name: String },
+    // 空、"."、".."、"/"を含むなど、エントリに使えない名前
+    InvalidName { parent: u64, name: String },
+    // 既にたどったディレクトリを再び指すエントリ
+    Cycle { parent: u64, name: String, child_ino: u64 },
+    // ino 1からたどれないinode
+    Orphan { ino: u64 },
+    // ディレクトリではない、またはattrのないinoのentry
+    StrayEntry { ino: u64 },
+    // entryのないディレクトリ
+    MissingEntry { ino: u64 },
+    // attrのないinoのdata
+    StrayData { ino: u64 },
+    // attrのないinoの拡張属性
+    StrayXattr { ino: u64 },
+    // ディレクトリのdata
+    DirectoryData { ino: u64 },
+    // dataのないファイル
+    MissingData { ino: u64 },
+    SizeMismatch { ino: u64, recorded: u64, actual: u64 },
+    NlinkMismatch { ino: u64, recorded: u32, actual: u32 }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingRoot => write!(f, "root directory (ino {}) is missing", ROOT_INO),
+            Self::DanglingChild { parent, name, child_ino } => write!(f, "ino {}: entry {:?} points to missing ino {}", parent, name, child_ino),
+            Self::DuplicateName { parent, name } => write!(f, "ino {}: duplicate entry {:?}", parent, name),
+            Self::InvalidName { parent, name } => write!(f, "ino {}: invalid entry name {:?}", parent, name),
+            Self::Cycle { parent, name, child_ino } => write!(f, "ino {}: entry {:?} links directory ino {} more than once", parent, name, child_ino),
+            Self::Orphan { ino } => write!(f, "ino {}: unreachable from the root", ino),
+            Self::StrayEntry { ino } => write!(f, "ino {}: entry record for a non-directory", ino),
+            Self::MissingEntry { ino } => write!(f, "ino {}: directory has no entry record", ino),
+            Self::StrayData { ino } => write!(f, "ino {}: data record without attr", ino),
+            Self::StrayXattr { ino } => write!(f, "ino {}: xattr record without attr", ino),
+            Self::DirectoryData { ino } => write!(f, "ino {}: data record for a directory", ino),
+            Self::MissingData { ino } => write!(f, "ino {}: file has no data record", ino),
+            Self::SizeMismatch { ino, recorded, actual } => write!(f, "ino {}: size is {} but should be {}", ino, recorded, actual),
+            Self::NlinkMismatch { ino, recorded, actual } => write!(f, "ino {}: nlink is {} but should be {}", ino, recorded, actual)
+        }
+    }
+}
+
+const ROOT_INO: u64 = 1;
+// ルートからたどれないinodeをつなぎ直すディレクトリ
+const LOST_FOUND: &str = "lost+found";
+
+// イメージの不整合を調べ、repairがtrueなら見つけた順に修復する
+// 後の検査は修復後の状態に対して行う
+// new_inoはlost+foundを作る場合だけ呼ぶ
+pub fn check<N: FnMut() -> u64>(
+    attrs: &mut attr::AttrsStruct,
+    entries: &mut entry::EntriesStruct,
+    all_data: &mut data::AllDataStruct,
+    xattrs: &mut xattr::XattrsStruct,
+    repair: bool,
+    mut new_ino: N
+) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    // ルートディレクトリ
+    let root_is_dir = match attrs.attr(ROOT_INO) {
+        Some(attr) => is_dir(attr),
+        None => false
+    };
+    if !root_is_dir {
+        problems.push(Problem::MissingRoot);
+        if repair {
+            let st = attr::SystemTime::now();
+            attrs.update_attr(attr::Attr::new(ROOT_INO, 0, attr::FileType::Directory, 0o755, 0, 0, st, st, st, 2));
+            all_data.del(ROOT_INO).ok();
+        }
+    }
+
+    // entryとattrの対応
+    for ino in sorted(entries.entries().keys()) {
+        let stray = match attrs.attr(ino) {
+            Some(attr) => !is_dir(attr),
+            None => true
+        };
+        if stray {
+            problems.push(Problem::StrayEntry { ino });
+            if repair {
+                entries.del(ino);
+            }
+        }
+    }
+    for ino in sorted(attrs.attrs().keys()) {
+        if is_dir(attrs.attr(ino).unwrap()) && entries.entry(ino).is_none() {
+            problems.push(Problem::MissingEntry { ino });
+            if repair {
+                entries.insert_entry(ino);
+            }
+        }
+    }
+
+    // dataとattrの対応
+    for ino in sorted(all_data.data().keys()) {
+        let problem = match attrs.attr(ino) {
+            Some(attr) if is_dir(attr) => Problem::DirectoryData { ino },
+            Some(_) => continue,
+            None => Problem::StrayData { ino }
+        };
+        problems.push(problem);
+        if repair {
+            all_data.del(ino).ok();
+        }
+    }
+    for ino in sorted(attrs.attrs().keys()) {
+        if !is_dir(attrs.attr(ino).unwrap()) && all_data.all_data(ino).is_none() {
+            problems.push(Problem::MissingData { ino });
+            if repair {
+                all_data.update_data(ino, data::Data::new(ino, Vec::new())).ok();
+            }
+        }
+    }
+
+    // 拡張属性とattrの対応
+    for ino in sorted(xattrs.xattrs().keys()) {
+        if attrs.attr(ino).is_none() {
+            problems.push(Problem::StrayXattr { ino });
+            if repair {
+                xattrs.del(ino);
+            }
+        }
+    }
+
+    // ルートからたどり、残すエントリを決める
+    let mut kept: HashMap<u64, Vec<entry::Entry>> = HashMap::new();
+    let mut reachable = HashSet::new();
+    reachable.insert(ROOT_INO);
+    walk(attrs, entries, vec![ROOT_INO], repair, &mut kept, &mut reachable, &mut problems);
+
+    // ルートからたどれないinode
+    // 修復する場合は削除せず、lost+foundに"#ino"の名前でつなぎ直す
+    let orphans: Vec<u64> = sorted(attrs.attrs().keys()).into_iter().filter(|ino| !reachable.contains(ino)).collect();
+    for ino in orphans.iter() {
+        problems.push(Problem::Orphan { ino: *ino });
+    }
+    if repair && !orphans.is_empty() {
+        let lost_found = lost_found(attrs, entries, &mut kept, &mut reachable, &mut new_ino);
+
+        loop {
+            let remaining: Vec<u64> = sorted(attrs.attrs().keys()).into_iter().filter(|ino| !reachable.contains(ino)).collect();
+            if remaining.is_empty() {
+                break;
+            }
+            // たどれないディレクトリから指されているinodeは、そのディレクトリごとつなぎ直す
+            // 互いに指し合っているだけの場合は、最も小さいinoからつなぎ直す
+            let referenced: HashSet<u64> = remaining.iter()
+                .filter_map(|ino| entries.entry(*ino))
+                .flat_map(|children| children.iter().map(|child| child.child_ino()))
+                .collect();
+            let mut tops: Vec<u64> = remaining.iter().cloned().filter(|ino| !referenced.contains(ino)).collect();
+            if tops.is_empty() {
+                tops.push(remaining[0]);
+            }
+
+            let mut stack = Vec::new();
+            for ino in tops {
+                let child = entry::Entry::new(ino, format!("#{}", ino));
+                entries.insert_child_ino(lost_found, ino, child.name());
+                kept.entry(lost_found).or_default().push(child);
+                attrs.inc_size(lost_found).ok();
+                reachable.insert(ino);
+                if is_dir(attrs.attr(ino).unwrap()) {
+                    attrs.inc_nlink(lost_found).ok();
+                    stack.push(ino);
+                }
+            }
+            walk(attrs, entries, stack, repair, &mut kept, &mut reachable, &mut problems);
+        }
+    }
+
+    // sizeとnlink
+    let mut nlinks: HashMap<u64, u32> = HashMap::new();
+    for (dir, children) in kept.iter() {
+        for child in children.iter() {
+            match attrs.attr(child.child_ino()) {
+                Some(attr) if is_dir(attr) => *nlinks.entry(*dir).or_insert(0) += 1,
+                _ => *nlinks.entry(child.child_ino()).or_insert(0) += 1
+            }
+        }
+    }
+
+    for ino in sorted(reachable.iter()) {
+        let attr = match attrs.attr(ino) {
+            Some(attr) => attr,
+            None => continue
+        };
+        let (size, nlink) = if is_dir(attr) {
+            let size = match kept.get(&ino) {
+                Some(children) => children.len() as u64,
+                None => 0
+            };
+            (Some(size), 2 + nlinks.get(&ino).cloned().unwrap_or(0))
+        } else {
+            let size = all_data.all_data(ino).map(|data| data.data().len() as u64);
+            (size, nlinks.get(&ino).cloned().unwrap_or(0))
+        };
+        let (recorded_size, recorded_nlink) = (attr.size(), attr.nlink());
+
+        if let Some(size) = size {
+            if size != recorded_size {
+                problems.push(Problem::SizeMismatch { ino, recorded: recorded_size, actual: size });
+                if repair {
+                    attrs.update_size(ino, size).ok();
+                }
+            }
+        }
+        if nlink != recorded_nlink {
+            problems.push(Problem::NlinkMismatch { ino, recorded: recorded_nlink, actual: nlink });
+            if repair {
+                attrs.update_nlink(ino, nlink).ok();
+            }
+        }
+    }
+
+    problems
+}
+
+// stackのディレクトリからたどり、たどれたエントリをkeptに、inodeをreachableに加える
+// 存在しないinoや使えない名前、重複した名前、既にたどったディレクトリを指すエントリは残さない
+fn walk(
+    attrs: &attr::AttrsStruct,
+    entries: &mut entry::EntriesStruct,
+    mut stack: Vec<u64>,
+    repair: bool,
+    kept: &mut HashMap<u64, Vec<entry::Entry>>,
+    reachable: &mut HashSet<u64>,
+    problems: &mut Vec<Problem>
+) {
+    while let Some(dir) = stack.pop() {
+        let children = match entries.entry(dir) {
+            Some(children) => children.clone(),
+            None => Vec::new()
+        };
+        let mut names = HashSet::new();
+        let mut dir_kept = Vec::new();
+
+        for child in children {
+            let child_ino = child.child_ino();
+            let child_attr = match attrs.attr(child_ino) {
+                Some(child_attr) => child_attr,
+                None => {
+                    problems.push(Problem::DanglingChild { parent: dir, name: child.name().to_string(), child_ino });
+                    continue;
+                }
+            };
+            if !entry::valid_name(child.name()) {
+                problems.push(Problem::InvalidName { parent: dir, name: child.name().to_string() });
+                continue;
+            }
+            if !names.insert(child.name().to_string()) {
+                problems.push(Problem::DuplicateName { parent: dir, name: child.name().to_string() });
+                continue;
+            }
+            if is_dir(child_attr) {
+                // ディレクトリは1つの親からしか指されない
+                if !reachable.insert(child_ino) {
+                    problems.push(Problem::Cycle { parent: dir, name: child.name().to_string(), child_ino });
+                    continue;
+                }
+                stack.push(child_ino);
+            } else {
+                reachable.insert(child_ino);
+            }
+            dir_kept.push(child);
+        }
+
+        if repair && entries.entry(dir).map(|children| children.len()) != Some(dir_kept.len()) {
+            entries.update_entry(dir, dir_kept.clone());
+        }
+        kept.insert(dir, dir_kept);
+    }
+}
+
+// ルート直下のlost+foundを返し、なければ作る
+// 同じ名前のディレクトリ以外があれば、"lost+found.<ino>"の名前で作る
+fn lost_found<N: FnMut() -> u64>(
+    attrs: &mut attr::AttrsStruct,
+    entries: &mut entry::EntriesStruct,
+    kept: &mut HashMap<u64, Vec<entry::Entry>>,
+    reachable: &mut HashSet<u64>,
+    new_ino: &mut N
+) -> u64 {
+    let existing = kept.get(&ROOT_INO)
+        .and_then(|children| children.iter().find(|child| child.name() == LOST_FOUND))
+        .map(|child| child.child_ino());
+    let name = match existing {
+        Some(ino) if is_dir(attrs.attr(ino).unwrap()) => return ino,
+        Some(_) => None,
+        None => Some(LOST_FOUND.to_string())
+    };
+
+    let ino = new_ino();
+    let name = name.unwrap_or_else(|| format!("{}.{}", LOST_FOUND, ino));
+    let st = attr::SystemTime::now();
+    attrs.update_attr(attr::Attr::new(ino, 0, attr::FileType::Directory, 0o700, 0, 0, st, st, st, 2));
+    entries.insert_entry(ino);
+    entries.insert_child_ino(ROOT_INO, ino, &name);
+    kept.entry(ROOT_INO).or_default().push(entry::Entry::new(ino, name));
+    kept.insert(ino, Vec::new());
+    attrs.inc_size(ROOT_INO).ok();
+    attrs.inc_nlink(ROOT_INO).ok();
+    reachable.insert(ino);
+
+    ino
+}
+
+fn is_dir(attr: &attr::Attr) -> bool {
+    match attr.kind() {
+        attr::FileType::Directory => true,
+        _ => false
+    }
+}
+
+fn sorted<'a, I: Iterator<Item = &'a u64>>(inos: I) -> Vec<u64> {
+    let mut inos: Vec<u64> = inos.cloned().collect();
+    inos.sort();
+    inos
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attr(ino: u64, kind: attr::FileType, size: u64, nlink: u32) -> (u64, attr::Attr) {
+        let st = attr::SystemTime::new(0, 0);
+        (ino, attr::Attr::new(ino, size, kind, 0o755, 0, 0, st, st, st, nlink))
+    }
+
+    fn child(ino: u64, name: &str) -> entry::Entry {
+        entry::Entry::new(ino, name.to_string())
+    }
+
+    fn no_xattrs() -> xattr::XattrsStruct {
+        xattr::XattrsStruct::new(HashMap::new())
+    }
+
+    #[test]
+    fn check_reports_and_repairs_problems() {
+        let mut attrs = attr::AttrsStruct::new(vec![
+            attr(1, attr::FileType::Directory, 4, 3),
+            attr(2, attr::FileType::TextFile, 3, 1),
+            attr(3, attr::FileType::Directory, 1, 2),
+            attr(4, attr::FileType::TextFile, 0, 1),
+        ].into_iter().collect());
+        let mut entries = entry::EntriesStruct::new(vec![
+            (1, vec![child(2, "file1"), child(9, "dangling"), child(2, "file1"), child(3, "dir"), child(3, "again")]),
+            (3, vec![child(1, "loop")]),
+            (2, vec![]),
+        ].into_iter().collect());
+        let mut all_data = data::AllDataStruct::new(vec![
+            (2, data::Data::new(2, b"abc".to_vec())),
+            (3, data::Data::new(3, Vec::new())),
+        ].into_iter().collect());
+
+        let mut xattrs = no_xattrs();
+        let problems = check(&mut attrs, &mut entries, &mut all_data, &mut xattrs, false, || unreachable!());
+
+        assert!(problems.contains(&Problem::StrayEntry { ino: 2 }));
+        assert!(problems.contains(&Problem::DirectoryData { ino: 3 }));
+        assert!(problems.contains(&Problem::MissingData { ino: 4 }));
+        assert!(problems.contains(&Problem::DanglingChild { parent: 1, name: "dangling".to_string(), child_ino: 9 }));
+        assert!(problems.contains(&Problem::DuplicateName { parent: 1, name: "file1".to_string() }));
+        assert!(problems.contains(&Problem::Cycle { parent: 1, name: "again".to_string(), child_ino: 3 }));
+        assert!(problems.contains(&Problem::Cycle { parent: 3, name: "loop".to_string(), child_ino: 1 }));
+        assert!(problems.contains(&Problem::Orphan { ino: 4 }));
+        assert!(problems.contains(&Problem::SizeMismatch { ino: 1, recorded: 4, actual: 2 }));
+        assert!(problems.contains(&Problem::SizeMismatch { ino: 3, recorded: 1, actual: 0 }));
+        // 検査だけでは何も変更しない
+        assert_eq!(entries.entry(1).unwrap().len(), 5);
+
+        check(&mut attrs, &mut entries, &mut all_data, &mut xattrs, true, || 10);
+
+        assert_eq!(check(&mut attrs, &mut entries, &mut all_data, &mut xattrs, false, || unreachable!()), Vec::new());
+        let names: Vec<&str> = entries.entry(1).unwrap().iter().map(|entry| entry.name()).collect();
+        assert_eq!(names, vec!["file1", "dir", "lost+found"]);
+        // たどれないinodeは削除せず、lost+foundにつなぎ直す
+        assert_eq!(entries.child(10, "#4").map(|entry| entry.child_ino()), Some(4));
+        assert_eq!(all_data.all_data(4).map(|data| data.data().len()), Some(0));
+        assert!(all_data.all_data(3).is_none());
+        assert_eq!(attrs.attr(1).unwrap().size(), 3);
+        assert_eq!(attrs.attr(1).unwrap().nlink(), 4);
+        assert_eq!(attrs.attr(10).unwrap().perm(), 0o700);
+    }
+
+    #[test]
+    fn repair_reattaches_orphaned_trees_and_drops_invalid_names_and_stray_xattrs() {
+        let mut attrs = attr::AttrsStruct::new(vec![
+            attr(1, attr::FileType::Directory, 2, 3),
+            attr(2, attr::FileType::Directory, 0, 2),
+            attr(3, attr::FileType::Directory, 1, 2),
+            attr(4, attr::FileType::TextFile, 1, 1),
+            attr(5, attr::FileType::TextFile, 1, 1),
+        ].into_iter().collect());
+        let mut entries = entry::EntriesStruct::new(vec![
+            (1, vec![child(2, "lost+found"), child(5, "../escape")]),
+            (2, vec![]),
+            (3, vec![child(4, "file")]),
+        ].into_iter().collect());
+        let mut all_data = data::AllDataStruct::new(vec![
+            (4, data::Data::new(4, b"x".to_vec())),
+            (5, data::Data::new(5, b"y".to_vec())),
+        ].into_iter().collect());
+        let mut xattrs = no_xattrs();
+        xattrs.set(4, "user.kept", b"1");
+        xattrs.set(9, "user.stray", b"1");
+
+        let problems = check(&mut attrs, &mut entries, &mut all_data, &mut xattrs, false, || unreachable!());
+        assert!(problems.contains(&Problem::InvalidName { parent: 1, name: "../escape".to_string() }));
+        assert!(problems.contains(&Problem::StrayXattr { ino: 9 }));
+        assert!(problems.contains(&Problem::Orphan { ino: 3 }));
+        assert!(problems.contains(&Problem::Orphan { ino: 4 }));
+        assert!(problems.contains(&Problem::Orphan { ino: 5 }));
+
+        // 既にあるlost+foundを使い、たどれないディレクトリは中身ごとつなぎ直す
+        check(&mut attrs, &mut entries, &mut all_data, &mut xattrs, true, || unreachable!());
+
+        assert_eq!(check(&mut attrs, &mut entries, &mut all_data, &mut xattrs, false, || unreachable!()), Vec::new());
+        let names: Vec<&str> = entries.entry(1).unwrap().iter().map(|entry| entry.name()).collect();
+        assert_eq!(names, vec!["lost+found"]);
+        let names: Vec<&str> = entries.entry(2).unwrap().iter().map(|entry| entry.name()).collect();
+        assert_eq!(names, vec!["#3", "#5"]);
+        assert_eq!(entries.child(3, "file").map(|entry| entry.child_ino()), Some(4));
+        assert!(xattrs.xattr(4).is_some());
+        assert!(xattrs.xattr(9).is_none());
+    }
+}
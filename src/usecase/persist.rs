@@ -0,0 +1,521 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+use anyhow::Result;
+use crate::entity::{self, attr, data, entry, mount, xattr};
+use super::repository;
+
+// 書き込みスレッドが追いつかない場合に積んでおける変更の数
+// 満杯になると、変更を積むリクエストは空きができるまで待たされる
+const QUEUE_SIZE: usize = 1024;
+
+// イメージへの書き込みを専用のスレッドで行う
+// リクエストを処理するスレッドは変更をキューに積むだけで、YAMLへの追記を待たない
+// 書き込みスレッドは変更をinodeごとにまとめ、write_behindの条件を満たしたら追記する
+#[derive(Debug)]
+pub struct Persister<F: repository::File> {
+    repository: Arc<Mutex<F>>,
+    write_behind: mount::WriteBehind,
+    // --daemonでforkする前にスレッドを作らないよう、最初の変更を積むときに起動する
+    worker: OnceLock<Worker>,
+    state: Arc<State>
+}
+
+#[derive(Debug)]
+struct Worker {
+    sender: mpsc::SyncSender<Change>,
+    thread: thread::JoinHandle<()>
+}
+
+// 書き込みスレッドに渡す変更
+#[derive(Debug)]
+enum Change {
+    Attr(attr::Attr),
+    DelAttr(u64),
+    Data(u64, Vec<u8>),
+    DelData(u64),
+    Entry(u64, Vec<entry::Entry>),
+    Xattr(u64, xattr::Xattrs),
+    // まとめている変更をすぐに書き込み、その間に起きたエラーを返す
+    // trueならファイルをディスクに同期する
+    Flush(bool, mpsc::Sender<Result<()>>)
+}
+
+// まだ書き込んでいない変更
+// 同じinodeへの変更は最後のものだけを残す(Noneは削除)
+#[derive(Debug, Default)]
+struct Batch {
+    attrs: BTreeMap<u64, Option<attr::Attr>>,
+    data: BTreeMap<u64, Option<Vec<u8>>>,
+    entries: BTreeMap<u64, Vec<entry::Entry>>,
+    xattrs: BTreeMap<u64, xattr::Xattrs>
+}
+
+// リクエストを処理するスレッドと書き込みスレッドで共有する状態
+#[derive(Debug, Default)]
+struct State {
+    // 生存しているレコードの数
+    live: AtomicU64,
+    // ログが大きくなりすぎてコンパクションが必要になった
+    needs_compaction: AtomicBool,
+    // 書き込みに失敗した場合、次のflushまで最初のエラーを残しておく
+    error: Mutex<Option<anyhow::Error>>
+}
+
+impl<F: repository::File + 'static> Persister<F> {
+    pub fn new(repository: F, write_behind: mount::WriteBehind) -> Persister<F> {
+        Persister {
+            repository: Arc::new(Mutex::new(repository)),
+            write_behind: write_behind,
+            worker: OnceLock::new(),
+            state: Arc::new(State::default())
+        }
+    }
+
+    // init、limits、availableのようにリポジトリを直接使う場合
+    // 書き込みスレッドが追記している間は待たされる
+    pub fn repository(&self) -> Result<MutexGuard<'_, F>> {
+        match self.repository.lock() {
+            Ok(repository) => Ok(repository),
+            Err(_) => Err(entity::Error::InternalError.into())
+        }
+    }
+
+    pub fn update_attr(&self, attr: &attr::Attr) -> Result<()> {
+        self.send(Change::Attr(attr.clone()))
+    }
+
+    pub fn del_attr(&self, ino: u64) -> Result<()> {
+        self.send(Change::DelAttr(ino))
+    }
+
+    pub fn write_data(&self, ino: u64, data: &[u8]) -> Result<()> {
+        self.send(Change::Data(ino, data.to_vec()))
+    }
+
+    pub fn del_data(&self, ino: u64) -> Result<()> {
+        self.send(Change::DelData(ino))
+    }
+
+    pub fn update_entry(&self, ino: u64, child_inos: &Vec<entry::Entry>) -> Result<()> {
+        self.send(Change::Entry(ino, child_inos.clone()))
+    }
+
+    pub fn update_xattr(&self, ino: u64, xattrs: &xattr::Xattrs) -> Result<()> {
+        self.send(Change::Xattr(ino, xattrs.clone()))
+    }
+
+    // 渡された状態でイメージを書き直し、書き終えるまで待つ
+    // ファイルの内容まで写すとメモリを倍使うため、呼び出し側は状態のロックを持ったまま呼ぶ
+    // ロックを持っている間は変更が積まれないため、まとめている変更を先に書き込めば順序は入れ替わらない
+    pub fn compact(
+        &self,
+        attrs: &attr::AttrsStruct,
+        entries: &entry::EntriesStruct,
+        data: &data::AllDataStruct,
+        xattrs: &xattr::XattrsStruct
+    ) -> Result<()> {
+        // 書き直しが失敗したり途中で止まったりしても、ログだけで同じ状態を表せるようにしておく
+        // それまでの書き込みのエラーは、次のflushで伝えるため残す
+        if let Err(e) = self.flush() {
+            keep_error(&self.state, e);
+        }
+        // 書き直す前に求められていたコンパクションはこれで済む
+        self.state.needs_compaction.store(false, Ordering::Release);
+
+        let repository = self.repository()?;
+        match repository.compact(attrs, entries, data, xattrs) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                eprintln!("Failed to write image: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    // 生存しているレコードの数を伝え、書き込みスレッドがコンパクションを求めていればtrueを返す
+    // 一度trueを返したら、次に求められるまではfalseを返す
+    pub fn needs_compaction(&self, live: u64) -> bool {
+        self.state.live.store(live, Ordering::Relaxed);
+        self.state.needs_compaction.swap(false, Ordering::AcqRel)
+    }
+
+    // キューに積んだ変更を全て書き終えるまで待つ
+    // 前回のflushから書き込みに失敗していればそのエラーを返す
+    pub fn flush(&self) -> Result<()> {
+        self.wait(false)
+    }
+
+    // flushに加えて、書き込んだファイルをディスクに同期するまで待つ
+    pub fn sync(&self) -> Result<()> {
+        self.wait(true)
+    }
+
+    fn wait(&self, sync: bool) -> Result<()> {
+        let (done, wait) = mpsc::channel();
+        self.send(Change::Flush(sync, done))?;
+
+        match wait.recv() {
+            Ok(result) => result,
+            Err(_) => Err(entity::Error::InternalError.into())
+        }
+    }
+
+    // キューが満杯の場合は空きができるまで待つ
+    // 書き込みスレッドは状態のロックを取らないため、ロックを持ったまま待ってもデッドロックしない
+    fn send(&self, change: Change) -> Result<()> {
+        let worker = self.worker.get_or_init(|| {
+            let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+            let repository = Arc::clone(&self.repository);
+            let state = Arc::clone(&self.state);
+            let write_behind = self.write_behind;
+
+            Worker {
+                sender: sender,
+                thread: thread::spawn(move || run(repository, receiver, state, write_behind))
+            }
+        });
+
+        match worker.sender.send(change) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(entity::Error::InternalError.into())
+        }
+    }
+}
+
+// 破棄する前にキューに残っている変更とまとめている変更を書き終える
+impl<F: repository::File> Drop for Persister<F> {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            drop(worker.sender);
+            let _ = worker.thread.join();
+        }
+    }
+}
+
+impl Batch {
+    fn len(&self) -> usize {
+        self.attrs.len() + self.data.len() + self.entries.len() + self.xattrs.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn add(&mut self, change: Change) {
+        match change {
+            Change::Attr(attr) => { self.attrs.insert(attr.ino(), Some(attr)); },
+            Change::DelAttr(ino) => { self.attrs.insert(ino, None); },
+            Change::Data(ino, data) => { self.data.insert(ino, Some(data)); },
+            Change::DelData(ino) => { self.data.insert(ino, None); },
+            Change::Entry(ino, entries) => { self.entries.insert(ino, entries); },
+            Change::Xattr(ino, xattrs) => { self.xattrs.insert(ino, xattrs); },
+            Change::Flush(_, _) => {}
+        }
+    }
+
+    // まとめた変更を追記して空にする
+    // 途中で失敗しても残りは書き込み、最初のエラーを返す
+    // 新しいinodeはエントリより先に、削除したinodeはエントリより後に書き込み、
+    // 途中で止まっても存在しないinodeを指すエントリが残らないようにする
+    fn write<F: repository::File>(&mut self, repository: &F) -> Result<()> {
+        let batch = mem::take(self);
+        let mut results = Vec::with_capacity(batch.len());
+
+        for attr in batch.attrs.values().flatten() {
+            results.push(repository.update_attr(attr));
+        }
+        for (ino, data) in batch.data.iter() {
+            if let Some(data) = data {
+                results.push(repository.write_data(*ino, data));
+            }
+        }
+        for (ino, xattrs) in batch.xattrs.iter() {
+            results.push(repository.update_xattr(*ino, xattrs));
+        }
+        for (ino, entries) in batch.entries.iter() {
+            results.push(repository.update_entry(*ino, entries));
+        }
+        for (ino, data) in batch.data.iter() {
+            if data.is_none() {
+                results.push(repository.del_data(*ino));
+            }
+        }
+        for (ino, attr) in batch.attrs.iter() {
+            if attr.is_none() {
+                results.push(repository.del_attr(*ino));
+            }
+        }
+
+        let mut first = Ok(());
+        for result in results {
+            if let Err(e) = result {
+                eprintln!("Failed to write image: {}", e);
+                if first.is_ok() {
+                    first = Err(e);
+                }
+            }
+        }
+        first
+    }
+}
+
+fn run<F: repository::File>(
+    repository: Arc<Mutex<F>>,
+    receiver: mpsc::Receiver<Change>,
+    state: Arc<State>,
+    write_behind: mount::WriteBehind
+) {
+    let mut batch = Batch::default();
+    // まとめている変更を書き込む期限
+    let mut deadline: Option<Instant> = None;
+
+    loop {
+        let change = match deadline {
+            Some(deadline) => match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(change) => Some(change),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                // Persisterが破棄されたら、まとめている変更を書き込んで終了する
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    write(&repository, &mut batch, &state);
+                    return;
+                }
+            },
+            None => match receiver.recv() {
+                Ok(change) => Some(change),
+                Err(_) => return
+            }
+        };
+
+        match change {
+            Some(Change::Flush(sync, done)) => {
+                write(&repository, &mut batch, &state);
+                deadline = None;
+                if sync {
+                    let result = match repository.lock() {
+                        Ok(repository) => repository.sync(),
+                        Err(_) => return
+                    };
+                    if let Err(e) = result {
+                        eprintln!("Failed to sync image: {}", e);
+                        keep_error(&state, e);
+                    }
+                }
+                let error = match state.error.lock() {
+                    Ok(mut error) => error.take(),
+                    Err(_) => Some(entity::Error::InternalError.into())
+                };
+                let _ = done.send(match error {
+                    Some(e) => Err(e),
+                    None => Ok(())
+                });
+            },
+            Some(change) => {
+                batch.add(change);
+                if batch.len() >= write_behind.batch {
+                    write(&repository, &mut batch, &state);
+                    deadline = None;
+                } else if deadline.is_none() {
+                    deadline = Some(Instant::now() + write_behind.interval);
+                }
+            },
+            // 期限が来た
+            None => {
+                write(&repository, &mut batch, &state);
+                deadline = None;
+            }
+        }
+    }
+}
+
+// まとめている変更を書き込み、ログが大きくなっていればコンパクションを求める
+// 失敗してもメモリ上の状態は正しいため処理は続け、次のflushで伝える
+fn write<F: repository::File>(repository: &Mutex<F>, batch: &mut Batch, state: &State) {
+    if batch.is_empty() {
+        return;
+    }
+    let repository = match repository.lock() {
+        Ok(repository) => repository,
+        Err(_) => return
+    };
+
+    if let Err(e) = batch.write(&*repository) {
+        keep_error(state, e);
+    }
+    if repository.needs_compaction(state.live.load(Ordering::Relaxed)) {
+        state.needs_compaction.store(true, Ordering::Release);
+    }
+}
+
+fn keep_error(state: &State, e: anyhow::Error) {
+    if let Ok(mut error) = state.error.lock() {
+        error.get_or_insert(e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::path;
+    use std::time::Duration;
+    use crate::entity::limit;
+
+    // 書き込まれた変更を順に記録するリポジトリ
+    struct Recorder {
+        log: Arc<Mutex<Vec<String>>>,
+        fail: bool
+    }
+
+    impl repository::File for Recorder {
+        fn init(&mut self, _path: &path::Path) -> Result<(u64, attr::AttrsStruct, entry::EntriesStruct, data::AllDataStruct, xattr::XattrsStruct)> {
+            Err(entity::Error::InternalError.into())
+        }
+        fn write_data(&self, ino: u64, data: &[u8]) -> Result<()> {
+            if self.fail {
+                return Err(entity::Error::NoSpace.into());
+            }
+            self.log.lock().unwrap().push(format!("data {} {}", ino, String::from_utf8_lossy(data)));
+            Ok(())
+        }
+        fn update_attr(&self, attr: &attr::Attr) -> Result<()> {
+            self.log.lock().unwrap().push(format!("attr {} {}", attr.ino(), attr.size()));
+            Ok(())
+        }
+        fn del_attr(&self, ino: u64) -> Result<()> {
+            self.log.lock().unwrap().push(format!("del attr {}", ino));
+            Ok(())
+        }
+        fn del_data(&self, ino: u64) -> Result<()> {
+            self.log.lock().unwrap().push(format!("del data {}", ino));
+            Ok(())
+        }
+        fn update_entry(&self, ino: u64, child_inos: &Vec<entry::Entry>) -> Result<()> {
+            self.log.lock().unwrap().push(format!("entry {} {}", ino, child_inos.len()));
+            Ok(())
+        }
+        fn update_xattr(&self, ino: u64, _xattrs: &xattr::Xattrs) -> Result<()> {
+            self.log.lock().unwrap().push(format!("xattr {}", ino));
+            Ok(())
+        }
+        fn sync(&self) -> Result<()> {
+            self.log.lock().unwrap().push("sync".to_string());
+            Ok(())
+        }
+        fn compact(&self, attrs: &attr::AttrsStruct, _entries: &entry::EntriesStruct, _data: &data::AllDataStruct, _xattrs: &xattr::XattrsStruct) -> Result<()> {
+            self.log.lock().unwrap().push(format!("compact {}", attrs.attrs().len()));
+            Ok(())
+        }
+        fn needs_compaction(&self, live: u64) -> bool { live == 0 }
+        fn limits(&self) -> limit::Limits { limit::Limits::default() }
+        fn available(&self) -> Result<u64> { Ok(0) }
+    }
+
+    fn recorder(fail: bool, write_behind: mount::WriteBehind) -> (Persister<Recorder>, Arc<Mutex<Vec<String>>>) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        (Persister::new(Recorder { log: Arc::clone(&log), fail: fail }, write_behind), log)
+    }
+
+    // 時間かflushでしか書き込まない
+    fn never() -> mount::WriteBehind {
+        mount::WriteBehind { interval: Duration::from_secs(60), batch: usize::MAX }
+    }
+
+    // 書き込みスレッドがlogにcount件書き込むまで待つ
+    fn wait_for(log: &Arc<Mutex<Vec<String>>>, count: usize) -> Vec<String> {
+        for _ in 0..500 {
+            if log.lock().unwrap().len() >= count {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        log.lock().unwrap().clone()
+    }
+
+    fn text_file(ino: u64, size: u64) -> attr::Attr {
+        let st = attr::SystemTime::new(0, 0);
+        attr::Attr::new(ino, size, attr::FileType::TextFile, 0o644, 0, 0, st, st, st, 1)
+    }
+
+    #[test]
+    fn changes_are_coalesced_per_inode() {
+        let (persister, log) = recorder(false, never());
+
+        persister.update_attr(&text_file(2, 3)).unwrap();
+        persister.write_data(2, b"abc").unwrap();
+        persister.update_attr(&text_file(2, 4)).unwrap();
+        persister.write_data(2, b"abcd").unwrap();
+        persister.update_entry(1, &vec![entry::Entry::new(2, "file".to_string())]).unwrap();
+        persister.update_attr(&text_file(3, 0)).unwrap();
+        persister.del_attr(3).unwrap();
+        persister.del_data(3).unwrap();
+        assert!(log.lock().unwrap().is_empty());
+        persister.flush().unwrap();
+
+        // 新しいinodeはエントリより先に、削除はエントリより後に書き込む
+        assert_eq!(*log.lock().unwrap(), vec!["attr 2 4", "data 2 abcd", "entry 1 1", "del data 3", "del attr 3"]);
+        // 書き込みスレッドがコンパクションを求めるのは一度だけ
+        assert!(persister.needs_compaction(0));
+        assert!(!persister.needs_compaction(0));
+    }
+
+    #[test]
+    fn batch_size_interval_and_drop_trigger_write() {
+        let (persister, log) = recorder(false, mount::WriteBehind { interval: Duration::from_secs(60), batch: 2 });
+        persister.write_data(2, b"a").unwrap();
+        persister.write_data(3, b"b").unwrap();
+        assert_eq!(wait_for(&log, 2), vec!["data 2 a", "data 3 b"]);
+
+        let (persister, log) = recorder(false, mount::WriteBehind { interval: Duration::from_millis(10), batch: usize::MAX });
+        persister.write_data(2, b"a").unwrap();
+        assert_eq!(wait_for(&log, 1), vec!["data 2 a"]);
+
+        let (persister, log) = recorder(false, never());
+        persister.write_data(2, b"a").unwrap();
+        drop(persister);
+        assert_eq!(*log.lock().unwrap(), vec!["data 2 a"]);
+
+        let (persister, log) = recorder(false, never());
+        persister.write_data(2, b"a").unwrap();
+        persister.sync().unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["data 2 a", "sync"]);
+    }
+
+    #[test]
+    fn compact_writes_pending_changes_first() {
+        let (persister, log) = recorder(false, never());
+        let empty = || (
+            attr::AttrsStruct::new(HashMap::new()),
+            entry::EntriesStruct::new(HashMap::new()),
+            data::AllDataStruct::new(HashMap::new()),
+            xattr::XattrsStruct::new(HashMap::new())
+        );
+        let (attrs, entries, all_data, xattrs) = empty();
+
+        // 書き直し終えてから戻る
+        persister.write_data(2, b"abc").unwrap();
+        persister.compact(&attrs, &entries, &all_data, &xattrs).unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["data 2 abc", "compact 0"]);
+
+        // 先に書き込んだ変更のエラーは、次のflushで伝える
+        let (persister, log) = recorder(true, never());
+        persister.write_data(2, b"abc").unwrap();
+        persister.compact(&attrs, &entries, &all_data, &xattrs).unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["compact 0"]);
+        assert!(matches!(persister.flush().unwrap_err().downcast_ref::<entity::Error>(), Some(entity::Error::NoSpace)));
+    }
+
+    #[test]
+    fn failed_write_is_reported_by_next_flush() {
+        let (persister, log) = recorder(true, never());
+
+        persister.write_data(2, b"abc").unwrap();
+        persister.update_xattr(2, &xattr::Xattrs::new()).unwrap();
+        assert!(matches!(persister.flush().unwrap_err().downcast_ref::<entity::Error>(), Some(entity::Error::NoSpace)));
+        persister.flush().unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["xattr 2"]);
+    }
+}
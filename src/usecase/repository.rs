@@ -1,12 +1,88 @@
 use std::path;
-use crate::entity::{self, attr, entry, data};
+use crate::entity::{self, attr, entry, data, limit, xattr};
 use anyhow::Result;
 
-pub trait File {
-    fn init(&mut self, path: &path::Path) -> Result<(u64, attr::AttrsStruct, entry::EntriesStruct, data::AllDataStruct)>;
-    fn write_data(&self, ino: u64, data: &str) -> Result<()>;
+pub trait File: Send {
+    fn init(&mut self, path: &path::Path) -> Result<(u64, attr::AttrsStruct, entry::EntriesStruct, data::AllDataStruct, xattr::XattrsStruct)>;
+    fn write_data(&self, ino: u64, data: &[u8]) -> Result<()>;
     fn update_attr(&self, attr: &attr::Attr) -> Result<()>;
     fn update_entry(&self, ino: u64, child_inos: &Vec<entry::Entry>) -> Result<()>;
+    fn update_xattr(&self, ino: u64, xattrs: &xattr::Xattrs) -> Result<()>;
+    fn sync(&self) -> Result<()>;
+    fn compact(&self, attrs: &attr::AttrsStruct, entries: &entry::EntriesStruct, data: &data::AllDataStruct, xattrs: &xattr::XattrsStruct) -> Result<()>;
+    fn needs_compaction(&self, live: u64) -> bool;
+    fn limits(&self) -> limit::Limits;
+    fn available(&self) -> Result<u64>;
     fn del_attr(&self, ino: u64) -> Result<()>;
     fn del_data(&self, ino: u64) -> Result<()>;
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub mod mock {
+    use std::collections::HashMap;
+    use std::path;
+    use crate::entity::{attr, data, entry, limit, xattr};
+    use anyhow::Result;
+    use super::File;
+
+    // ルートディレクトリ(ino: 1)直下にテキストファイルを並べたイメージを返すモック
+    pub struct MockFile {
+        files: Vec<(String, String)>,
+        limits: limit::Limits
+    }
+
+    // 上限を設定しない場合にホストの空き容量として返すバイト数
+    pub const AVAILABLE: u64 = 1 << 30;
+
+    impl MockFile {
+        pub fn new(files: Vec<(&str, &str)>) -> MockFile {
+            MockFile {
+                files: files.iter().map(|(name, data)| (name.to_string(), data.to_string())).collect(),
+                limits: limit::Limits::default()
+            }
+        }
+
+        pub fn with_limits(mut self, limits: limit::Limits) -> MockFile {
+            self.limits = limits;
+            self
+        }
+    }
+
+    impl File for MockFile {
+        fn init(&mut self, _path: &path::Path) -> Result<(u64, attr::AttrsStruct, entry::EntriesStruct, data::AllDataStruct, xattr::XattrsStruct)> {
+            let st = attr::SystemTime::new(0, 0);
+            let mut attrs = HashMap::new();
+            let mut entries = HashMap::new();
+            let mut all_data = HashMap::new();
+            let mut root_entry = Vec::new();
+
+            attrs.insert(1, attr::Attr::new(1, self.files.len() as u64, attr::FileType::Directory, 0o755, 1000, 1000, st, st, st, 2));
+            for (i, (name, text)) in self.files.iter().enumerate() {
+                let ino = i as u64 + 2;
+                attrs.insert(ino, attr::Attr::new(ino, text.len() as u64, attr::FileType::TextFile, 0o644, 1000, 1000, st, st, st, 1));
+                all_data.insert(ino, data::Data::new(ino, text.clone().into_bytes()));
+                root_entry.push(entry::Entry::new(ino, name.clone()));
+            }
+            entries.insert(1, root_entry);
+
+            Ok((
+                self.files.len() as u64 + 2,
+                attr::AttrsStruct::new(attrs),
+                entry::EntriesStruct::new(entries),
+                data::AllDataStruct::new(all_data),
+                xattr::XattrsStruct::new(HashMap::new())
+            ))
+        }
+
+        fn write_data(&self, _ino: u64, _data: &[u8]) -> Result<()> { Ok(()) }
+        fn update_attr(&self, _attr: &attr::Attr) -> Result<()> { Ok(()) }
+        fn del_attr(&self, _ino: u64) -> Result<()> { Ok(()) }
+        fn del_data(&self, _ino: u64) -> Result<()> { Ok(()) }
+        fn update_entry(&self, _ino: u64, _child_inos: &Vec<entry::Entry>) -> Result<()> { Ok(()) }
+        fn update_xattr(&self, _ino: u64, _xattrs: &xattr::Xattrs) -> Result<()> { Ok(()) }
+        fn sync(&self) -> Result<()> { Ok(()) }
+        fn compact(&self, _attrs: &attr::AttrsStruct, _entries: &entry::EntriesStruct, _data: &data::AllDataStruct, _xattrs: &xattr::XattrsStruct) -> Result<()> { Ok(()) }
+        fn needs_compaction(&self, _live: u64) -> bool { false }
+        fn limits(&self) -> limit::Limits { self.limits.clone() }
+        fn available(&self) -> Result<u64> { Ok(AVAILABLE) }
+    }
+}
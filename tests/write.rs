@@ -0,0 +1,145 @@
+use std::ffi::OsStr;
+use std::sync::Arc;
+use std::thread;
+use hfs::di;
+use hfs::entity::{self, credentials, handle, mount};
+use hfs::testing::TempDir;
+use hfs::usecase::Usecase;
+
+const CONTENT: &[u8] = b"this is content of file1";
+
+// テンプレートのイメージを一時ディレクトリに複製し、image.yamlのパスを返す
+// ディレクトリはテストが終わるかパニックしたときに削除する
+fn image(name: &str) -> (TempDir, String) {
+    let dir = TempDir::new(&format!("write-{}", name));
+    let image = dir.template_image().to_str().unwrap().to_string();
+    (dir, image)
+}
+
+fn root() -> credentials::Credentials {
+    credentials::Credentials::root()
+}
+
+fn file1(usecase: &impl Usecase) -> u64 {
+    usecase.lookup(&root(), 1, OsStr::new("file1")).unwrap().ino()
+}
+
+fn error_of<T>(result: anyhow::Result<T>) -> entity::Error {
+    match result {
+        Ok(_) => panic!("expected an error"),
+        Err(e) => e.downcast::<entity::Error>().unwrap()
+    }
+}
+
+#[test]
+fn append_writes_land_at_end_of_file() {
+    let (_dir, image) = image("append");
+    let usecase = di::open(&image, mount::Options::default()).unwrap();
+    let ino = file1(&usecase);
+
+    // カーネルが渡すoffsetが古くても末尾に追記する
+    let fh = usecase.open(&root(), ino, handle::O_RDWR | handle::O_APPEND).unwrap();
+    assert_eq!(usecase.write(&root(), ino, Some(fh), 0, b"\nappended").unwrap(), 9);
+    assert_eq!(usecase.write(&root(), ino, Some(fh), 0, b"!").unwrap(), 1);
+    usecase.release(fh).unwrap();
+
+    assert_eq!(usecase.read(&root(), ino, None, 0, 4096).unwrap(), [CONTENT, b"\nappended!"].concat());
+}
+
+#[test]
+fn concurrent_appends_do_not_overwrite_each_other() {
+    let (_dir, image) = image("concurrent");
+    let usecase = Arc::new(di::open(&image, mount::Options::default()).unwrap());
+    let ino = file1(&*usecase);
+    let mut threads = Vec::new();
+
+    for i in 0..4u8 {
+        let usecase = Arc::clone(&usecase);
+        threads.push(thread::spawn(move || {
+            let fh = usecase.open(&root(), ino, handle::O_WRONLY | handle::O_APPEND).unwrap();
+            for _ in 0..100 {
+                usecase.write(&root(), ino, Some(fh), CONTENT.len() as u64, &[b'a' + i]).unwrap();
+            }
+            usecase.release(fh).unwrap();
+        }));
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let data = usecase.read(&root(), ino, None, 0, 4096).unwrap();
+    assert_eq!(data.len(), CONTENT.len() + 400);
+    assert_eq!(&data[..CONTENT.len()], CONTENT);
+    for i in 0..4u8 {
+        assert_eq!(data[CONTENT.len()..].iter().filter(|&&c| c == b'a' + i).count(), 100);
+    }
+}
+
+#[test]
+fn write_without_append_honors_offset() {
+    let (_dir, image) = image("overwrite");
+    let usecase = di::open(&image, mount::Options::default()).unwrap();
+    let ino = file1(&usecase);
+
+    let fh = usecase.open(&root(), ino, handle::O_WRONLY).unwrap();
+    assert_eq!(usecase.write(&root(), ino, Some(fh), 0, b"THIS").unwrap(), 4);
+    usecase.release(fh).unwrap();
+
+    assert_eq!(usecase.read(&root(), ino, None, 0, 4096).unwrap(), b"THIS is content of file1");
+}
+
+#[test]
+fn truncate_through_setattr_empties_file_and_persists() {
+    let (_dir, image) = image("truncate");
+    {
+        let usecase = di::open(&image, mount::Options::default()).unwrap();
+        let ino = file1(&usecase);
+
+        // O_TRUNCで開くと、カーネルはsetattrでサイズを0にしてからopenを呼ぶ
+        usecase.setattr(&root(), ino, None, None, None, None, Some(0), None, None).unwrap();
+        let fh = usecase.open(&root(), ino, handle::O_WRONLY).unwrap();
+        assert_eq!(usecase.read(&root(), ino, None, 0, 4096).unwrap(), b"");
+        usecase.write(&root(), ino, Some(fh), 0, b"new").unwrap();
+        usecase.release(fh).unwrap();
+        usecase.fsync().unwrap();
+    }
+
+    // 読み込み直しても切り詰めた後の内容になっている
+    let usecase = di::open(&image, mount::Options::default()).unwrap();
+    let ino = file1(&usecase);
+    assert_eq!(usecase.attr_from_ino(ino).unwrap().size(), 3);
+    assert_eq!(usecase.read(&root(), ino, None, 0, 4096).unwrap(), b"new");
+}
+
+#[test]
+fn truncate_requires_write_permission() {
+    let (_dir, image) = image("truncate-denied");
+    let usecase = di::open(&image, mount::Options::default()).unwrap();
+    let ino = file1(&usecase);
+    let other = credentials::Credentials::new(2000, 2000);
+
+    assert!(matches!(error_of(usecase.setattr(&other, ino, None, None, None, None, Some(0), None, None)), entity::Error::PermissionDenied));
+    assert_eq!(usecase.read(&root(), ino, None, 0, 4096).unwrap(), CONTENT);
+}
+
+#[test]
+fn unlinked_file_written_while_open_is_removed_from_image() {
+    let (_dir, image) = image("unlinked");
+    let ino = {
+        let usecase = di::open(&image, mount::Options::default()).unwrap();
+        let ino = file1(&usecase);
+
+        let fh = usecase.open(&root(), ino, handle::O_RDWR).unwrap();
+        usecase.unlink(&root(), 1, OsStr::new("file1")).unwrap();
+        usecase.forget(ino, 1).unwrap();
+        // 削除を遅延している間の書き込みも、閉じたあとはイメージに残らない
+        usecase.write(&root(), ino, Some(fh), 0, b"after unlink").unwrap();
+        usecase.release(fh).unwrap();
+        usecase.fsync().unwrap();
+        ino
+    };
+
+    let usecase = di::open(&image, mount::Options::default()).unwrap();
+    assert!(matches!(error_of(usecase.attr_from_ino(ino)), entity::Error::NotFound));
+    assert!(matches!(error_of(usecase.lookup(&root(), 1, OsStr::new("file1"))), entity::Error::NotFound));
+}